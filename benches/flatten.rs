@@ -1,5 +1,7 @@
 // smooth-json/benches/flatten.rs
-use criterion::{BenchmarkId, Criterion, Throughput, black_box, criterion_group, criterion_main};
+use criterion::{
+    BatchSize, BenchmarkId, Criterion, Throughput, black_box, criterion_group, criterion_main,
+};
 use serde_json::{Map, Value, json};
 use smooth_json::Flattener;
 
@@ -28,6 +30,19 @@ fn make_collision_array(count: usize) -> Value {
     Value::Array(arr)
 }
 
+/// Build an array of `count` records with long string fields, where cloning leaves dominates.
+fn make_string_heavy(count: usize, len: usize) -> Value {
+    let mut arr = Vec::with_capacity(count);
+    for i in 0..count {
+        arr.push(json!({
+            "id": i,
+            "body": "x".repeat(len),
+            "meta": { "title": "y".repeat(len), "tags": ["z".repeat(len), "w".repeat(len)] }
+        }));
+    }
+    Value::Array(arr)
+}
+
 fn bench_flatten_inputs(c: &mut Criterion) {
     let mut group = c.benchmark_group("flatten_inputs");
     group.throughput(Throughput::Elements(1));
@@ -116,5 +131,144 @@ fn bench_collision_cases(c: &mut Criterion) {
     group.finish();
 }
 
-criterion_group!(benches, bench_flatten_inputs, bench_collision_cases);
+fn bench_owned(c: &mut Criterion) {
+    let mut group = c.benchmark_group("flatten_owned");
+    group.throughput(Throughput::Elements(1));
+
+    for &len in &[16usize, 1024, 16384] {
+        let input = make_string_heavy(100, len);
+
+        // Both variants clone the input in the setup, so only the flattening itself is timed
+        group.bench_with_input(BenchmarkId::new("borrowed", len), &input, |b, v| {
+            let fl = Flattener::new();
+            b.iter_batched(
+                || v.clone(),
+                |v| fl.flatten(black_box(&v)),
+                BatchSize::SmallInput,
+            )
+        });
+
+        group.bench_with_input(BenchmarkId::new("owned", len), &input, |b, v| {
+            let fl = Flattener::new();
+            b.iter_batched(
+                || v.clone(),
+                |v| fl.flatten_owned(black_box(v)),
+                BatchSize::SmallInput,
+            )
+        });
+    }
+
+    group.finish();
+}
+
+fn bench_streaming(c: &mut Criterion) {
+    let mut group = c.benchmark_group("flatten_from_str");
+    group.throughput(Throughput::Elements(1));
+
+    for (name, input) in [
+        ("nested", make_nested(6, 4)),
+        ("string_heavy", make_string_heavy(100, 1024)),
+    ] {
+        let text = input.to_string();
+
+        group.bench_with_input(
+            BenchmarkId::new("parse_then_flatten", name),
+            &text,
+            |b, t| {
+                let fl = Flattener::new();
+                b.iter(|| {
+                    let value: Value = serde_json::from_str(black_box(t)).unwrap();
+                    fl.flatten_owned(value)
+                })
+            },
+        );
+
+        group.bench_with_input(BenchmarkId::new("streaming", name), &text, |b, t| {
+            let fl = Flattener::new();
+            b.iter(|| fl.flatten_from_str(black_box(t)).unwrap())
+        });
+    }
+
+    group.finish();
+}
+
+fn bench_serialize(c: &mut Criterion) {
+    let mut group = c.benchmark_group("flatten_serialize");
+    group.throughput(Throughput::Elements(1));
+
+    // Stands in for a typed struct: anything `Serialize` takes the same path
+    let input: Vec<Vec<(String, Vec<u64>)>> = (0..100)
+        .map(|i| (0..10).map(|j| (format!("k{}", j), vec![i, j])).collect())
+        .collect();
+
+    group.bench_function("to_value_then_flatten", |b| {
+        let fl = Flattener::new();
+        b.iter(|| fl.flatten_owned(serde_json::to_value(black_box(&input)).unwrap()))
+    });
+
+    group.bench_function("flatten_serialize", |b| {
+        let fl = Flattener::new();
+        b.iter(|| fl.flatten_serialize(black_box(&input)).unwrap())
+    });
+
+    group.finish();
+}
+
+#[cfg(feature = "rayon")]
+fn bench_batch(c: &mut Criterion) {
+    let mut group = c.benchmark_group("flatten_batch");
+
+    let records: Vec<Value> = (0..1000).map(|_| make_nested(3, 4)).collect();
+    let ndjson: String = records.iter().map(|r| format!("{}\n", r)).collect();
+    group.throughput(Throughput::Elements(records.len() as u64));
+
+    group.bench_function("sequential", |b| {
+        let fl = Flattener::new();
+        b.iter(|| {
+            black_box(&records)
+                .iter()
+                .map(|r| fl.flatten(r))
+                .collect::<Vec<_>>()
+        })
+    });
+
+    let max_threads = std::thread::available_parallelism().map_or(1, |n| n.get());
+    let threads = [1, 2, 4, 8, 16].into_iter().filter(|&n| n <= max_threads);
+    for n in threads {
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(n)
+            .build()
+            .unwrap();
+
+        group.bench_with_input(BenchmarkId::new("flatten_batch", n), &records, |b, r| {
+            let fl = Flattener::new();
+            b.iter(|| pool.install(|| fl.flatten_batch(black_box(r))))
+        });
+
+        group.bench_with_input(
+            BenchmarkId::new("par_flatten_ndjson", n),
+            &ndjson,
+            |b, t| {
+                let fl = Flattener::new();
+                b.iter(|| pool.install(|| fl.par_flatten_ndjson(black_box(t).as_bytes()).count()))
+            },
+        );
+    }
+
+    group.finish();
+}
+
+// Run with `cargo bench --features rayon` to compare thread counts
+#[cfg(not(feature = "rayon"))]
+fn bench_batch(_: &mut Criterion) {}
+
+criterion_group!(
+    benches,
+    bench_flatten_inputs,
+    bench_collision_cases,
+    bench_owned,
+    bench_streaming,
+    bench_serialize,
+    bench_batch
+);
 criterion_main!(benches);
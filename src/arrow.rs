@@ -0,0 +1,829 @@
+//! Arrow record batches of flattened records, enabled by the `arrow` feature.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use arrow_array::RecordBatch;
+use arrow_array::RecordBatchOptions;
+use arrow_array::builder::ArrayBuilder;
+use arrow_array::builder::BooleanBuilder;
+use arrow_array::builder::LargeListBuilder;
+use arrow_array::builder::LargeStringBuilder;
+use arrow_array::builder::ListBuilder;
+use arrow_array::builder::NullBuilder;
+use arrow_array::builder::PrimitiveBuilder;
+use arrow_array::builder::StringBuilder;
+use arrow_array::builder::make_builder;
+use arrow_array::types::ArrowPrimitiveType;
+use arrow_array::types::Float32Type;
+use arrow_array::types::Float64Type;
+use arrow_array::types::Int8Type;
+use arrow_array::types::Int16Type;
+use arrow_array::types::Int32Type;
+use arrow_array::types::Int64Type;
+use arrow_array::types::UInt8Type;
+use arrow_array::types::UInt16Type;
+use arrow_array::types::UInt32Type;
+use arrow_array::types::UInt64Type;
+use arrow_schema::ArrowError;
+use arrow_schema::DataType;
+use arrow_schema::Field;
+use arrow_schema::Schema;
+use arrow_schema::SchemaRef;
+use serde_json::Map;
+use serde_json::Value;
+
+use crate::Flattener;
+use crate::JsonType;
+use crate::SchemaInferrer;
+use crate::Scratch;
+
+impl JsonType {
+    /// Returns the Arrow type that holds values of this type
+    ///
+    /// Arrays become a `List` of their element type. `Object` and `Mixed` become `Utf8`, holding
+    /// strings as they are and anything else as JSON text.
+    /// # Examples
+    /// ```
+    /// use smooth_json::JsonType;
+    /// use arrow_schema::{DataType, Field};
+    ///
+    /// let ints = JsonType::Array(Box::new(JsonType::Int));
+    ///
+    /// assert_eq!(ints.arrow_type(), DataType::new_list(DataType::Int64, true));
+    /// ```
+    pub fn arrow_type(&self) -> DataType {
+        self.arrow_type_with(&DataType::Int64)
+    }
+
+    /// Returns the Arrow type that holds values of this type, with `Int` as `int_type`.
+    fn arrow_type_with(&self, int_type: &DataType) -> DataType {
+        match self {
+            JsonType::Null => DataType::Null,
+            JsonType::Bool => DataType::Boolean,
+            JsonType::Int => int_type.clone(),
+            JsonType::Float => DataType::Float64,
+            JsonType::String | JsonType::Object | JsonType::Mixed => DataType::Utf8,
+            JsonType::Array(element) => DataType::new_list(element.arrow_type_with(int_type), true),
+        }
+    }
+}
+
+impl SchemaInferrer {
+    /// Returns an Arrow schema with a field for every column, in the order they were first seen
+    /// in, see [`JsonType::arrow_type`]
+    /// # Examples
+    /// ```
+    /// use smooth_json::SchemaInferrer;
+    /// use arrow_schema::{DataType, Field, Schema};
+    /// use serde_json::json;
+    ///
+    /// let mut inferrer = SchemaInferrer::new();
+    /// inferrer.observe(&json!({"id": 1, "name": "Alice"}));
+    /// inferrer.observe(&json!({"id": 2}));
+    ///
+    /// assert_eq!(
+    ///     inferrer.arrow_schema(),
+    ///     Schema::new(vec![
+    ///         Field::new("id", DataType::Int64, false),
+    ///         Field::new("name", DataType::Utf8, true),
+    ///     ])
+    /// );
+    /// ```
+    pub fn arrow_schema(&self) -> Schema {
+        self.arrow_schema_with(|_| DataType::Int64)
+    }
+
+    /// Returns an Arrow schema like [`SchemaInferrer::arrow_schema`], with `Int` as the type
+    /// `int_type` gives for the name of each column.
+    fn arrow_schema_with(&self, int_type: impl Fn(&str) -> DataType) -> Schema {
+        let fields: Vec<_> = self
+            .columns()
+            .iter()
+            .map(|column| {
+                let data_type = column.data_type().arrow_type_with(&int_type(column.name()));
+                let nullable = column.is_nullable() || data_type == DataType::Null;
+                Field::new(column.name(), data_type, nullable)
+            })
+            .collect();
+        Schema::new(fields)
+    }
+}
+
+/// The integers seen in a column, to pick an Arrow type that holds every one of them.
+#[derive(Debug, Default)]
+struct IntRange {
+    /// Whether an integer above `i64::MAX` was seen, alone or in an array
+    large_unsigned: bool,
+    /// Whether a negative integer was seen, alone or in an array
+    negative: bool,
+}
+
+impl IntRange {
+    fn observe(&mut self, value: &Value) {
+        match value {
+            Value::Number(n) => match n.as_i64() {
+                Some(i) => self.negative |= i < 0,
+                None => self.large_unsigned |= n.is_u64(),
+            },
+            Value::Array(elements) => elements.iter().for_each(|e| self.observe(e)),
+            _ => {}
+        }
+    }
+
+    fn arrow_type(&self) -> DataType {
+        match (self.large_unsigned, self.negative) {
+            (false, _) => DataType::Int64,
+            (true, false) => DataType::UInt64,
+            (true, true) => DataType::Float64,
+        }
+    }
+}
+
+/// Builds record batches out of flattened records, one column per field of the schema.
+pub(crate) struct BatchBuilder {
+    schema: SchemaRef,
+    /// Position of each field of the schema
+    index: HashMap<String, usize>,
+    builders: Vec<Box<dyn ArrayBuilder>>,
+    rows: usize,
+    /// Marks the columns set by the record being appended
+    seen: Vec<bool>,
+}
+
+impl BatchBuilder {
+    pub(crate) fn new(schema: SchemaRef) -> Result<Self, ArrowError> {
+        for field in schema.fields() {
+            check_supported(field.data_type())?;
+        }
+
+        let mut index = HashMap::new();
+        for (i, field) in schema.fields().iter().enumerate() {
+            if index.insert(field.name().clone(), i).is_some() {
+                return Err(ArrowError::SchemaError(format!(
+                    "field `{}` appears twice in the schema",
+                    field.name()
+                )));
+            }
+        }
+        let builders = schema
+            .fields()
+            .iter()
+            .map(|field| make_builder(field.data_type(), 0))
+            .collect();
+
+        Ok(BatchBuilder {
+            seen: vec![false; schema.fields().len()],
+            schema,
+            index,
+            builders,
+            rows: 0,
+        })
+    }
+
+    /// Number of records appended since the last batch was finished.
+    #[cfg(feature = "parquet")]
+    pub(crate) fn len(&self) -> usize {
+        self.rows
+    }
+
+    /// Appends a flattened record as a row. Keys without a field are skipped, and fields
+    /// without a key are null.
+    ///
+    /// Every value is checked before any is appended, so a record that does not fit leaves the
+    /// batch as it was.
+    pub(crate) fn append(&mut self, record: &Map<String, Value>) -> Result<(), ArrowError> {
+        self.seen.fill(false);
+        for (key, value) in record {
+            if let Some(&i) = self.index.get(key) {
+                check(self.schema.field(i), Some(value)).map_err(|e| in_column(e, key))?;
+                self.seen[i] = true;
+            }
+        }
+        for (i, field) in self.schema.fields().iter().enumerate() {
+            if !self.seen[i] {
+                check(field, None).map_err(|e| in_column(e, field.name()))?;
+            }
+        }
+
+        for (key, value) in record {
+            if let Some(&i) = self.index.get(key) {
+                append(
+                    self.builders[i].as_mut(),
+                    self.schema.field(i).data_type(),
+                    Some(value),
+                );
+            }
+        }
+        for (i, field) in self.schema.fields().iter().enumerate() {
+            if !self.seen[i] {
+                append(self.builders[i].as_mut(), field.data_type(), None);
+            }
+        }
+        self.rows += 1;
+        Ok(())
+    }
+
+    /// Returns the rows appended so far as a batch, and starts a new one.
+    pub(crate) fn finish(&mut self) -> Result<RecordBatch, ArrowError> {
+        let columns = self.builders.iter_mut().map(|b| b.finish()).collect();
+        let options = RecordBatchOptions::new().with_row_count(Some(self.rows));
+        self.rows = 0;
+        RecordBatch::try_new_with_options(self.schema.clone(), columns, &options)
+    }
+}
+
+fn check_supported(data_type: &DataType) -> Result<(), ArrowError> {
+    match data_type {
+        DataType::Null
+        | DataType::Boolean
+        | DataType::Int8
+        | DataType::Int16
+        | DataType::Int32
+        | DataType::Int64
+        | DataType::UInt8
+        | DataType::UInt16
+        | DataType::UInt32
+        | DataType::UInt64
+        | DataType::Float32
+        | DataType::Float64
+        | DataType::Utf8
+        | DataType::LargeUtf8 => Ok(()),
+        DataType::List(field) | DataType::LargeList(field) => check_supported(field.data_type()),
+        data_type => Err(ArrowError::NotYetImplemented(format!(
+            "flattened values cannot be converted to {}",
+            data_type
+        ))),
+    }
+}
+
+fn in_column(error: ArrowError, column: &str) -> ArrowError {
+    match error {
+        ArrowError::CastError(message) => {
+            ArrowError::CastError(format!("{} in column `{}`", message, column))
+        }
+        error => error,
+    }
+}
+
+fn mismatch(value: &Value, data_type: &DataType) -> ArrowError {
+    ArrowError::CastError(format!("cannot convert `{}` to {}", value, data_type))
+}
+
+fn downcast<B: ArrayBuilder>(builder: &mut dyn ArrayBuilder) -> &mut B {
+    builder
+        .as_any_mut()
+        .downcast_mut::<B>()
+        .expect("builders are made from the same data type")
+}
+
+/// Checks that a value, or a null for `None`, fits `field`, so that appending it cannot fail.
+fn check(field: &Field, value: Option<&Value>) -> Result<(), ArrowError> {
+    let data_type = field.data_type();
+    let Some(value) = value.filter(|v| !v.is_null()) else {
+        return if field.is_nullable() {
+            Ok(())
+        } else {
+            Err(mismatch(&Value::Null, data_type))
+        };
+    };
+    let fits = match data_type {
+        DataType::Null => false,
+        DataType::Boolean => value.is_boolean(),
+        DataType::Int8 => native::<Int8Type>(value).is_some(),
+        DataType::Int16 => native::<Int16Type>(value).is_some(),
+        DataType::Int32 => native::<Int32Type>(value).is_some(),
+        DataType::Int64 => native::<Int64Type>(value).is_some(),
+        DataType::UInt8 => native::<UInt8Type>(value).is_some(),
+        DataType::UInt16 => native::<UInt16Type>(value).is_some(),
+        DataType::UInt32 => native::<UInt32Type>(value).is_some(),
+        DataType::UInt64 => native::<UInt64Type>(value).is_some(),
+        DataType::Float32 | DataType::Float64 => value.is_number(),
+        DataType::Utf8 | DataType::LargeUtf8 => true,
+        DataType::List(field) | DataType::LargeList(field) => {
+            return elements(value)
+                .iter()
+                .try_for_each(|element| check(field, Some(element)));
+        }
+        _ => unreachable!("checked by check_supported"),
+    };
+    if fits {
+        Ok(())
+    } else {
+        Err(mismatch(value, data_type))
+    }
+}
+
+/// Appends a value, or a null for `None`, to a builder made for `data_type`. The value must
+/// have been checked with `check`.
+fn append(builder: &mut dyn ArrayBuilder, data_type: &DataType, value: Option<&Value>) {
+    let value = value.filter(|v| !v.is_null());
+    match data_type {
+        DataType::Null => downcast::<NullBuilder>(builder).append_null(),
+        DataType::Boolean => {
+            downcast::<BooleanBuilder>(builder).append_option(value.and_then(Value::as_bool))
+        }
+        DataType::Int8 => append_int::<Int8Type>(builder, value),
+        DataType::Int16 => append_int::<Int16Type>(builder, value),
+        DataType::Int32 => append_int::<Int32Type>(builder, value),
+        DataType::Int64 => append_int::<Int64Type>(builder, value),
+        DataType::UInt8 => append_int::<UInt8Type>(builder, value),
+        DataType::UInt16 => append_int::<UInt16Type>(builder, value),
+        DataType::UInt32 => append_int::<UInt32Type>(builder, value),
+        DataType::UInt64 => append_int::<UInt64Type>(builder, value),
+        DataType::Float32 => downcast::<PrimitiveBuilder<Float32Type>>(builder)
+            .append_option(value.map(|v| v.as_f64().unwrap_or(f64::NAN) as f32)),
+        DataType::Float64 => downcast::<PrimitiveBuilder<Float64Type>>(builder)
+            .append_option(value.map(|v| v.as_f64().unwrap_or(f64::NAN))),
+        DataType::Utf8 => {
+            let builder = downcast::<StringBuilder>(builder);
+            match value {
+                None => builder.append_null(),
+                Some(Value::String(s)) => builder.append_value(s),
+                Some(v) => builder.append_value(v.to_string()),
+            }
+        }
+        DataType::LargeUtf8 => {
+            let builder = downcast::<LargeStringBuilder>(builder);
+            match value {
+                None => builder.append_null(),
+                Some(Value::String(s)) => builder.append_value(s),
+                Some(v) => builder.append_value(v.to_string()),
+            }
+        }
+        DataType::List(field) => {
+            let builder = downcast::<ListBuilder<Box<dyn ArrayBuilder>>>(builder);
+            if let Some(value) = value {
+                append_elements(builder.values().as_mut(), field.data_type(), value);
+            }
+            builder.append(value.is_some());
+        }
+        DataType::LargeList(field) => {
+            let builder = downcast::<LargeListBuilder<Box<dyn ArrayBuilder>>>(builder);
+            if let Some(value) = value {
+                append_elements(builder.values().as_mut(), field.data_type(), value);
+            }
+            builder.append(value.is_some());
+        }
+        _ => unreachable!("checked by check_supported"),
+    }
+}
+
+/// The elements of a list. Flattening turns an array with a single element into that element,
+/// so anything but an array is a list of one.
+fn elements(value: &Value) -> &[Value] {
+    match value {
+        Value::Array(elements) => elements,
+        value => std::slice::from_ref(value),
+    }
+}
+
+fn append_elements(builder: &mut dyn ArrayBuilder, data_type: &DataType, value: &Value) {
+    for element in elements(value) {
+        append(builder, data_type, Some(element));
+    }
+}
+
+/// Converts a number to the native type of an integer type, if it fits.
+fn native<T>(value: &Value) -> Option<T::Native>
+where
+    T: ArrowPrimitiveType,
+    T::Native: TryFrom<i64> + TryFrom<u64>,
+{
+    match value {
+        Value::Number(n) => match (n.as_i64(), n.as_u64()) {
+            (Some(i), _) => T::Native::try_from(i).ok(),
+            (None, Some(u)) => T::Native::try_from(u).ok(),
+            (None, None) => None,
+        },
+        _ => None,
+    }
+}
+
+fn append_int<T>(builder: &mut dyn ArrayBuilder, value: Option<&Value>)
+where
+    T: ArrowPrimitiveType,
+    T::Native: TryFrom<i64> + TryFrom<u64>,
+{
+    let native = value.map(|v| native::<T>(v).expect("checked before appending"));
+    downcast::<PrimitiveBuilder<T>>(builder).append_option(native);
+}
+
+impl<'a> Flattener<'a> {
+    /// Flattens records into an Arrow record batch, with a column for every key found in them
+    ///
+    /// Column types are inferred with a [`SchemaInferrer`] and [`SchemaInferrer::arrow_schema`],
+    /// so the records are flattened and held in memory before the batch is built. Integer
+    /// columns holding values above `i64::MAX` are `UInt64` instead of `Int64`, or `Float64`
+    /// when they also hold negative values. Use
+    /// [`Flattener::record_batch_with_schema`] to build it as the records are read instead.
+    ///
+    /// # Arguments
+    ///
+    /// * `records` - The records to flatten
+    ///
+    /// # Examples
+    /// ```
+    /// use smooth_json::Flattener;
+    /// use arrow_array::{Array, Int64Array, ListArray};
+    /// use serde_json::json;
+    ///
+    /// let flattener = Flattener { alt_array_flattening: true, ..Default::default() };
+    /// let records = vec![
+    ///     json!({"id": 1, "items": [{"qty": 2}, {"qty": 3}]}),
+    ///     json!({"id": 2, "items": [{"qty": 4}]}),
+    /// ];
+    ///
+    /// let batch = flattener.record_batch(&records).unwrap();
+    ///
+    /// assert_eq!(batch.num_rows(), 2);
+    /// let qty = batch.column_by_name("items.qty").unwrap();
+    /// let qty = qty.as_any().downcast_ref::<ListArray>().unwrap();
+    /// let first = qty.value(0);
+    /// assert_eq!(first.as_any().downcast_ref::<Int64Array>().unwrap().values(), &[2, 3]);
+    /// ```
+    pub fn record_batch<'r, I>(&self, records: I) -> Result<RecordBatch, ArrowError>
+    where
+        I: IntoIterator<Item = &'r Value>,
+    {
+        let (flattened, schema) = self.flatten_with_schema(records);
+        let mut builder = BatchBuilder::new(Arc::new(schema))?;
+        for record in &flattened {
+            builder.append(record)?;
+        }
+        builder.finish()
+    }
+
+    /// Flattens records and infers their Arrow schema, see [`Flattener::record_batch`].
+    pub(crate) fn flatten_with_schema<'r, I>(&self, records: I) -> (Vec<Map<String, Value>>, Schema)
+    where
+        I: IntoIterator<Item = &'r Value>,
+    {
+        let mut inferrer = SchemaInferrer::new();
+        let mut ranges: HashMap<String, IntRange> = HashMap::new();
+        let mut scratch = Scratch::new();
+        let flattened = records
+            .into_iter()
+            .map(|record| {
+                let mut out = Map::new();
+                self.flatten_into(record, &mut out, &mut scratch);
+                inferrer.observe_object(&out);
+                for (key, value) in &out {
+                    match ranges.get_mut(key) {
+                        Some(range) => range.observe(value),
+                        None => {
+                            let mut range = IntRange::default();
+                            range.observe(value);
+                            ranges.insert(key.clone(), range);
+                        }
+                    }
+                }
+                out
+            })
+            .collect();
+
+        let schema = inferrer.arrow_schema_with(|name| {
+            ranges
+                .get(name)
+                .map_or(DataType::Int64, IntRange::arrow_type)
+        });
+        (flattened, schema)
+    }
+
+    /// Flattens records into an Arrow record batch with the given schema
+    ///
+    /// Every field of the schema is filled from the flattened key with the same name, and is
+    /// null when a record does not have it. Other keys are skipped. A value that does not fit
+    /// the type of its field, such as a string in an `Int64` field, is an error, except for
+    /// string fields which hold anything but strings as JSON text. So is a null or missing
+    /// value in a field, or a null element in a list, that is not nullable. List fields take a
+    /// value that is not an array as a list of one. A schema with two fields of the same name
+    /// is an error.
+    ///
+    /// # Arguments
+    ///
+    /// * `records` - The records to flatten
+    /// * `schema` - The schema of the batch
+    ///
+    /// # Examples
+    /// ```
+    /// use std::sync::Arc;
+    ///
+    /// use smooth_json::Flattener;
+    /// use smooth_json::arrow_array::{Array, Int32Array};
+    /// use smooth_json::arrow_schema::{DataType, Field, Schema};
+    /// use serde_json::json;
+    ///
+    /// let flattener = Flattener::new();
+    /// let schema = Arc::new(Schema::new(vec![Field::new("user.age", DataType::Int32, true)]));
+    /// let records = vec![json!({"user": {"age": 30}}), json!({"user": {}})];
+    ///
+    /// let batch = flattener.record_batch_with_schema(&records, schema).unwrap();
+    ///
+    /// let ages = batch.column(0).as_any().downcast_ref::<Int32Array>().unwrap();
+    /// assert_eq!(ages.value(0), 30);
+    /// assert!(ages.is_null(1));
+    /// ```
+    pub fn record_batch_with_schema<'r, I>(
+        &self,
+        records: I,
+        schema: SchemaRef,
+    ) -> Result<RecordBatch, ArrowError>
+    where
+        I: IntoIterator<Item = &'r Value>,
+    {
+        let mut builder = BatchBuilder::new(schema)?;
+        let mut out = Map::new();
+        let mut scratch = Scratch::new();
+        for record in records {
+            out.clear();
+            self.flatten_into(record, &mut out, &mut scratch);
+            builder.append(&out)?;
+        }
+        builder.finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use arrow_array::Array;
+    use arrow_array::BooleanArray;
+    use arrow_array::Float64Array;
+    use arrow_array::Int64Array;
+    use arrow_array::LargeStringArray;
+    use arrow_array::ListArray;
+    use arrow_array::NullArray;
+    use arrow_array::StringArray;
+    use arrow_array::UInt8Array;
+    use arrow_array::UInt64Array;
+    use serde_json::json;
+
+    #[test]
+    fn record_batch_infers_types() {
+        let flattener = Flattener::new();
+        let records = [
+            json!({"id": 1, "score": 1, "ok": true, "tags": ["a"], "meta": "x", "none": null}),
+            json!({"id": 2, "score": 2.5, "tags": ["b", "c"], "meta": 3}),
+            json!({"id": 3, "tags": [], "meta": {"k": [1, "v"]}, "none": null}),
+        ];
+
+        let batch = flattener.record_batch(&records).unwrap();
+        let schema = batch.schema();
+        let fields: Vec<_> = schema
+            .fields()
+            .iter()
+            .map(|f| (f.name().as_str(), f.data_type().clone(), f.is_nullable()))
+            .collect();
+        assert_eq!(
+            fields,
+            vec![
+                ("id", DataType::Int64, false),
+                ("meta", DataType::Utf8, true),
+                ("none", DataType::Null, true),
+                ("ok", DataType::Boolean, true),
+                ("score", DataType::Float64, true),
+                ("tags", DataType::new_list(DataType::Utf8, true), false),
+                ("meta.k", DataType::Utf8, true),
+            ]
+        );
+
+        let column = |name| batch.column_by_name(name).unwrap();
+        let ids = column("id");
+        let ids = ids.as_any().downcast_ref::<Int64Array>().unwrap();
+        assert_eq!(ids.values(), &[1, 2, 3]);
+
+        let meta = column("meta");
+        let meta = meta.as_any().downcast_ref::<StringArray>().unwrap();
+        assert_eq!((meta.value(0), meta.value(1)), ("x", "3"));
+        assert!(meta.is_null(2));
+
+        assert_eq!(
+            column("none")
+                .as_any()
+                .downcast_ref::<NullArray>()
+                .unwrap()
+                .len(),
+            3
+        );
+
+        let ok = column("ok");
+        let ok = ok.as_any().downcast_ref::<BooleanArray>().unwrap();
+        assert!(ok.value(0) && ok.is_null(1) && ok.is_null(2));
+
+        let score = column("score");
+        let score = score.as_any().downcast_ref::<Float64Array>().unwrap();
+        assert_eq!((score.value(0), score.value(1)), (1.0, 2.5));
+
+        let tags = column("tags");
+        let tags = tags.as_any().downcast_ref::<ListArray>().unwrap();
+        let lens: Vec<_> = (0..3).map(|i| tags.value(i).len()).collect();
+        assert_eq!(lens, vec![1, 2, 0]);
+
+        let meta_k = column("meta.k");
+        let meta_k = meta_k.as_any().downcast_ref::<StringArray>().unwrap();
+        assert_eq!(meta_k.value(2), "[1,\"v\"]");
+    }
+
+    #[test]
+    fn record_batch_large_unsigned() {
+        let flattener = Flattener::new();
+        let records = [
+            json!({"id": u64::MAX, "ids": [1, u64::MAX], "n": u64::MAX}),
+            json!({"id": 1, "ids": 2, "n": [-1, 2]}),
+        ];
+
+        let batch = flattener.record_batch(&records).unwrap();
+        let schema = batch.schema();
+        let types: Vec<_> = schema.fields().iter().map(|f| f.data_type()).collect();
+        assert_eq!(
+            types,
+            vec![
+                &DataType::UInt64,
+                &DataType::new_list(DataType::UInt64, true),
+                &DataType::new_list(DataType::Float64, true),
+            ]
+        );
+
+        let id = batch
+            .column(0)
+            .as_any()
+            .downcast_ref::<UInt64Array>()
+            .unwrap();
+        assert_eq!(id.values(), &[u64::MAX, 1]);
+        let n = batch
+            .column(2)
+            .as_any()
+            .downcast_ref::<ListArray>()
+            .unwrap();
+        let n = n.value(1);
+        let n = n.as_any().downcast_ref::<Float64Array>().unwrap();
+        assert_eq!(n.value(0), -1.0);
+
+        // `arrow_schema` only sees the JSON types, and keeps `Int` as `Int64`
+        let mut inferrer = SchemaInferrer::new();
+        records.iter().for_each(|r| inferrer.observe(r));
+        assert_eq!(
+            inferrer.arrow_schema().field(0).data_type(),
+            &DataType::Int64
+        );
+    }
+
+    #[test]
+    fn record_batch_with_user_schema() {
+        let flattener = Flattener {
+            alt_array_flattening: true,
+            ..Default::default()
+        };
+        let schema = Arc::new(Schema::new(vec![
+            Field::new("a.b", DataType::UInt8, true),
+            Field::new("c", DataType::new_list(DataType::Float64, true), true),
+            Field::new("d", DataType::LargeUtf8, true),
+        ]));
+        let records = [
+            json!({"a": {"b": 3}, "c": [1, null], "d": [true], "ignored": 1}),
+            json!({"c": 1.5, "d": "x"}),
+        ];
+
+        let batch = flattener
+            .record_batch_with_schema(&records, schema.clone())
+            .unwrap();
+        assert_eq!(batch.schema(), schema);
+
+        let b = batch
+            .column(0)
+            .as_any()
+            .downcast_ref::<UInt8Array>()
+            .unwrap();
+        assert_eq!(b.value(0), 3);
+        assert!(b.is_null(1));
+
+        let c = batch
+            .column(1)
+            .as_any()
+            .downcast_ref::<ListArray>()
+            .unwrap();
+        let first = c.value(0);
+        let first = first.as_any().downcast_ref::<Float64Array>().unwrap();
+        assert_eq!(first.value(0), 1.0);
+        assert!(first.is_null(1));
+        // Not an array, so a list of one
+        assert_eq!(c.value(1).len(), 1);
+
+        let d = batch
+            .column(2)
+            .as_any()
+            .downcast_ref::<LargeStringArray>()
+            .unwrap();
+        assert_eq!((d.value(0), d.value(1)), ("[true]", "x"));
+
+        // Arrays collected by `alt_array_flattening` do not fit in a `UInt8`
+        let records = [json!({"a": [{"b": 1}, {"b": 2}]})];
+        assert!(
+            flattener
+                .record_batch_with_schema(&records, schema)
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn record_batch_errors() {
+        let flattener = Flattener::new();
+
+        let schema = Arc::new(Schema::new(vec![Field::new("a", DataType::Int8, true)]));
+        let error = flattener
+            .record_batch_with_schema(&[json!({"a": 300})], schema)
+            .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Cast error: cannot convert `300` to Int8 in column `a`"
+        );
+
+        let schema = Arc::new(Schema::new(vec![
+            Field::new("a", DataType::Int64, false),
+            Field::new("b", DataType::new_list(DataType::Int64, false), true),
+        ]));
+        let error = |record: Value| {
+            flattener
+                .record_batch_with_schema(&[json!({"a": 1}), record], schema.clone())
+                .unwrap_err()
+                .to_string()
+        };
+        assert_eq!(
+            error(json!({"b": 1})),
+            "Cast error: cannot convert `null` to Int64 in column `a`"
+        );
+        assert_eq!(
+            error(json!({"a": null})),
+            "Cast error: cannot convert `null` to Int64 in column `a`"
+        );
+        assert_eq!(
+            error(json!({"a": 2, "b": [1, null]})),
+            "Cast error: cannot convert `null` to Int64 in column `b`"
+        );
+
+        let schema = Arc::new(Schema::new(vec![Field::new("a", DataType::Date32, true)]));
+        assert!(matches!(
+            flattener.record_batch_with_schema(&[json!({})], schema),
+            Err(ArrowError::NotYetImplemented(_))
+        ));
+
+        let schema = Arc::new(Schema::new(vec![
+            Field::new("a", DataType::Int64, true),
+            Field::new("a", DataType::Utf8, true),
+        ]));
+        let error = flattener
+            .record_batch_with_schema(&[json!({"a": 1})], schema)
+            .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Schema error: field `a` appears twice in the schema"
+        );
+    }
+
+    #[test]
+    fn rejected_records_are_not_appended() {
+        let schema = Arc::new(Schema::new(vec![
+            Field::new("a", DataType::Int64, true),
+            Field::new("b", DataType::new_list(DataType::Int8, true), true),
+            Field::new("c", DataType::Boolean, true),
+        ]));
+        let mut builder = BatchBuilder::new(schema).unwrap();
+        let flattener = Flattener::new();
+        let mut record = |value: Value| match flattener.flatten(&value) {
+            Value::Object(record) => builder.append(&record),
+            _ => unreachable!(),
+        };
+
+        assert!(record(json!({"a": 1, "b": [1, 2], "c": true})).is_ok());
+        // Fails on a list element, after `a` fits
+        assert!(record(json!({"a": 2, "b": [3, 300]})).is_err());
+        assert!(record(json!({"a": 3, "c": "no"})).is_err());
+        assert!(record(json!({"a": 4, "b": 5})).is_ok());
+
+        let batch = builder.finish().unwrap();
+        assert_eq!(batch.num_rows(), 2);
+        let a = batch
+            .column(0)
+            .as_any()
+            .downcast_ref::<Int64Array>()
+            .unwrap();
+        assert_eq!(a.values(), &[1, 4]);
+        let b = batch
+            .column(1)
+            .as_any()
+            .downcast_ref::<ListArray>()
+            .unwrap();
+        assert_eq!(b.value(0).len() + b.value(1).len(), 3);
+    }
+
+    #[test]
+    fn record_batch_without_columns() {
+        let flattener = Flattener::new();
+        let batch = flattener.record_batch(&[json!({}), json!({})]).unwrap();
+
+        assert_eq!(batch.num_columns(), 0);
+        assert_eq!(batch.num_rows(), 2);
+    }
+}
@@ -1,22 +1,606 @@
-//! This bin target is only used for this crate's tests.
-//! It is not intended for users and is not published with the library code to crates.io.
-
-use std::fs;
-
-use serde_json::Value;
-fn main() {
-    let flattener = smooth_json::Flattener::new();
-    // get all files in the directory
-    // for each file, read the file
-    // parse the file as json
-    // flatten the json
-
-    let paths = fs::read_dir("tests/input").unwrap();
-
-    for path in paths {
-        let path = path.unwrap().path();
-        let json_str = fs::read_to_string(&path).unwrap();
-        let json: Value = serde_json::from_str(&json_str).unwrap();
-        let _flat_json = flattener.flatten(&json);
+//! Command-line tool that flattens JSON or newline-delimited JSON records and writes them as
+//! JSON, NDJSON or CSV. Built with the `cli` feature, run `flatten-json --help` for its options.
+
+use std::env;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::process::ExitCode;
+
+use serde_json::{Map, Value};
+use smooth_json::{
+    ArrayEncoding, CollisionPolicy, CsvOptions, DepthLimit, EmptyObjects, Flattener, KeyFormat,
+    KeyTransform, SchemaInferrer, Scratch,
+};
+
+const USAGE: &str = "\
+Usage: flatten-json [OPTIONS] [FILE]...
+
+Flattens the JSON records of each FILE, or of standard input when there is no FILE or FILE
+is -, and writes them to standard output.
+
+Input and output:
+  -i, --input-format <FORMAT>     json: one document per file, a top-level array being a
+                                  list of records (default)
+                                  ndjson: one document per line
+  -f, --output-format <FORMAT>    json: a pretty-printed record, or an array of them for
+                                  NDJSON input, more than one FILE or a top-level array
+                                  ndjson: one record per line
+                                  csv: a header and a row per record, written once
+                                  every record is read; records must be objects
+                                  [default: the input format]
+  -o, --output <FILE>             Write to FILE instead of standard output
+
+Flattening:
+  -s, --separator <SEP>           Separator between keys [default: .]
+      --alt-array-flattening      Put values of objects nested in arrays in arrays
+      --preserve-arrays           Keep the index of array elements in the key
+      --escape-keys               Escape the separator and backslashes inside keys
+      --key-format <FORMAT>       dotted (default), bracketed, jsonpath or pointer
+      --collision-policy <POLICY> merge (default), keep-first, keep-last, suffix or error
+      --max-depth <N>             Levels of objects and arrays to flatten
+      --depth-limit <LIMIT>       What to do past --max-depth: stringify (default), keep,
+                                  drop or error
+      --max-keys <N>              Report records that flatten to more than N keys
+      --empty-objects <EMPTY>     omit (default), object or null
+      --include <PATTERN>         Only flatten values matching PATTERN, such as user.* or
+                                  labels.**.name; may be repeated
+      --exclude <PATTERN>         Leave out values matching PATTERN, even when they are
+                                  included; may be repeated
+      --keep-whole <PATTERN>      Keep objects and arrays matching PATTERN as JSON values
+                                  instead of flattening them; may be repeated
+      --stringify <PATTERN>       Keep objects and arrays matching PATTERN as JSON strings
+                                  instead of flattening them; may be repeated
+
+Output keys, transformed in the order given:
+      --snake-case                Split camelCase words with _ and lowercase them
+      --lowercase                 Lowercase keys
+      --replace-invalid <CHAR>    Replace characters other than ASCII letters, digits and _
+      --truncate-keys <N>         Shorten keys longer than N bytes, ending them with a hash
+      --rename <KEY=NAME>         Write the flattened key KEY as NAME, without transforming
+                                  it; may be repeated
+
+CSV output:
+  -d, --delimiter <CHAR>          Field delimiter [default: ,]
+      --join-arrays <SEP>         Join array elements with SEP instead of writing JSON text
+      --explode-arrays            Write a row per array element
+
+  -h, --help                      Print this help
+  -V, --version                   Print the version
+
+Exit status:
+  0  every record was written
+  1  a record is not valid JSON, or cannot be flattened with the options given
+  2  the arguments are invalid
+  3  a file cannot be read or written";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+enum InputFormat {
+    #[default]
+    Json,
+    Ndjson,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum OutputFormat {
+    Json,
+    Ndjson,
+    Csv,
+}
+
+/// Options given on the command line. Options of the flattener and of the CSV writer are
+/// `None` or `false` when not given, so their defaults come from the library.
+#[derive(Debug, Default)]
+struct Args {
+    input_format: InputFormat,
+    output_format: Option<OutputFormat>,
+    output: Option<String>,
+    separator: Option<String>,
+    alt_array_flattening: bool,
+    preserve_arrays: bool,
+    escape_keys: bool,
+    collision_policy: Option<CollisionPolicy>,
+    max_depth: Option<usize>,
+    depth_limit: Option<DepthLimit>,
+    max_keys: Option<usize>,
+    empty_objects: Option<EmptyObjects>,
+    key_format: Option<KeyFormat>,
+    include: Vec<String>,
+    exclude: Vec<String>,
+    keep_whole: Vec<String>,
+    stringify: Vec<String>,
+    key_transforms: Vec<KeyTransform>,
+    rename: Vec<(String, String)>,
+    delimiter: Option<u8>,
+    join_arrays: Option<String>,
+    explode_arrays: bool,
+    files: Vec<String>,
+}
+
+enum Command {
+    Help,
+    Version,
+    Run(Box<Args>),
+}
+
+/// Where a record comes from: a file, or `-` for standard input, and its line for NDJSON
+struct Location<'s> {
+    source: &'s str,
+    line: Option<usize>,
+}
+
+impl fmt::Display for Location<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let source = if self.source == "-" {
+            "<stdin>"
+        } else {
+            self.source
+        };
+        match self.line {
+            Some(line) => write!(f, "{}:{}", source, line),
+            None => write!(f, "{}", source),
+        }
+    }
+}
+
+#[derive(Debug)]
+enum Error {
+    /// The arguments are invalid
+    Usage(String),
+    /// A record is not valid JSON or cannot be flattened
+    Input(String),
+    /// A file cannot be read or written
+    Io(String, io::Error),
+}
+
+impl Error {
+    fn exit_code(&self) -> u8 {
+        match self {
+            Error::Input(_) => 1,
+            Error::Usage(_) => 2,
+            Error::Io(..) => 3,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Usage(message) => {
+                write!(
+                    f,
+                    "{}\nTry 'flatten-json --help' for more information.",
+                    message
+                )
+            }
+            Error::Input(message) => write!(f, "{}", message),
+            Error::Io(path, source) => write!(f, "{}: {}", path, source),
+        }
+    }
+}
+
+fn usage(message: impl Into<String>) -> Error {
+    Error::Usage(message.into())
+}
+
+/// Returns the value of an option, given as `--name=value` or as the next argument
+fn value(
+    name: &str,
+    inline: &mut Option<String>,
+    args: &mut impl Iterator<Item = String>,
+) -> Result<String, Error> {
+    inline
+        .take()
+        .or_else(|| args.next())
+        .ok_or_else(|| usage(format!("{} needs a value", name)))
+}
+
+fn parse_args(mut args: impl Iterator<Item = String>) -> Result<Command, Error> {
+    let mut parsed = Args::default();
+    let mut options_done = false;
+
+    while let Some(arg) = args.next() {
+        if options_done || arg == "-" || !arg.starts_with('-') {
+            parsed.files.push(arg);
+            continue;
+        }
+        if arg == "--" {
+            options_done = true;
+            continue;
+        }
+
+        let (name, mut inline) = match arg.split_once('=') {
+            Some((name, value)) if arg.starts_with("--") => {
+                (name.to_string(), Some(value.to_string()))
+            }
+            _ => (arg, None),
+        };
+
+        match name.as_str() {
+            "-h" | "--help" => return Ok(Command::Help),
+            "-V" | "--version" => return Ok(Command::Version),
+            "-i" | "--input-format" => {
+                parsed.input_format = match value(&name, &mut inline, &mut args)?.as_str() {
+                    "json" => InputFormat::Json,
+                    "ndjson" => InputFormat::Ndjson,
+                    other => return Err(usage(format!("invalid input format `{}`", other))),
+                }
+            }
+            "-f" | "--output-format" => {
+                parsed.output_format = match value(&name, &mut inline, &mut args)?.as_str() {
+                    "json" => Some(OutputFormat::Json),
+                    "ndjson" => Some(OutputFormat::Ndjson),
+                    "csv" => Some(OutputFormat::Csv),
+                    other => return Err(usage(format!("invalid output format `{}`", other))),
+                }
+            }
+            "-o" | "--output" => parsed.output = Some(value(&name, &mut inline, &mut args)?),
+            "-s" | "--separator" => parsed.separator = Some(value(&name, &mut inline, &mut args)?),
+            "--alt-array-flattening" => parsed.alt_array_flattening = true,
+            "--preserve-arrays" => parsed.preserve_arrays = true,
+            "--escape-keys" => parsed.escape_keys = true,
+            "--collision-policy" => {
+                parsed.collision_policy = match value(&name, &mut inline, &mut args)?.as_str() {
+                    "merge" => Some(CollisionPolicy::MergeIntoArray),
+                    "keep-first" => Some(CollisionPolicy::KeepFirst),
+                    "keep-last" => Some(CollisionPolicy::KeepLast),
+                    "suffix" => Some(CollisionPolicy::Suffix),
+                    "error" => Some(CollisionPolicy::Error),
+                    other => return Err(usage(format!("invalid collision policy `{}`", other))),
+                }
+            }
+            "--max-depth" => {
+                let depth = value(&name, &mut inline, &mut args)?;
+                parsed.max_depth = Some(
+                    depth
+                        .parse()
+                        .map_err(|_| usage(format!("invalid depth `{}`", depth)))?,
+                );
+            }
+            "--max-keys" => {
+                let keys = value(&name, &mut inline, &mut args)?;
+                parsed.max_keys = Some(
+                    keys.parse()
+                        .map_err(|_| usage(format!("invalid number of keys `{}`", keys)))?,
+                );
+            }
+            "--depth-limit" => {
+                parsed.depth_limit = match value(&name, &mut inline, &mut args)?.as_str() {
+                    "stringify" => Some(DepthLimit::Stringify),
+                    "keep" => Some(DepthLimit::Keep),
+                    "drop" => Some(DepthLimit::Drop),
+                    "error" => Some(DepthLimit::Error),
+                    other => return Err(usage(format!("invalid depth limit `{}`", other))),
+                }
+            }
+            "--empty-objects" => {
+                parsed.empty_objects = match value(&name, &mut inline, &mut args)?.as_str() {
+                    "omit" => Some(EmptyObjects::Omit),
+                    "object" => Some(EmptyObjects::Object),
+                    "null" => Some(EmptyObjects::Null),
+                    other => return Err(usage(format!("invalid empty objects `{}`", other))),
+                }
+            }
+            "--key-format" => {
+                parsed.key_format = match value(&name, &mut inline, &mut args)?.as_str() {
+                    "dotted" => Some(KeyFormat::Dotted),
+                    "bracketed" => Some(KeyFormat::Bracketed),
+                    "jsonpath" => Some(KeyFormat::JsonPath),
+                    "pointer" => Some(KeyFormat::Pointer),
+                    other => return Err(usage(format!("invalid key format `{}`", other))),
+                }
+            }
+            "--include" => parsed.include.push(value(&name, &mut inline, &mut args)?),
+            "--exclude" => parsed.exclude.push(value(&name, &mut inline, &mut args)?),
+            "--keep-whole" => parsed
+                .keep_whole
+                .push(value(&name, &mut inline, &mut args)?),
+            "--stringify" => parsed.stringify.push(value(&name, &mut inline, &mut args)?),
+            "--snake-case" => parsed.key_transforms.push(KeyTransform::SnakeCase),
+            "--lowercase" => parsed.key_transforms.push(KeyTransform::Lowercase),
+            "--replace-invalid" => {
+                let replacement = value(&name, &mut inline, &mut args)?;
+                let mut chars = replacement.chars();
+                match (chars.next(), chars.next()) {
+                    (Some(c), None) => parsed.key_transforms.push(KeyTransform::ReplaceInvalid(c)),
+                    _ => {
+                        return Err(usage(format!(
+                            "the replacement must be a single character, not `{}`",
+                            replacement
+                        )));
+                    }
+                }
+            }
+            "--truncate-keys" => {
+                let len = value(&name, &mut inline, &mut args)?;
+                let len = len
+                    .parse()
+                    .map_err(|_| usage(format!("invalid key length `{}`", len)))?;
+                parsed.key_transforms.push(KeyTransform::Truncate(len));
+            }
+            "--rename" => {
+                let rename = value(&name, &mut inline, &mut args)?;
+                match rename.split_once('=') {
+                    Some((key, name)) => parsed.rename.push((key.to_string(), name.to_string())),
+                    None => {
+                        return Err(usage(format!("--rename needs KEY=NAME, not `{}`", rename)));
+                    }
+                }
+            }
+            "-d" | "--delimiter" => {
+                let delimiter = value(&name, &mut inline, &mut args)?;
+                parsed.delimiter = match delimiter.as_bytes() {
+                    [byte] if byte.is_ascii() => Some(*byte),
+                    _ => {
+                        return Err(usage(format!(
+                            "the delimiter must be a single ASCII character, not `{}`",
+                            delimiter
+                        )));
+                    }
+                }
+            }
+            "--join-arrays" => parsed.join_arrays = Some(value(&name, &mut inline, &mut args)?),
+            "--explode-arrays" => parsed.explode_arrays = true,
+            _ => return Err(usage(format!("unknown option `{}`", name))),
+        }
+
+        if inline.is_some() {
+            return Err(usage(format!("{} does not take a value", name)));
+        }
+    }
+
+    if parsed.join_arrays.is_some() && parsed.explode_arrays {
+        return Err(usage(
+            "--join-arrays and --explode-arrays cannot be used together",
+        ));
+    }
+    Ok(Command::Run(Box::new(parsed)))
+}
+
+/// Flattens records and writes them in the output format
+struct Output<'f, 'a> {
+    flattener: &'f Flattener<'a>,
+    format: OutputFormat,
+    name: String,
+    writer: BufWriter<Box<dyn Write>>,
+    /// Whether JSON output is an array of records, rather than a single one
+    array: bool,
+    /// Number of records written as JSON so far
+    written: usize,
+    /// Flattened records held until the CSV header is known
+    records: Vec<Map<String, Value>>,
+    inferrer: SchemaInferrer,
+    scratch: Scratch,
+}
+
+impl Output<'_, '_> {
+    fn write(&mut self, record: Value, at: &Location) -> Result<(), Error> {
+        let invalid = |e| Error::Input(format!("{}: {}", at, e));
+        if self.format == OutputFormat::Csv {
+            if !record.is_object() {
+                return Err(Error::Input(format!(
+                    "{}: a CSV record must be a JSON object",
+                    at
+                )));
+            }
+            let mut flattened = Map::new();
+            self.flattener
+                .try_flatten_into(&record, &mut flattened, &mut self.scratch)
+                .map_err(invalid)?;
+            self.inferrer.observe_object(&flattened);
+            self.records.push(flattened);
+            return Ok(());
+        }
+
+        let flattened = self.flattener.try_flatten_owned(record).map_err(invalid)?;
+        if self.format == OutputFormat::Json {
+            let separator: &[u8] = match (self.array, self.written) {
+                (false, _) => b"",
+                (true, 0) => b"[\n",
+                (true, _) => b",\n",
+            };
+            let end: &[u8] = if self.array { b"" } else { b"\n" };
+            self.written += 1;
+            self.writer
+                .write_all(separator)
+                .and_then(|()| {
+                    serde_json::to_writer_pretty(&mut self.writer, &flattened)
+                        .map_err(io::Error::from)
+                })
+                .and_then(|()| self.writer.write_all(end))
+        } else {
+            serde_json::to_writer(&mut self.writer, &flattened)
+                .map_err(io::Error::from)
+                .and_then(|()| self.writer.write_all(b"\n"))
+        }
+        .map_err(|e| Error::Io(self.name.clone(), e))
+    }
+
+    fn finish(mut self, options: CsvOptions) -> Result<(), Error> {
+        if self.format == OutputFormat::Json && self.array {
+            let end: &[u8] = if self.written == 0 { b"[]\n" } else { b"\n]\n" };
+            self.writer
+                .write_all(end)
+                .map_err(|e| Error::Io(self.name.clone(), e))?;
+        }
+        if self.format == OutputFormat::Csv {
+            let header: Vec<&str> = self.inferrer.columns().iter().map(|c| c.name()).collect();
+            let mut writer = self
+                .flattener
+                .csv_writer(&mut self.writer, &header, options)
+                .map_err(|e| Error::Io(self.name.clone(), e.into()))?;
+            for record in &self.records {
+                writer
+                    .write_flattened(record)
+                    .map_err(|e| Error::Io(self.name.clone(), e.into()))?;
+            }
+            writer
+                .flush()
+                .map_err(|e| Error::Io(self.name.clone(), e))?;
+        }
+        self.writer
+            .flush()
+            .map_err(|e| Error::Io(self.name.clone(), e))
+    }
+}
+
+/// Reads every record of an input and hands it to the output
+fn read(
+    reader: impl BufRead,
+    source: &str,
+    format: InputFormat,
+    output: &mut Output,
+) -> Result<(), Error> {
+    let invalid = |e: serde_json::Error, line| {
+        let at = Location { source, line };
+        if e.is_io() {
+            Error::Io(at.to_string(), e.into())
+        } else {
+            Error::Input(format!("{}: {}", at, e))
+        }
+    };
+
+    match format {
+        InputFormat::Ndjson => {
+            let mut reader = reader;
+            let mut buf = Vec::new();
+            let mut line = 0;
+            loop {
+                line += 1;
+                buf.clear();
+                let read = reader
+                    .read_until(b'\n', &mut buf)
+                    .map_err(|e| Error::Io(Location { source, line: None }.to_string(), e))?;
+                if read == 0 {
+                    return Ok(());
+                }
+                if buf.iter().all(u8::is_ascii_whitespace) {
+                    continue;
+                }
+                let record = serde_json::from_slice(&buf).map_err(|e| invalid(e, Some(line)))?;
+                output.write(
+                    record,
+                    &Location {
+                        source,
+                        line: Some(line),
+                    },
+                )?;
+            }
+        }
+        InputFormat::Json => {
+            let at = Location { source, line: None };
+            match serde_json::from_reader(reader).map_err(|e| invalid(e, None))? {
+                Value::Array(records) => {
+                    output.array = true;
+                    records
+                        .into_iter()
+                        .try_for_each(|record| output.write(record, &at))
+                }
+                record => output.write(record, &at),
+            }
+        }
+    }
+}
+
+fn run(args: Args) -> Result<(), Error> {
+    let include: Vec<&str> = args.include.iter().map(String::as_str).collect();
+    let exclude: Vec<&str> = args.exclude.iter().map(String::as_str).collect();
+    let keep_whole: Vec<&str> = args.keep_whole.iter().map(String::as_str).collect();
+    let stringify: Vec<&str> = args.stringify.iter().map(String::as_str).collect();
+    let rename: Vec<(&str, &str)> = args
+        .rename
+        .iter()
+        .map(|(key, name)| (key.as_str(), name.as_str()))
+        .collect();
+    let mut flattener = Flattener {
+        include: &include,
+        exclude: &exclude,
+        keep_whole: &keep_whole,
+        stringify: &stringify,
+        key_transforms: &args.key_transforms,
+        rename: &rename,
+        ..Default::default()
+    };
+    if let Some(separator) = &args.separator {
+        flattener.separator = separator;
+    }
+    flattener.alt_array_flattening |= args.alt_array_flattening;
+    flattener.preserve_arrays |= args.preserve_arrays;
+    flattener.escape_keys |= args.escape_keys;
+    flattener.collision_policy = args.collision_policy.unwrap_or(flattener.collision_policy);
+    flattener.max_depth = args.max_depth.or(flattener.max_depth);
+    flattener.depth_limit = args.depth_limit.unwrap_or(flattener.depth_limit);
+    flattener.max_keys = args.max_keys.or(flattener.max_keys);
+    flattener.empty_objects = args.empty_objects.unwrap_or(flattener.empty_objects);
+    flattener.key_format = args.key_format.unwrap_or(flattener.key_format);
+
+    let mut csv_options = CsvOptions::default();
+    csv_options.delimiter = args.delimiter.unwrap_or(csv_options.delimiter);
+    if let Some(separator) = &args.join_arrays {
+        csv_options.array_encoding = ArrayEncoding::Join(separator);
+    } else if args.explode_arrays {
+        csv_options.array_encoding = ArrayEncoding::Explode;
+    }
+
+    let (name, writer): (String, Box<dyn Write>) = match &args.output {
+        Some(path) => {
+            let file = File::create(path).map_err(|e| Error::Io(path.clone(), e))?;
+            (path.clone(), Box::new(file))
+        }
+        None => ("<stdout>".to_string(), Box::new(io::stdout().lock())),
+    };
+    let mut output = Output {
+        flattener: &flattener,
+        format: args.output_format.unwrap_or(match args.input_format {
+            InputFormat::Json => OutputFormat::Json,
+            InputFormat::Ndjson => OutputFormat::Ndjson,
+        }),
+        name,
+        writer: BufWriter::new(writer),
+        array: args.input_format == InputFormat::Ndjson || args.files.len() > 1,
+        written: 0,
+        records: Vec::new(),
+        inferrer: SchemaInferrer::new(),
+        scratch: Scratch::new(),
+    };
+
+    let stdin = ["-".to_string()];
+    let files = if args.files.is_empty() {
+        &stdin[..]
+    } else {
+        &args.files[..]
+    };
+    for file in files {
+        if file == "-" {
+            read(io::stdin().lock(), file, args.input_format, &mut output)?;
+        } else {
+            let reader = File::open(file).map_err(|e| Error::Io(file.clone(), e))?;
+            read(BufReader::new(reader), file, args.input_format, &mut output)?;
+        }
+    }
+    output.finish(csv_options)
+}
+
+fn main() -> ExitCode {
+    let result = parse_args(env::args().skip(1)).and_then(|command| match command {
+        Command::Help => {
+            println!("{}", USAGE);
+            Ok(())
+        }
+        Command::Version => {
+            println!("flatten-json {}", env!("CARGO_PKG_VERSION"));
+            Ok(())
+        }
+        Command::Run(args) => run(*args),
+    });
+
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        // The reader of the output went away, such as `flatten-json file.json | head`
+        Err(Error::Io(_, e)) if e.kind() == io::ErrorKind::BrokenPipe => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("flatten-json: {}", e);
+            ExitCode::from(e.exit_code())
+        }
     }
 }
@@ -0,0 +1,500 @@
+//! CSV output of flattened records, enabled by the `csv` feature.
+
+use std::collections::HashMap;
+use std::io;
+
+use serde_json::Map;
+use serde_json::Value;
+
+use crate::Flattener;
+use crate::SchemaInferrer;
+use crate::Scratch;
+
+/// How a column whose value is an array is written to a CSV cell
+/// # Examples
+/// ```
+/// use smooth_json::{ArrayEncoding, CsvOptions};
+///
+/// let options = CsvOptions { array_encoding: ArrayEncoding::Join("|"), ..Default::default() };
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ArrayEncoding<'a> {
+    /// Write the array as JSON text, such as `[1,"a"]`
+    #[default]
+    Json,
+    /// Join the elements with the given delimiter, such as `1|a`. Strings are written as they
+    /// are and other elements as JSON text
+    Join(&'a str),
+    /// Write one row per element
+    ///
+    /// Rows are aligned across array columns: row `i` holds element `i` of every array, or an
+    /// empty cell when an array is shorter. Columns that are not arrays are repeated on every
+    /// row.
+    Explode,
+}
+
+/// Options for writing flattened records as CSV
+/// # Examples
+/// ```
+/// use smooth_json::{ArrayEncoding, CsvOptions};
+///
+/// let options = CsvOptions { delimiter: b';', array_encoding: ArrayEncoding::Explode };
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CsvOptions<'a> {
+    /// Byte separating the cells of a row, `,` by default
+    pub delimiter: u8,
+    /// How array values are written, see [`ArrayEncoding`]
+    pub array_encoding: ArrayEncoding<'a>,
+}
+
+impl Default for CsvOptions<'_> {
+    fn default() -> Self {
+        CsvOptions {
+            delimiter: b',',
+            array_encoding: ArrayEncoding::Json,
+        }
+    }
+}
+
+/// Writes records as CSV rows under a fixed header, created by [`Flattener::csv_writer`]
+///
+/// Each record is flattened, then written with a cell for every column of the header. Columns
+/// the record does not have are left empty, as are `null` values, and keys that are not in the
+/// header are skipped. A record that is not a JSON object has no columns, and is rejected with
+/// an error.
+/// # Examples
+/// ```
+/// use smooth_json::{CsvOptions, Flattener};
+/// use serde_json::json;
+///
+/// let flattener = Flattener::new();
+/// let mut writer = flattener
+///     .csv_writer(Vec::new(), &["id", "user.name"], CsvOptions::default())
+///     .unwrap();
+///
+/// writer.write_record(&json!({"id": 1, "user": {"name": "Alice"}})).unwrap();
+/// writer.write_record(&json!({"id": 2, "extra": true})).unwrap();
+///
+/// let csv = String::from_utf8(writer.into_inner().unwrap()).unwrap();
+/// assert_eq!(csv, "id,user.name\n1,Alice\n2,\n");
+/// ```
+pub struct CsvWriter<'f, 'a, W: io::Write> {
+    flattener: &'f Flattener<'a>,
+    array_encoding: ArrayEncoding<'a>,
+    writer: ::csv::Writer<W>,
+    /// Position of each column of the header
+    index: HashMap<String, usize>,
+    record: Map<String, Value>,
+    scratch: Scratch,
+    row: Vec<String>,
+}
+
+impl<'f, 'a, W: io::Write> CsvWriter<'f, 'a, W> {
+    /// Flattens a record and writes it as one or more rows
+    ///
+    /// # Arguments
+    ///
+    /// * `record` - A serde_json Value to flatten
+    ///
+    /// # Examples
+    /// ```
+    /// use smooth_json::{ArrayEncoding, CsvOptions, Flattener};
+    /// use serde_json::json;
+    ///
+    /// let flattener = Flattener::new();
+    /// let options = CsvOptions { array_encoding: ArrayEncoding::Explode, ..Default::default() };
+    /// let mut writer = flattener.csv_writer(Vec::new(), &["id", "tags"], options).unwrap();
+    ///
+    /// writer.write_record(&json!({"id": 1, "tags": ["a", "b"]})).unwrap();
+    ///
+    /// let csv = String::from_utf8(writer.into_inner().unwrap()).unwrap();
+    /// assert_eq!(csv, "id,tags\n1,a\n1,b\n");
+    /// ```
+    pub fn write_record(&mut self, record: &Value) -> ::csv::Result<()> {
+        if !record.is_object() {
+            return Err(not_an_object());
+        }
+        let mut flattened = std::mem::take(&mut self.record);
+        flattened.clear();
+        self.flattener
+            .flatten_into(record, &mut flattened, &mut self.scratch);
+        let result = self.write_flattened(&flattened);
+        self.record = flattened;
+        result
+    }
+
+    /// Writes a record that is already flattened as one or more rows
+    ///
+    /// The keys of the record are matched with the header as they are, so it should come from
+    /// the same flattener, such as records held while the header is found.
+    ///
+    /// # Arguments
+    ///
+    /// * `record` - A flattened record
+    ///
+    /// # Examples
+    /// ```
+    /// use smooth_json::{CsvOptions, Flattener};
+    /// use serde_json::{json, Map};
+    ///
+    /// let flattener = Flattener::new();
+    /// let mut writer = flattener.csv_writer(Vec::new(), &["a.b"], CsvOptions::default()).unwrap();
+    ///
+    /// let mut record = Map::new();
+    /// record.insert("a.b".to_string(), json!(1));
+    /// writer.write_flattened(&record).unwrap();
+    ///
+    /// assert_eq!(writer.into_inner().unwrap(), b"a.b\n1\n");
+    /// ```
+    pub fn write_flattened(&mut self, record: &Map<String, Value>) -> ::csv::Result<()> {
+        let mut cells: Vec<Option<&Value>> = vec![None; self.row.len()];
+        for (key, value) in record {
+            if let Some(&i) = self.index.get(key) {
+                cells[i] = Some(value);
+            }
+        }
+
+        let rows = match self.array_encoding {
+            ArrayEncoding::Explode => cells
+                .iter()
+                .filter_map(|cell| match cell {
+                    Some(Value::Array(elements)) => Some(elements.len()),
+                    _ => None,
+                })
+                .max()
+                .unwrap_or(1)
+                .max(1),
+            ArrayEncoding::Json | ArrayEncoding::Join(_) => 1,
+        };
+
+        for i in 0..rows {
+            for (cell, out) in cells.iter().zip(&mut self.row) {
+                out.clear();
+                match (cell, self.array_encoding) {
+                    (Some(Value::Array(elements)), ArrayEncoding::Explode) => {
+                        if let Some(element) = elements.get(i) {
+                            write_scalar(element, out);
+                        }
+                    }
+                    (Some(Value::Array(elements)), ArrayEncoding::Join(delimiter)) => {
+                        for (j, element) in elements.iter().enumerate() {
+                            if j > 0 {
+                                out.push_str(delimiter);
+                            }
+                            write_scalar(element, out);
+                        }
+                    }
+                    (Some(value), _) => write_scalar(value, out),
+                    (None, _) => {}
+                }
+            }
+            self.writer.write_record(&self.row)?;
+        }
+        Ok(())
+    }
+
+    /// Flushes the rows written so far to the underlying writer
+    /// # Examples
+    /// ```
+    /// use smooth_json::{CsvOptions, Flattener};
+    ///
+    /// let flattener = Flattener::new();
+    /// let mut writer = flattener.csv_writer(Vec::new(), &["a"], CsvOptions::default()).unwrap();
+    ///
+    /// writer.flush().unwrap();
+    /// ```
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+
+    /// Flushes the rows written so far and returns the underlying writer
+    /// # Examples
+    /// ```
+    /// use smooth_json::{CsvOptions, Flattener};
+    ///
+    /// let flattener = Flattener::new();
+    /// let writer = flattener.csv_writer(Vec::new(), &["a"], CsvOptions::default()).unwrap();
+    ///
+    /// assert_eq!(writer.into_inner().unwrap(), b"a\n");
+    /// ```
+    pub fn into_inner(self) -> ::csv::Result<W> {
+        self.writer.into_inner().map_err(|e| e.into_error().into())
+    }
+}
+
+/// Error for a record that is not a JSON object, and so cannot be a row.
+fn not_an_object() -> ::csv::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidInput,
+        "a CSV record must be a JSON object",
+    )
+    .into()
+}
+
+/// Writes a value to a cell: `null` as nothing, strings as they are and anything else as JSON
+/// text.
+fn write_scalar(value: &Value, out: &mut String) {
+    match value {
+        Value::Null => {}
+        Value::String(s) => out.push_str(s),
+        value => out.push_str(&value.to_string()),
+    }
+}
+
+impl<'a> Flattener<'a> {
+    /// Returns a writer of flattened records as CSV rows, with a header declared upfront
+    ///
+    /// The header row is written straight away, so records can be streamed from a source of
+    /// any size. Use [`Flattener::write_csv`] to find the header from the records instead.
+    ///
+    /// # Arguments
+    ///
+    /// * `writer` - Where the CSV text is written to
+    /// * `header` - The flattened keys to write a column for, in order, each only once
+    /// * `options` - How the CSV is written
+    ///
+    /// # Examples
+    /// ```
+    /// use smooth_json::{ArrayEncoding, CsvOptions, Flattener};
+    /// use serde_json::json;
+    ///
+    /// let flattener = Flattener::new();
+    /// let options = CsvOptions { array_encoding: ArrayEncoding::Join("|"), ..Default::default() };
+    /// let mut writer = flattener.csv_writer(Vec::new(), &["tags"], options).unwrap();
+    ///
+    /// writer.write_record(&json!({"tags": ["a", "b"]})).unwrap();
+    ///
+    /// assert_eq!(writer.into_inner().unwrap(), b"tags\na|b\n");
+    /// ```
+    pub fn csv_writer<'f, W: io::Write, S: AsRef<str>>(
+        &'f self,
+        writer: W,
+        header: &[S],
+        options: CsvOptions<'a>,
+    ) -> ::csv::Result<CsvWriter<'f, 'a, W>> {
+        let mut index = HashMap::new();
+        for (i, column) in header.iter().enumerate() {
+            if index.insert(column.as_ref().to_string(), i).is_some() {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!(
+                        "column `{}` appears twice in the CSV header",
+                        column.as_ref()
+                    ),
+                )
+                .into());
+            }
+        }
+
+        let mut writer = ::csv::WriterBuilder::new()
+            .delimiter(options.delimiter)
+            .from_writer(writer);
+        writer.write_record(header.iter().map(AsRef::as_ref))?;
+
+        Ok(CsvWriter {
+            flattener: self,
+            array_encoding: options.array_encoding,
+            writer,
+            index,
+            record: Map::new(),
+            scratch: Scratch::new(),
+            row: vec![String::new(); header.len()],
+        })
+    }
+
+    /// Writes records as CSV, with a column for every key found in them
+    ///
+    /// The header lists keys in the order they are first seen in, so the records are flattened
+    /// and held in memory before anything is written. Every record must be a JSON object.
+    ///
+    /// # Arguments
+    ///
+    /// * `writer` - Where the CSV text is written to
+    /// * `records` - The records to flatten
+    /// * `options` - How the CSV is written
+    ///
+    /// # Examples
+    /// ```
+    /// use smooth_json::{CsvOptions, Flattener};
+    /// use serde_json::json;
+    ///
+    /// let flattener = Flattener::new();
+    /// let records = vec![json!({"b": 1}), json!({"a": "x,y", "b": 2})];
+    ///
+    /// let mut csv = Vec::new();
+    /// flattener.write_csv(&mut csv, &records, CsvOptions::default()).unwrap();
+    ///
+    /// assert_eq!(String::from_utf8(csv).unwrap(), "b,a\n1,\n2,\"x,y\"\n");
+    /// ```
+    pub fn write_csv<'r, W, I>(
+        &self,
+        writer: W,
+        records: I,
+        options: CsvOptions<'a>,
+    ) -> ::csv::Result<()>
+    where
+        W: io::Write,
+        I: IntoIterator<Item = &'r Value>,
+    {
+        let mut inferrer = SchemaInferrer::new();
+        let mut scratch = Scratch::new();
+        let mut flattened = vec![];
+        for record in records {
+            if !record.is_object() {
+                return Err(not_an_object());
+            }
+            let mut row = Map::new();
+            self.flatten_into(record, &mut row, &mut scratch);
+            inferrer.observe_object(&row);
+            flattened.push(row);
+        }
+        let header: Vec<&str> = inferrer.columns().iter().map(|c| c.name()).collect();
+
+        let mut writer = self.csv_writer(writer, &header, options)?;
+        for row in &flattened {
+            writer.write_flattened(row)?;
+        }
+        writer.flush()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use serde_json::json;
+
+    use crate::DepthLimit;
+
+    fn write(flattener: &Flattener, records: &[Value], options: CsvOptions) -> String {
+        let mut csv = Vec::new();
+        flattener.write_csv(&mut csv, records, options).unwrap();
+        String::from_utf8(csv).unwrap()
+    }
+
+    #[test]
+    fn csv_header_in_first_seen_order() {
+        let flattener = Flattener::new();
+        let records = [
+            json!({"z": 1, "a": {"b": null}}),
+            json!({"m": "quoted \"text\"\nwith newline", "z": 2}),
+        ];
+
+        assert_eq!(
+            write(&flattener, &records, CsvOptions::default()),
+            "a.b,z,m\n,1,\n,2,\"quoted \"\"text\"\"\nwith newline\"\n"
+        );
+    }
+
+    #[test]
+    fn csv_rejects_records_that_are_not_objects() {
+        let flattener = Flattener::new();
+        let mut csv = Vec::new();
+        let records = [json!({"a": 1}), json!("root")];
+
+        let error = flattener
+            .write_csv(&mut csv, &records, CsvOptions::default())
+            .unwrap_err();
+        assert_eq!(error.to_string(), "a CSV record must be a JSON object");
+        assert!(csv.is_empty());
+
+        let mut writer = flattener
+            .csv_writer(Vec::new(), &["a"], CsvOptions::default())
+            .unwrap();
+        assert!(writer.write_record(&json!([1])).is_err());
+        writer.write_record(&records[0]).unwrap();
+        assert_eq!(writer.into_inner().unwrap(), b"a\n1\n");
+    }
+
+    #[test]
+    fn csv_array_encodings() {
+        // Keeps the arrays whole, so they can hold arrays and objects
+        let flattener = Flattener {
+            max_depth: Some(1),
+            depth_limit: DepthLimit::Keep,
+            ..Default::default()
+        };
+        let records = [json!({
+            "id": 1,
+            "tags": ["a", "b", "c"],
+            "scores": [1.5, null],
+            "empty": [],
+            "nested": [[1, 2], {"k": "v"}]
+        })];
+
+        let json = CsvOptions::default();
+        assert_eq!(
+            write(&flattener, &records, json),
+            "empty,id,nested,scores,tags\n\
+             [],1,\"[[1,2],{\"\"k\"\":\"\"v\"\"}]\",\"[1.5,null]\",\"[\"\"a\"\",\"\"b\"\",\"\"c\"\"]\"\n"
+        );
+
+        let join = CsvOptions {
+            array_encoding: ArrayEncoding::Join("|"),
+            ..Default::default()
+        };
+        assert_eq!(
+            write(&flattener, &records, join),
+            "empty,id,nested,scores,tags\n,1,\"[1,2]|{\"\"k\"\":\"\"v\"\"}\",1.5|,a|b|c\n"
+        );
+
+        let explode = CsvOptions {
+            delimiter: b';',
+            array_encoding: ArrayEncoding::Explode,
+        };
+        assert_eq!(
+            write(&flattener, &records, explode),
+            "empty;id;nested;scores;tags\n\
+             ;1;[1,2];1.5;a\n\
+             ;1;\"{\"\"k\"\":\"\"v\"\"}\";;b\n\
+             ;1;;;c\n"
+        );
+    }
+
+    #[test]
+    fn csv_explode_without_arrays() {
+        let flattener = Flattener::new();
+        let explode = CsvOptions {
+            array_encoding: ArrayEncoding::Explode,
+            ..Default::default()
+        };
+        let records = [json!({"a": 1, "b": []}), json!({"a": 2})];
+
+        assert_eq!(write(&flattener, &records, explode), "a,b\n1,\n2,\n");
+    }
+
+    #[test]
+    fn csv_declared_header() {
+        let flattener = Flattener {
+            separator: "_",
+            ..Default::default()
+        };
+        let mut writer = flattener
+            .csv_writer(Vec::new(), &["b_c", "missing"], CsvOptions::default())
+            .unwrap();
+
+        writer
+            .write_record(&json!({"a": 1, "b": {"c": true}}))
+            .unwrap();
+
+        assert_eq!(
+            writer.into_inner().unwrap(),
+            b"b_c,missing\ntrue,\n".to_vec()
+        );
+
+        let duplicate = flattener
+            .csv_writer(
+                Vec::new(),
+                &["b_c", "missing", "b_c"],
+                CsvOptions::default(),
+            )
+            .err()
+            .unwrap();
+        assert_eq!(
+            duplicate.to_string(),
+            "column `b_c` appears twice in the CSV header"
+        );
+    }
+}
@@ -0,0 +1,509 @@
+//! Flattening driven by a serde `Deserializer`, without building the input as a `Value` first.
+//!
+//! Each value is inserted into the output as soon as it is parsed, see `emit`. Only subtrees
+//! that are kept whole because of `max_depth`, `keep_whole` or `stringify` are collected into a
+//! `Value`.
+
+use std::fmt;
+use std::io;
+
+use serde::de;
+use serde::de::DeserializeSeed;
+use serde::de::IgnoredAny;
+use serde::de::MapAccess;
+use serde::de::SeqAccess;
+use serde::de::Visitor;
+use serde_json::Map;
+use serde_json::Number;
+use serde_json::Value;
+
+use crate::Flattener;
+use crate::Scratch;
+use crate::emit::Emitter;
+use crate::filter::Visit;
+
+/// Flattens a value as it is deserialized, created by [`Flattener::seed`]
+///
+/// Flattened keys are added to the output as the deserializer produces them, so the input never
+/// exists as a `serde_json::Value`. The output is the same as [`Flattener::flatten_into`] with
+/// two exceptions: values merged by a collision are in document order rather than in the key
+/// order of `serde_json::Map`, and a key repeated within one object is kept for every
+/// occurrence instead of only the last one.
+/// # Examples
+/// ```
+/// use smooth_json::{Flattener, Scratch};
+/// use serde::de::DeserializeSeed;
+/// use serde_json::{json, Map};
+///
+/// let flattener = Flattener::new();
+/// let mut out = Map::new();
+/// let mut scratch = Scratch::new();
+///
+/// let mut deserializer = serde_json::Deserializer::from_str(r#"{"a": {"b": 1}}"#);
+/// flattener.seed(&mut out, &mut scratch).deserialize(&mut deserializer).unwrap();
+/// deserializer.end().unwrap();
+///
+/// assert_eq!(out["a.b"], json!(1));
+/// ```
+pub struct FlattenSeed<'f, 'a, 'm> {
+    flattener: &'f Flattener<'a>,
+    out: &'m mut Map<String, Value>,
+    scratch: &'m mut Scratch,
+}
+
+impl<'de, 'f, 'a, 'm> DeserializeSeed<'de> for FlattenSeed<'f, 'a, 'm> {
+    type Value = ();
+
+    fn deserialize<D: de::Deserializer<'de>>(self, deserializer: D) -> Result<(), D::Error> {
+        let mut emitter = Emitter::new(self.flattener, self.out, self.scratch);
+        match emitter.visit(false) {
+            Visit::Skip => deserializer.deserialize_ignored_any(IgnoredAny).map(drop),
+            visit => Node {
+                emitter: &mut emitter,
+                arr: false,
+                depth: 0,
+                included: visit == Visit::Include,
+            }
+            .deserialize(deserializer),
+        }
+    }
+}
+
+/// A value whose key is already in the emitter's key buffer.
+struct Node<'s, 'f, 'a, 'm> {
+    emitter: &'s mut Emitter<'f, 'a, 'm>,
+    arr: bool,
+    depth: usize,
+    /// Whether the value is part of the output, rather than only some of its children
+    included: bool,
+}
+
+impl Node<'_, '_, '_, '_> {
+    fn leaf<E: de::Error>(self, value: Value) -> Result<(), E> {
+        if !self.included {
+            return Ok(());
+        }
+        self.emitter.insert(value, self.arr).map_err(E::custom)
+    }
+}
+
+impl<'de> DeserializeSeed<'de> for Node<'_, '_, '_, '_> {
+    type Value = ();
+
+    fn deserialize<D: de::Deserializer<'de>>(self, deserializer: D) -> Result<(), D::Error> {
+        deserializer.deserialize_any(self)
+    }
+}
+
+impl<'de> Visitor<'de> for Node<'_, '_, '_, '_> {
+    type Value = ();
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("any valid JSON value")
+    }
+
+    fn visit_bool<E: de::Error>(self, v: bool) -> Result<(), E> {
+        self.leaf(Value::Bool(v))
+    }
+
+    fn visit_i64<E: de::Error>(self, v: i64) -> Result<(), E> {
+        self.leaf(Value::from(v))
+    }
+
+    fn visit_u64<E: de::Error>(self, v: u64) -> Result<(), E> {
+        self.leaf(Value::from(v))
+    }
+
+    fn visit_f64<E: de::Error>(self, v: f64) -> Result<(), E> {
+        // Matches `Value`, which has no representation for NaN or infinities
+        let value = Number::from_f64(v).map_or(Value::Null, Value::Number);
+        self.leaf(value)
+    }
+
+    fn visit_str<E: de::Error>(self, v: &str) -> Result<(), E> {
+        self.leaf(Value::String(v.to_string()))
+    }
+
+    fn visit_string<E: de::Error>(self, v: String) -> Result<(), E> {
+        self.leaf(Value::String(v))
+    }
+
+    fn visit_unit<E: de::Error>(self) -> Result<(), E> {
+        self.leaf(Value::Null)
+    }
+
+    fn visit_none<E: de::Error>(self) -> Result<(), E> {
+        self.visit_unit()
+    }
+
+    fn visit_some<D: de::Deserializer<'de>>(self, deserializer: D) -> Result<(), D::Error> {
+        self.deserialize(deserializer)
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<(), A::Error> {
+        let emitter = self.emitter;
+        if emitter.kept_whole(self.depth) && !self.included {
+            return IgnoredAny.visit_seq(seq).map(drop);
+        }
+        if emitter.kept_whole(self.depth) {
+            let mut elements = Vec::new();
+            while let Some(element) = seq.next_element::<Value>()? {
+                elements.push(element);
+            }
+            return emitter
+                .insert_subtree(Value::Array(elements), self.arr)
+                .map_err(de::Error::custom);
+        }
+
+        let mark = emitter.mark();
+        let arr = emitter.flattener.alt_array_flattening;
+        let mut len = 0;
+        loop {
+            if emitter.flattener.preserve_arrays {
+                emitter.push_index(len);
+            }
+            let element = match emitter.visit(self.included) {
+                Visit::Skip => seq.next_element::<IgnoredAny>()?.map(drop),
+                visit => seq.next_element_seed(Node {
+                    emitter: &mut *emitter,
+                    arr,
+                    depth: self.depth + 1,
+                    included: visit == Visit::Include,
+                })?,
+            };
+            emitter.truncate(mark);
+            if element.is_none() {
+                break;
+            }
+            len += 1;
+        }
+
+        if len == 0 && self.included {
+            emitter
+                .insert_subtree(Value::Array(Vec::new()), self.arr)
+                .map_err(de::Error::custom)?;
+        }
+        Ok(())
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<(), A::Error> {
+        let emitter = self.emitter;
+        if emitter.kept_whole(self.depth) && !self.included {
+            return IgnoredAny.visit_map(map).map(drop);
+        }
+        if emitter.kept_whole(self.depth) {
+            let mut entries = Map::new();
+            while let Some((key, value)) = map.next_entry::<String, Value>()? {
+                entries.insert(key, value);
+            }
+            return emitter
+                .insert_subtree(Value::Object(entries), self.arr)
+                .map_err(de::Error::custom);
+        }
+
+        let mark = emitter.mark();
+        let mut empty = true;
+        while map.next_key_seed(KeySeed { emitter })?.is_some() {
+            empty = false;
+            match emitter.visit(self.included) {
+                Visit::Skip => map.next_value::<IgnoredAny>().map(drop)?,
+                visit => map.next_value_seed(Node {
+                    emitter: &mut *emitter,
+                    arr: self.arr,
+                    depth: self.depth + 1,
+                    included: visit == Visit::Include,
+                })?,
+            }
+            emitter.truncate(mark);
+        }
+
+        if empty && self.included {
+            emitter
+                .insert_subtree(Value::Object(Map::new()), self.arr)
+                .map_err(de::Error::custom)?;
+        }
+        Ok(())
+    }
+}
+
+/// An object key, appended to the emitter's key buffer as it is deserialized.
+struct KeySeed<'s, 'f, 'a, 'm> {
+    emitter: &'s mut Emitter<'f, 'a, 'm>,
+}
+
+impl<'de> DeserializeSeed<'de> for KeySeed<'_, '_, '_, '_> {
+    type Value = ();
+
+    fn deserialize<D: de::Deserializer<'de>>(self, deserializer: D) -> Result<(), D::Error> {
+        deserializer.deserialize_str(self)
+    }
+}
+
+impl<'de> Visitor<'de> for KeySeed<'_, '_, '_, '_> {
+    type Value = ();
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("a string key")
+    }
+
+    fn visit_str<E: de::Error>(self, v: &str) -> Result<(), E> {
+        self.emitter.push_key(v);
+        Ok(())
+    }
+}
+
+impl<'a> Flattener<'a> {
+    /// Returns a [`DeserializeSeed`] that flattens a value into `out` while it is deserialized
+    ///
+    /// This gives the same result as [`Flattener::flatten_into`] without building the input as
+    /// a `serde_json::Value` first, see [`FlattenSeed`] for the differences. Like `flatten_into`,
+    /// collisions and depth limits never fail, so every error comes from the deserializer.
+    ///
+    /// # Arguments
+    ///
+    /// * `out` - The object to add flattened keys to
+    /// * `scratch` - Buffers reused between calls
+    ///
+    /// # Examples
+    /// ```
+    /// use smooth_json::{Flattener, Scratch};
+    /// use serde::de::DeserializeSeed;
+    /// use serde_json::{json, Map};
+    ///
+    /// let flattener = Flattener { preserve_arrays: true, ..Default::default() };
+    /// let mut out = Map::new();
+    ///
+    /// let mut deserializer = serde_json::Deserializer::from_str(r#"{"a": [1, 2]}"#);
+    /// flattener.seed(&mut out, &mut Scratch::new()).deserialize(&mut deserializer).unwrap();
+    ///
+    /// assert_eq!(out["a.1"], json!(2));
+    /// ```
+    pub fn seed<'f, 'm>(
+        &'f self,
+        out: &'m mut Map<String, Value>,
+        scratch: &'m mut Scratch,
+    ) -> FlattenSeed<'f, 'a, 'm> {
+        FlattenSeed {
+            flattener: self,
+            out,
+            scratch,
+        }
+    }
+
+    /// Flattens a JSON document as it is parsed from a string
+    ///
+    /// Gives the same result as parsing `json` and calling [`Flattener::flatten`], see
+    /// [`FlattenSeed`] for the exceptions.
+    ///
+    /// # Arguments
+    ///
+    /// * `json` - A JSON document
+    ///
+    /// # Examples
+    /// ```
+    /// use smooth_json;
+    /// use serde_json::json;
+    ///
+    /// let flattener = smooth_json::Flattener::new();
+    /// let flattened = flattener.flatten_from_str(r#"{"a": {"b": [1, 2]}}"#).unwrap();
+    ///
+    /// assert_eq!(flattened, json!({"a.b": [1, 2]}));
+    /// ```
+    pub fn flatten_from_str(&self, json: &str) -> serde_json::Result<Value> {
+        self.flatten_from(&mut serde_json::Deserializer::from_str(json))
+    }
+
+    /// Flattens a JSON document as it is parsed from a reader
+    ///
+    /// Only the flattened output is kept in memory, so this suits documents too large to hold
+    /// as a `serde_json::Value`. Wrap unbuffered readers such as files in a `BufReader`.
+    ///
+    /// # Arguments
+    ///
+    /// * `reader` - A reader over a JSON document
+    ///
+    /// # Examples
+    /// ```
+    /// use smooth_json;
+    /// use serde_json::json;
+    ///
+    /// let flattener = smooth_json::Flattener::new();
+    /// let flattened = flattener.flatten_from_reader(r#"[{"a": 1}, {"a": 2}]"#.as_bytes()).unwrap();
+    ///
+    /// assert_eq!(flattened, json!({"a": [1, 2]}));
+    /// ```
+    pub fn flatten_from_reader<R: io::Read>(&self, reader: R) -> serde_json::Result<Value> {
+        self.flatten_from(&mut serde_json::Deserializer::from_reader(reader))
+    }
+
+    fn flatten_from<'de, R: serde_json::de::Read<'de>>(
+        &self,
+        deserializer: &mut serde_json::Deserializer<R>,
+    ) -> serde_json::Result<Value> {
+        let mut flattened_val = Map::new();
+        self.seed(&mut flattened_val, &mut Scratch::new())
+            .deserialize(&mut *deserializer)?;
+        deserializer.end()?;
+        Ok(Value::Object(flattened_val))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use serde_json::json;
+
+    use crate::CollisionPolicy;
+    use crate::DepthLimit;
+    use crate::EmptyObjects;
+
+    fn flatteners() -> Vec<Flattener<'static>> {
+        vec![
+            Flattener::new(),
+            Flattener {
+                preserve_arrays: true,
+                ..Default::default()
+            },
+            Flattener {
+                alt_array_flattening: true,
+                ..Default::default()
+            },
+            Flattener {
+                separator: "::",
+                escape_keys: true,
+                ..Default::default()
+            },
+        ]
+    }
+
+    fn assert_same_output(flattener: &Flattener, input: &Value) {
+        let streamed = flattener.flatten_from_str(&input.to_string()).unwrap();
+        assert_eq!(streamed, flattener.flatten(input), "input: {}", input);
+    }
+
+    #[test]
+    fn streaming_matches_flatten() {
+        let inputs = [
+            json!({"name": "John", "address": {"city": "Anytown", "zip": 12345}}),
+            json!({"a": [{"b": 1}, {"b": [2, 3]}], "c": [], "d": {"e": null}}),
+            json!({"a": [[1, [2, {"x": 3}]], [4]], "b": [1.5, -2, true, "s"]}),
+            json!({"a.b": {"c::d": 1}, "e\\f": [{}, {"g": {}}]}),
+            json!([{"a": 1}, {"a": 2}, 3]),
+            json!("only"),
+            json!([]),
+            json!({}),
+        ];
+
+        for flattener in flatteners() {
+            for input in &inputs {
+                assert_same_output(&flattener, input);
+            }
+        }
+    }
+
+    #[test]
+    fn streaming_honors_options() {
+        let input = json!({
+            "a": {"b": {"c": [1, {"d": 2}]}, "e": {}},
+            "f": [[], {}, {"g": [1, 2]}],
+            "h": {"i": 1},
+            "h.i": 2
+        });
+
+        for empty_objects in [EmptyObjects::Omit, EmptyObjects::Object, EmptyObjects::Null] {
+            for depth_limit in [DepthLimit::Stringify, DepthLimit::Keep, DepthLimit::Drop] {
+                for max_depth in [None, Some(0), Some(1), Some(2), Some(3)] {
+                    let flattener = Flattener {
+                        collision_policy: CollisionPolicy::Suffix,
+                        empty_objects,
+                        depth_limit,
+                        max_depth,
+                        ..Default::default()
+                    };
+                    assert_same_output(&flattener, &input);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn streaming_honors_filters() {
+        let input = json!({
+            "a": {"b": {"c": [1, {"d": 2}]}, "e": {}},
+            "f": [[], {}, {"g": [1, 2]}],
+            "h": {"i": 1, "password": "x"}
+        });
+        let filters: [(&[&str], &[&str]); 5] = [
+            (&["a.*"], &[]),
+            (&["**.d", "f.g"], &[]),
+            (&[], &["**.password", "a.b"]),
+            (&["a", "h"], &["h.*"]),
+            (&["*"], &[""]),
+        ];
+
+        for (include, exclude) in filters {
+            for max_depth in [None, Some(1), Some(2)] {
+                for preserve_arrays in [false, true] {
+                    let flattener = Flattener {
+                        include,
+                        exclude,
+                        max_depth,
+                        preserve_arrays,
+                        empty_objects: EmptyObjects::Object,
+                        ..Default::default()
+                    };
+                    assert_same_output(&flattener, &input);
+                }
+            }
+        }
+
+        let stops: [(&[&str], &[&str]); 3] = [
+            (&["a.b", "f"], &["a.b.c"]),
+            (&["**"], &[]),
+            (&[], &["h", "a.e", "f"]),
+        ];
+        for (keep_whole, stringify) in stops {
+            for alt_array_flattening in [false, true] {
+                let flattener = Flattener {
+                    keep_whole,
+                    stringify,
+                    alt_array_flattening,
+                    max_depth: Some(2),
+                    ..Default::default()
+                };
+                assert_same_output(&flattener, &input);
+            }
+        }
+    }
+
+    #[test]
+    fn streaming_reports_parse_errors() {
+        let flattener = Flattener::new();
+
+        assert!(flattener.flatten_from_str(r#"{"a": [1, 2}"#).is_err());
+        assert!(flattener.flatten_from_str(r#"{"a": 1} {"b": 2}"#).is_err());
+        assert!(flattener.flatten_from_reader("".as_bytes()).is_err());
+    }
+
+    #[test]
+    fn seed_reuses_scratch() {
+        let flattener = Flattener {
+            collision_policy: CollisionPolicy::KeepFirst,
+            ..Default::default()
+        };
+        let mut out = Map::new();
+        let mut scratch = Scratch::new();
+
+        for input in [r#"{"a": {"b": 1}}"#, r#"{"a.b": 2}"#] {
+            out.clear();
+            let mut deserializer = serde_json::Deserializer::from_str(input);
+            flattener
+                .seed(&mut out, &mut scratch)
+                .deserialize(&mut deserializer)
+                .unwrap();
+        }
+
+        assert_eq!(Value::Object(out), json!({"a.b": 2}));
+    }
+}
@@ -0,0 +1,142 @@
+//! Output shared by the flatteners that serde drives, rather than ones that walk a `Value`.
+//!
+//! The deserializer and serializer hand over one value at a time, so the key of the current
+//! value lives in the buffers of a `Scratch`, extended before each child and truncated back
+//! after it.
+
+use std::borrow::Cow;
+
+use serde_json::Map;
+use serde_json::Value;
+
+use crate::Builder;
+use crate::EmptyObjects;
+use crate::FlattenError;
+use crate::Flattener;
+use crate::Scratch;
+use crate::filter::Filter;
+use crate::filter::Stop;
+use crate::filter::Visit;
+use crate::walk;
+
+pub(crate) struct Emitter<'f, 'a, 'm> {
+    pub(crate) flattener: &'f Flattener<'a>,
+    builder: Builder<'m>,
+    /// Flattened key of the current value
+    key: &'m mut String,
+    /// Source path of the current value, see `Flattener::insert`
+    source: &'m mut String,
+    filter: &'m mut Filter,
+}
+
+/// Lengths of the buffers of an `Emitter`, to `truncate` back to after a child.
+#[derive(Clone, Copy)]
+pub(crate) struct Mark {
+    key: usize,
+    source: usize,
+    path: usize,
+}
+
+impl<'f, 'a, 'm> Emitter<'f, 'a, 'm> {
+    /// Starts a lenient flatten into `out`, reusing the buffers of `scratch`.
+    pub(crate) fn new(
+        flattener: &'f Flattener<'a>,
+        out: &'m mut Map<String, Value>,
+        scratch: &'m mut Scratch,
+    ) -> Self {
+        let Scratch {
+            key,
+            source,
+            sources,
+            filter,
+        } = scratch;
+        key.clear();
+        source.clear();
+        sources.clear();
+        filter.reset(flattener);
+
+        Emitter {
+            flattener,
+            builder: Builder {
+                map: out,
+                sources,
+                strict: false,
+            },
+            key,
+            source,
+            filter,
+        }
+    }
+
+    pub(crate) fn mark(&self) -> Mark {
+        Mark {
+            key: self.key.len(),
+            source: self.source.len(),
+            path: self.filter.len(),
+        }
+    }
+
+    pub(crate) fn truncate(&mut self, mark: Mark) {
+        self.key.truncate(mark.key);
+        self.source.truncate(mark.source);
+        self.filter.truncate(mark.path);
+    }
+
+    pub(crate) fn push_key(&mut self, segment: &str) {
+        self.flattener.push_key(self.key, self.source, segment);
+        self.filter.push_key(segment);
+    }
+
+    pub(crate) fn push_index(&mut self, index: usize) {
+        self.flattener.push_index(self.key, self.source, index);
+        self.filter.push_index(index);
+    }
+
+    /// What to do with the current value, see `Filter::visit`.
+    pub(crate) fn visit(&self, included: bool) -> Visit {
+        self.filter.visit(included)
+    }
+
+    /// Whether an object or array at `depth` is kept whole instead of flattened, because it
+    /// sits at `max_depth` or matches `keep_whole` or `stringify`.
+    pub(crate) fn kept_whole(&self, depth: usize) -> bool {
+        self.flattener
+            .max_depth
+            .is_some_and(|max_depth| depth >= max_depth)
+            || self.filter.stop().is_some()
+    }
+
+    /// Inserts a scalar under the current key.
+    pub(crate) fn insert(&mut self, value: Value, arr: bool) -> Result<(), FlattenError> {
+        self.flattener
+            .insert(&mut self.builder, self.key, self.source, value, arr)
+    }
+
+    /// Inserts an object or array that is not flattened any further: either because it is
+    /// empty, or because it is kept whole.
+    pub(crate) fn insert_subtree(&mut self, value: Value, arr: bool) -> Result<(), FlattenError> {
+        match self.filter.stop() {
+            Some(Stop::Value) => return self.insert(value, arr),
+            Some(Stop::String) => {
+                return self.insert(Value::String(walk::to_json_string(&value)), arr);
+            }
+            None => {}
+        }
+        match &value {
+            Value::Object(obj) if obj.is_empty() => match self.flattener.empty_objects {
+                EmptyObjects::Omit => Ok(()),
+                EmptyObjects::Object => self.insert(value, false),
+                EmptyObjects::Null => self.insert(Value::Null, arr),
+            },
+            // Empty arrays should be preserved, instead of being omitted
+            Value::Array(elements) if elements.is_empty() => self.insert(value, false),
+            _ => self.flattener.flatten_too_deep(
+                &mut self.builder,
+                self.key,
+                self.source,
+                Cow::Owned(value),
+                arr,
+            ),
+        }
+    }
+}
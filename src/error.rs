@@ -0,0 +1,69 @@
+//! Errors reported by [`Flattener::try_flatten`](crate::Flattener::try_flatten).
+
+use std::fmt;
+
+/// Errors that can occur when flattening JSON with [`Flattener::try_flatten`](crate::Flattener::try_flatten)
+///
+/// Every variant carries the flattened path where the problem was found.
+/// # Examples
+/// ```
+/// use smooth_json::{CollisionPolicy, FlattenError, Flattener};
+/// use serde_json::json;
+///
+/// let flattener = Flattener { collision_policy: CollisionPolicy::Error, ..Default::default() };
+///
+/// match flattener.try_flatten(&json!({"a": {"b": 1}, "a.b": 2})) {
+///     Err(FlattenError::Collision { path }) => assert_eq!(path, "a.b"),
+///     _ => unreachable!(),
+/// }
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum FlattenError {
+    /// Two different paths flattened to the same key under [`CollisionPolicy::Error`](crate::CollisionPolicy::Error)
+    Collision {
+        /// The flattened key both paths were written to
+        path: String,
+    },
+    /// A non-empty object or array sits deeper than `max_depth` under [`DepthLimit::Error`](crate::DepthLimit::Error)
+    DepthExceeded {
+        /// The flattened key of the object or array
+        path: String,
+    },
+    /// Exploding arrays with [`Flattener::explode`](crate::Flattener::explode) would give more
+    /// rows than allowed
+    TooManyRows {
+        /// The flattened key of the array or object where the limit was passed
+        path: String,
+        /// The most rows allowed
+        limit: usize,
+    },
+    /// The flattened value has more keys than [`Flattener::max_keys`](crate::Flattener::max_keys)
+    TooManyKeys {
+        /// The flattened key that passed the limit
+        path: String,
+        /// The most keys allowed
+        limit: usize,
+    },
+}
+
+impl fmt::Display for FlattenError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FlattenError::Collision { path } => {
+                write!(f, "key `{}` collides with another path", path)
+            }
+            FlattenError::DepthExceeded { path } => {
+                write!(f, "`{}` is nested deeper than the maximum depth", path)
+            }
+            FlattenError::TooManyRows { path, limit } => {
+                write!(f, "exploding `{}` gives more than {} rows", path, limit)
+            }
+            FlattenError::TooManyKeys { path, limit } => {
+                write!(f, "key `{}` is past the limit of {} keys", path, limit)
+            }
+        }
+    }
+}
+
+impl std::error::Error for FlattenError {}
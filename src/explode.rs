@@ -0,0 +1,477 @@
+//! Flattening into one row per element of chosen arrays, like Spark's `explode`.
+
+use std::borrow::Cow;
+
+use serde_json::Map;
+use serde_json::Value;
+
+use crate::FlattenError;
+use crate::Flattener;
+use crate::Scratch;
+use crate::filter::Filter;
+use crate::filter::Visit;
+
+/// Which arrays [`Flattener::explode`] turns into rows
+/// # Examples
+/// ```
+/// use smooth_json::ExplodeArrays;
+///
+/// let all = ExplodeArrays::All;
+/// let labels = ExplodeArrays::Paths(&["labels"]);
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ExplodeArrays<'a> {
+    /// Every array that is reached through objects and exploded arrays
+    #[default]
+    All,
+    /// Only the arrays at these flattened keys, built with the separator of the flattener. The
+    /// root is the empty key `""`, and arrays nested in exploded arrays have no index in their
+    /// key
+    ///
+    /// This holds with `preserve_arrays` too: the array `b` in `{"a": [{"b": [1, 2]}]}` is
+    /// chosen with `a.b`, not `a.0.b`, and is flattened to `a.b.0` and `a.b.1` when only `a` is
+    /// exploded. Arrays nested in arrays that are not exploded cannot be chosen.
+    Paths(&'a [&'a str]),
+}
+
+/// Options for [`Flattener::explode`]
+/// # Examples
+/// ```
+/// use smooth_json::{ExplodeArrays, ExplodeOptions};
+///
+/// let options = ExplodeOptions { arrays: ExplodeArrays::Paths(&["labels"]), max_rows: 100 };
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ExplodeOptions<'a> {
+    /// The arrays to explode, all of them by default
+    pub arrays: ExplodeArrays<'a>,
+    /// The most rows a single value can explode into, 10000 by default. Exploding several
+    /// arrays of the same object gives every combination of their elements, so the number of
+    /// rows can grow quickly
+    pub max_rows: usize,
+}
+
+impl Default for ExplodeOptions<'_> {
+    fn default() -> Self {
+        ExplodeOptions {
+            arrays: ExplodeArrays::All,
+            max_rows: 10_000,
+        }
+    }
+}
+
+/// Builds every variant of a value where each exploded array is replaced by one of its elements.
+struct Exploder<'f, 'a, 'o> {
+    flattener: &'f Flattener<'a>,
+    options: &'o ExplodeOptions<'o>,
+    key: String,
+    source: String,
+    filter: Filter,
+}
+
+impl Exploder<'_, '_, '_> {
+    fn explodes(&self, depth: usize) -> bool {
+        let within_depth = self
+            .flattener
+            .max_depth
+            .is_none_or(|max_depth| depth < max_depth);
+        within_depth
+            && match self.options.arrays {
+                ExplodeArrays::All => true,
+                ExplodeArrays::Paths(paths) => paths.contains(&self.key.as_str()),
+            }
+    }
+
+    fn too_many_rows(&self) -> FlattenError {
+        FlattenError::TooManyRows {
+            path: self.key.clone(),
+            limit: self.options.max_rows,
+        }
+    }
+
+    /// Builds the variants of `value`, where `included` is whether an include pattern matched
+    /// one of its parents.
+    fn variants<'v>(
+        &mut self,
+        value: &'v Value,
+        depth: usize,
+        included: bool,
+    ) -> Result<Vec<Cow<'v, Value>>, FlattenError> {
+        // Arrays that are left out or kept whole are not exploded
+        let visit = self.filter.visit(included);
+        if visit == Visit::Skip || self.filter.stop().is_some() {
+            return Ok(vec![Cow::Borrowed(value)]);
+        }
+        let too_deep = self
+            .flattener
+            .max_depth
+            .is_some_and(|max_depth| depth >= max_depth);
+
+        match value {
+            Value::Array(elements) if !elements.is_empty() && self.explodes(depth) => {
+                // Each element takes the place of the array, at the same key and depth
+                let mut variants = Vec::new();
+                for element in elements {
+                    variants.extend(self.variants(element, depth, included)?);
+                    if variants.len() > self.options.max_rows {
+                        return Err(self.too_many_rows());
+                    }
+                }
+                Ok(variants)
+            }
+            Value::Object(fields) if !too_deep => {
+                let mut exploded = Vec::new();
+                for (name, child) in fields {
+                    let (key_len, source_len) = (self.key.len(), self.source.len());
+                    let path_len = self.filter.len();
+                    self.flattener
+                        .push_key(&mut self.key, &mut self.source, name);
+                    self.filter.push_key(name);
+                    let variants = self.variants(child, depth + 1, visit == Visit::Include)?;
+                    self.key.truncate(key_len);
+                    self.source.truncate(source_len);
+                    self.filter.truncate(path_len);
+                    let unchanged =
+                        matches!(&variants[..], [Cow::Borrowed(v)] if std::ptr::eq(*v, child));
+                    if !unchanged {
+                        exploded.push((name, variants));
+                    }
+                }
+                if exploded.is_empty() {
+                    return Ok(vec![Cow::Borrowed(value)]);
+                }
+
+                let rows = exploded
+                    .iter()
+                    .try_fold(1usize, |rows, (_, variants)| {
+                        rows.checked_mul(variants.len())
+                            .filter(|&rows| rows <= self.options.max_rows)
+                    })
+                    .ok_or_else(|| self.too_many_rows())?;
+
+                // The first exploded field varies slowest, like nested loops over the fields.
+                // Only the other fields are cloned as they are, not the exploded arrays.
+                let mut objects = Vec::with_capacity(rows);
+                for i in 0..rows {
+                    let mut object = Map::new();
+                    let mut repeat = rows;
+                    let mut exploded = exploded.iter().peekable();
+                    for (name, child) in fields {
+                        let value = match exploded.next_if(|(exploded, _)| *exploded == name) {
+                            Some((_, variants)) => {
+                                repeat /= variants.len();
+                                variants[(i / repeat) % variants.len()].clone().into_owned()
+                            }
+                            None => child.clone(),
+                        };
+                        object.insert(name.clone(), value);
+                    }
+                    objects.push(Cow::Owned(Value::Object(object)));
+                }
+                Ok(objects)
+            }
+            _ => Ok(vec![Cow::Borrowed(value)]),
+        }
+    }
+}
+
+impl<'a> Flattener<'a> {
+    /// Flattens JSON variants into one row per element of the exploded arrays
+    ///
+    /// Each exploded array is replaced by one of its elements, and the result is flattened like
+    /// [`Flattener::try_flatten`], so the fields around the array are repeated on every row and
+    /// the fields of an element stay on the same row. When an object holds several exploded
+    /// arrays, every combination of their elements gets a row.
+    ///
+    /// Empty arrays, arrays deeper than `max_depth` and arrays nested in arrays that are not
+    /// exploded are flattened as usual. An exploded array does not count as a level of
+    /// `max_depth`, since its elements take its place. Arrays left out by `include` or
+    /// `exclude`, or kept whole by `keep_whole` or `stringify`, are not exploded.
+    ///
+    /// # Arguments
+    ///
+    /// * `json` - A serde_json Value to flatten
+    /// * `options` - Which arrays to explode, and how many rows to allow
+    ///
+    /// # Examples
+    /// ```
+    /// use smooth_json::{ExplodeArrays, ExplodeOptions, Flattener};
+    /// use serde_json::json;
+    ///
+    /// let flattener = Flattener::new();
+    /// let issue = json!({
+    ///     "id": 7,
+    ///     "labels": [{"id": 1, "name": "bug"}, {"id": 2, "name": "ui"}],
+    ///     "tags": ["x", "y"]
+    /// });
+    /// let options = ExplodeOptions { arrays: ExplodeArrays::Paths(&["labels"]), ..Default::default() };
+    ///
+    /// let rows = flattener.explode(&issue, &options).unwrap();
+    ///
+    /// assert_eq!(
+    ///     rows,
+    ///     vec![
+    ///         json!({"id": 7, "labels.id": 1, "labels.name": "bug", "tags": ["x", "y"]}),
+    ///         json!({"id": 7, "labels.id": 2, "labels.name": "ui", "tags": ["x", "y"]}),
+    ///     ]
+    ///     .into_iter()
+    ///     .map(|row| row.as_object().unwrap().clone())
+    ///     .collect::<Vec<_>>()
+    /// );
+    /// ```
+    pub fn explode(
+        &self,
+        json: &Value,
+        options: &ExplodeOptions,
+    ) -> Result<Vec<Map<String, Value>>, FlattenError> {
+        let mut exploder = Exploder {
+            flattener: self,
+            options,
+            key: String::new(),
+            source: String::new(),
+            filter: Filter::new(self),
+        };
+        let variants = exploder.variants(json, 0, false)?;
+
+        let mut scratch = Scratch {
+            filter: exploder.filter,
+            ..Scratch::default()
+        };
+        variants
+            .into_iter()
+            .map(|variant| {
+                let mut row = Map::new();
+                self.flatten_into_with(variant, &mut row, &mut scratch, true)?;
+                Ok(row)
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use serde_json::json;
+
+    use crate::DepthLimit;
+
+    fn rows(values: Value) -> Vec<Map<String, Value>> {
+        values
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|row| row.as_object().unwrap().clone())
+            .collect()
+    }
+
+    #[test]
+    fn explode_all_arrays() {
+        let flattener = Flattener::new();
+        let order = json!({
+            "order": 1,
+            "items": [
+                {"sku": "a", "tags": ["new", "sale"]},
+                {"sku": "b", "tags": []}
+            ],
+            "payments": [{"amount": 5}, {"amount": 6}]
+        });
+
+        let exploded = flattener
+            .explode(&order, &ExplodeOptions::default())
+            .unwrap();
+
+        assert_eq!(
+            exploded,
+            rows(json!([
+                {"order": 1, "items.sku": "a", "items.tags": "new", "payments.amount": 5},
+                {"order": 1, "items.sku": "a", "items.tags": "new", "payments.amount": 6},
+                {"order": 1, "items.sku": "a", "items.tags": "sale", "payments.amount": 5},
+                {"order": 1, "items.sku": "a", "items.tags": "sale", "payments.amount": 6},
+                {"order": 1, "items.sku": "b", "items.tags": [], "payments.amount": 5},
+                {"order": 1, "items.sku": "b", "items.tags": [], "payments.amount": 6},
+            ]))
+        );
+    }
+
+    #[test]
+    fn explode_chosen_paths() {
+        let flattener = Flattener {
+            separator: "_",
+            preserve_arrays: true,
+            ..Default::default()
+        };
+        let records = json!([
+            {"user": {"roles": [{"id": 1}, {"id": 2}]}, "tags": ["x", "y"]},
+            {"user": {"roles": []}}
+        ]);
+        let options = ExplodeOptions {
+            arrays: ExplodeArrays::Paths(&["", "user_roles"]),
+            ..Default::default()
+        };
+
+        assert_eq!(
+            flattener.explode(&records, &options).unwrap(),
+            rows(json!([
+                {"user_roles_id": 1, "tags_0": "x", "tags_1": "y"},
+                {"user_roles_id": 2, "tags_0": "x", "tags_1": "y"},
+                {"user_roles": []},
+            ]))
+        );
+
+        // Nothing to explode still gives the flattened value as its only row
+        let options = ExplodeOptions {
+            arrays: ExplodeArrays::Paths(&["missing"]),
+            ..Default::default()
+        };
+        assert_eq!(
+            flattener.explode(&json!({"a": [1]}), &options).unwrap(),
+            rows(json!([{"a_0": 1}]))
+        );
+    }
+
+    #[test]
+    fn explode_nested_arrays_with_preserve_arrays() {
+        let flattener = Flattener {
+            preserve_arrays: true,
+            ..Default::default()
+        };
+        let value = json!({"id": 1, "a": [{"b": [1, 2]}, {"b": [3]}]});
+        let explode = |paths| {
+            let options = ExplodeOptions {
+                arrays: ExplodeArrays::Paths(paths),
+                ..Default::default()
+            };
+            flattener.explode(&value, &options).unwrap()
+        };
+
+        assert_eq!(
+            explode(&["a"]),
+            rows(json!([
+                {"id": 1, "a.b.0": 1, "a.b.1": 2},
+                {"id": 1, "a.b.0": 3},
+            ]))
+        );
+        assert_eq!(
+            explode(&["a", "a.b"]),
+            rows(json!([
+                {"id": 1, "a.b": 1},
+                {"id": 1, "a.b": 2},
+                {"id": 1, "a.b": 3},
+            ]))
+        );
+        // `a` is not exploded, so the arrays inside it cannot be chosen
+        assert_eq!(
+            explode(&["a.0.b"]),
+            rows(json!([{"id": 1, "a.0.b.0": 1, "a.0.b.1": 2, "a.1.b.0": 3}]))
+        );
+    }
+
+    #[test]
+    fn explode_respects_max_depth() {
+        let flattener = Flattener {
+            max_depth: Some(2),
+            depth_limit: DepthLimit::Keep,
+            ..Default::default()
+        };
+        let value = json!({"a": [[1, 2], 3], "b": {"c": [4, 5]}});
+
+        assert_eq!(
+            flattener
+                .explode(&value, &ExplodeOptions::default())
+                .unwrap(),
+            rows(json!([
+                {"a": 1, "b.c": [4, 5]},
+                {"a": 2, "b.c": [4, 5]},
+                {"a": 3, "b.c": [4, 5]},
+            ]))
+        );
+
+        // A single element still takes the place of its array
+        let flattener = Flattener {
+            alt_array_flattening: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            flattener
+                .explode(&json!({"a": [{"b": 1}]}), &ExplodeOptions::default())
+                .unwrap(),
+            rows(json!([{"a.b": 1}]))
+        );
+    }
+
+    #[test]
+    fn explode_skips_arrays_not_flattened() {
+        let flattener = Flattener {
+            include: &["id", "items"],
+            exclude: &["items.hidden"],
+            ..Default::default()
+        };
+        let value = json!({
+            "id": 1,
+            "items": [{"sku": "a", "hidden": [1, 2]}, {"sku": "b"}],
+            "tags": ["x", "y"]
+        });
+
+        assert_eq!(
+            flattener
+                .explode(&value, &ExplodeOptions::default())
+                .unwrap(),
+            rows(json!([
+                {"id": 1, "items.sku": "a"},
+                {"id": 1, "items.sku": "b"},
+            ]))
+        );
+
+        let flattener = Flattener {
+            keep_whole: &["items.hidden"],
+            stringify: &["tags"],
+            ..Default::default()
+        };
+        assert_eq!(
+            flattener
+                .explode(&value, &ExplodeOptions::default())
+                .unwrap(),
+            rows(json!([
+                {"id": 1, "items.sku": "a", "items.hidden": [1, 2], "tags": "[\"x\",\"y\"]"},
+                {"id": 1, "items.sku": "b", "tags": "[\"x\",\"y\"]"},
+            ]))
+        );
+    }
+
+    #[test]
+    fn explode_caps_rows() {
+        let flattener = Flattener::new();
+        let value = json!({"a": {"x": [1, 2, 3]}, "b": [1, 2, 3], "c": [1, 2]});
+        let options = ExplodeOptions {
+            max_rows: 17,
+            ..Default::default()
+        };
+
+        assert_eq!(
+            flattener.explode(&value, &options),
+            Err(FlattenError::TooManyRows {
+                path: String::new(),
+                limit: 17
+            })
+        );
+
+        let options = ExplodeOptions {
+            max_rows: 18,
+            ..Default::default()
+        };
+        assert_eq!(flattener.explode(&value, &options).unwrap().len(), 18);
+
+        let options = ExplodeOptions {
+            max_rows: 2,
+            ..Default::default()
+        };
+        assert_eq!(
+            flattener.explode(&json!({"a": {"x": [1, 2, 3]}}), &options),
+            Err(FlattenError::TooManyRows {
+                path: "a.x".to_string(),
+                limit: 2
+            })
+        );
+    }
+}
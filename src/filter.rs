@@ -0,0 +1,316 @@
+//! The path patterns of a flattener, checked while the input is traversed: `include` and
+//! `exclude`, so subtrees that cannot be part of the output are never visited, and
+//! `keep_whole` and `stringify`, so subtrees that are not flattened are not descended into.
+//!
+//! Patterns are matched against the raw segments of a path, before they are escaped and joined
+//! into a flattened key, so the same patterns work with every key format.
+
+use std::fmt::Write;
+
+use crate::Flattener;
+use crate::KeyFormat;
+
+/// What to do with a value, given the path that leads to it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Visit {
+    /// Excluded, or no include pattern can match it or anything inside it
+    Skip,
+    /// Not included itself, but an include pattern may match something inside it
+    Descend,
+    /// Included, along with everything inside it that is not excluded
+    Include,
+}
+
+/// How an object or array is kept whole instead of being flattened.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Stop {
+    /// As a JSON value
+    Value,
+    /// Serialized to a JSON string
+    String,
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct Filter {
+    /// The `include`, `exclude`, `keep_whole` and `stringify` patterns as given, along with the
+    /// key options they were parsed with, to tell whether a flattener's patterns are the same
+    raw: [Vec<String>; 4],
+    separator: String,
+    key_format: KeyFormat,
+    escape_keys: bool,
+    include: Vec<Vec<String>>,
+    exclude: Vec<Vec<String>>,
+    keep_whole: Vec<Vec<String>>,
+    stringify: Vec<Vec<String>>,
+    /// Raw segments of the current path, one after another
+    path: String,
+    /// Where each segment of `path` ends
+    ends: Vec<usize>,
+}
+
+impl Filter {
+    /// Parses the patterns of `flattener`. Nothing is allocated when it has none.
+    pub(crate) fn new(flattener: &Flattener) -> Self {
+        let parse = |patterns: &[&str]| {
+            patterns
+                .iter()
+                .map(|pattern| flattener.split_key(&pattern_key(flattener, pattern)))
+                .collect()
+        };
+        let copy = |patterns: &[&str]| patterns.iter().map(|pattern| pattern.to_string()).collect();
+        let mut filter = Filter {
+            raw: [
+                copy(flattener.include),
+                copy(flattener.exclude),
+                copy(flattener.keep_whole),
+                copy(flattener.stringify),
+            ],
+            include: parse(flattener.include),
+            exclude: parse(flattener.exclude),
+            keep_whole: parse(flattener.keep_whole),
+            stringify: parse(flattener.stringify),
+            ..Filter::default()
+        };
+        if filter.is_active() {
+            filter.separator = flattener.separator.to_string();
+            filter.key_format = flattener.key_format;
+            filter.escape_keys = flattener.escape_keys;
+        }
+        filter
+    }
+
+    /// Gets ready to walk a new value with `flattener`, parsing its patterns again only when
+    /// they, or the key options they depend on, differ from the ones parsed last.
+    pub(crate) fn reset(&mut self, flattener: &Flattener) {
+        if self.parsed_from(flattener) {
+            self.truncate(0);
+        } else {
+            *self = Filter::new(flattener);
+        }
+    }
+
+    fn parsed_from(&self, flattener: &Flattener) -> bool {
+        let patterns = [
+            flattener.include,
+            flattener.exclude,
+            flattener.keep_whole,
+            flattener.stringify,
+        ];
+        let same_patterns = self
+            .raw
+            .iter()
+            .zip(patterns)
+            .all(|(raw, patterns)| raw.iter().eq(patterns));
+        same_patterns
+            && (!self.is_active()
+                || (self.separator == flattener.separator
+                    && self.key_format == flattener.key_format
+                    && self.escape_keys == flattener.escape_keys))
+    }
+
+    fn is_active(&self) -> bool {
+        !self.include.is_empty()
+            || !self.exclude.is_empty()
+            || !self.keep_whole.is_empty()
+            || !self.stringify.is_empty()
+    }
+
+    /// Number of segments in the current path, to `truncate` back to after a child.
+    pub(crate) fn len(&self) -> usize {
+        self.ends.len()
+    }
+
+    pub(crate) fn truncate(&mut self, len: usize) {
+        if len < self.ends.len() {
+            self.path
+                .truncate(if len == 0 { 0 } else { self.ends[len - 1] });
+            self.ends.truncate(len);
+        }
+    }
+
+    pub(crate) fn push_key(&mut self, segment: &str) {
+        if self.is_active() {
+            self.path.push_str(segment);
+            self.ends.push(self.path.len());
+        }
+    }
+
+    pub(crate) fn push_index(&mut self, index: usize) {
+        if self.is_active() {
+            write!(self.path, "{}", index).unwrap();
+            self.ends.push(self.path.len());
+        }
+    }
+
+    /// Decides what to do with the value at the current path.
+    ///
+    /// # Arguments
+    ///
+    /// * `included` - Whether an include pattern matched one of the value's parents
+    pub(crate) fn visit(&self, included: bool) -> Visit {
+        if self
+            .exclude
+            .iter()
+            .any(|pattern| self.matches(pattern, 0, false))
+        {
+            Visit::Skip
+        } else if included
+            || self.include.is_empty()
+            || self
+                .include
+                .iter()
+                .any(|pattern| self.matches(pattern, 0, false))
+        {
+            Visit::Include
+        } else if self
+            .include
+            .iter()
+            .any(|pattern| self.matches(pattern, 0, true))
+        {
+            Visit::Descend
+        } else {
+            Visit::Skip
+        }
+    }
+
+    /// Whether an object or array at the current path is kept whole, and how.
+    pub(crate) fn stop(&self) -> Option<Stop> {
+        let matches = |patterns: &[Vec<String>]| {
+            patterns
+                .iter()
+                .any(|pattern| self.matches(pattern, 0, false))
+        };
+        if matches(&self.stringify) {
+            Some(Stop::String)
+        } else if matches(&self.keep_whole) {
+            Some(Stop::Value)
+        } else {
+            None
+        }
+    }
+
+    fn segment(&self, i: usize) -> &str {
+        let start = if i == 0 { 0 } else { self.ends[i - 1] };
+        &self.path[start..self.ends[i]]
+    }
+
+    /// Whether `pattern` matches the path from its segment `from` onwards, or with `partial`,
+    /// whether it can match a path inside the current one.
+    fn matches(&self, pattern: &[String], from: usize, partial: bool) -> bool {
+        let Some((first, rest)) = pattern.split_first() else {
+            return from == self.ends.len();
+        };
+        if first == "**" {
+            return self.matches(rest, from, partial)
+                || (from < self.ends.len() && self.matches(pattern, from + 1, partial));
+        }
+        if from == self.ends.len() {
+            return partial;
+        }
+        glob(first, self.segment(from)) && self.matches(rest, from + 1, partial)
+    }
+}
+
+/// Writes the `[*]` and `[**]` wildcards of bracketed and JSONPath patterns as the segments
+/// `split_key` understands.
+fn pattern_key(flattener: &Flattener, pattern: &str) -> String {
+    let separator = match flattener.key_format {
+        KeyFormat::Bracketed => flattener.separator,
+        KeyFormat::JsonPath => ".",
+        KeyFormat::Dotted | KeyFormat::Pointer => return pattern.to_string(),
+    };
+    pattern
+        .replace("[**]", &format!("{}**", separator))
+        .replace("[*]", &format!("{}*", separator))
+}
+
+/// Matches one segment against a pattern where each `*` stands for any run of characters.
+fn glob(pattern: &str, segment: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or_default();
+    let Some(rest) = segment.strip_prefix(first) else {
+        return false;
+    };
+    let Some(last) = parts.next_back() else {
+        return rest.is_empty();
+    };
+    let Some(mut rest) = rest.strip_suffix(last) else {
+        return false;
+    };
+    for part in parts {
+        match rest.find(part) {
+            Some(i) => rest = &rest[i + part.len()..],
+            None => return false,
+        }
+    }
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn glob_segments() {
+        assert!(glob("name", "name"));
+        assert!(!glob("name", "names"));
+        assert!(glob("*", ""));
+        assert!(glob("na*", "name"));
+        assert!(glob("*me", "name"));
+        assert!(glob("n*m*e", "name"));
+        assert!(!glob("n*m*e", "nae"));
+        assert!(!glob("ab*ba", "aba"));
+    }
+
+    #[test]
+    fn visits_paths() {
+        let flattener = Flattener {
+            include: &["user.*", "labels.**.name"],
+            exclude: &["user.password"],
+            ..Default::default()
+        };
+        let mut filter = Filter::new(&flattener);
+        let mut visit = |path: &[&str]| {
+            filter.truncate(0);
+            for segment in path {
+                filter.push_key(segment);
+            }
+            filter.visit(false)
+        };
+
+        assert_eq!(visit(&[]), Visit::Descend);
+        assert_eq!(visit(&["user"]), Visit::Descend);
+        assert_eq!(visit(&["user", "id"]), Visit::Include);
+        assert_eq!(visit(&["user", "password"]), Visit::Skip);
+        assert_eq!(visit(&["labels"]), Visit::Descend);
+        assert_eq!(visit(&["labels", "name"]), Visit::Include);
+        assert_eq!(visit(&["labels", "0", "meta", "name"]), Visit::Include);
+        assert_eq!(visit(&["labels", "id"]), Visit::Descend);
+        assert_eq!(visit(&["id"]), Visit::Skip);
+    }
+
+    #[test]
+    fn reparses_changed_patterns() {
+        let dotted = Flattener {
+            exclude: &["a.b"],
+            ..Default::default()
+        };
+        let mut filter = Filter::default();
+        let visit = |filter: &mut Filter, flattener: &Flattener| {
+            filter.reset(flattener);
+            filter.push_key("a");
+            filter.push_key("b");
+            filter.visit(false)
+        };
+
+        assert_eq!(visit(&mut filter, &dotted), Visit::Skip);
+        assert_eq!(visit(&mut filter, &dotted), Visit::Skip);
+        let separated = Flattener {
+            separator: "_",
+            ..dotted
+        };
+        assert_eq!(visit(&mut filter, &separated), Visit::Include);
+        assert_eq!(visit(&mut filter, &Flattener::new()), Visit::Include);
+        assert_eq!(visit(&mut filter, &dotted), Visit::Skip);
+    }
+}
@@ -10,11 +10,63 @@
 //! use smooth_json::Flattener;
 //! ```
 
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::fmt::Write;
+
 use serde_json::Map;
 use serde_json::Value;
 use serde_json::json;
 
+#[cfg(feature = "arrow")]
+mod arrow;
+#[cfg(feature = "csv")]
+mod csv;
+mod de;
+mod emit;
+mod error;
+mod explode;
+mod filter;
+mod ndjson;
+#[cfg(feature = "parquet")]
+mod parquet;
+#[cfg(feature = "rayon")]
+mod rayon;
+mod schema;
+mod ser;
+mod transform;
+mod unflatten;
+mod walk;
+
+#[cfg(feature = "csv")]
+pub use crate::csv::{ArrayEncoding, CsvOptions, CsvWriter};
+#[cfg(feature = "parquet")]
+pub use crate::parquet::{ParquetOptions, ParquetWriter};
+#[cfg(feature = "rayon")]
+pub use crate::rayon::ParNdjsonRecords;
+/// The version of `arrow-array` that record batches are built with
+#[cfg(feature = "arrow")]
+pub use arrow_array;
+/// The version of `arrow-schema` that schemas are read with
+#[cfg(feature = "arrow")]
+pub use arrow_schema;
+pub use de::FlattenSeed;
+pub use error::FlattenError;
+pub use explode::{ExplodeArrays, ExplodeOptions};
+pub use ndjson::{NdjsonError, NdjsonRecords};
+pub use schema::{Column, JsonType, SchemaInferrer};
+pub use transform::KeyTransform;
+pub use unflatten::UnflattenError;
+pub use walk::Leaves;
+
+use filter::Filter;
+use walk::{Leaf, Walker};
+
 /// Flattener is the main driver when flattening JSON
+///
+/// A `Flattener` only holds its options and borrows nothing mutably, so it is `Send` and
+/// `Sync`: a single flattener can be shared by reference across threads, each flattening its
+/// own records with its own [`Scratch`].
 /// # Examples
 /// ```
 /// use smooth_json;
@@ -43,6 +95,318 @@ pub struct Flattener<'a> {
     /// let flattener = smooth_json::Flattener { preserve_arrays: true, ..Default::default()};
     /// ```
     pub preserve_arrays: bool,
+    /// Escape the separator and backslashes inside object keys with a backslash, so keys that
+    /// contain the separator cannot collide with nested keys
+    /// # Examples
+    /// ```
+    /// use smooth_json;
+    /// use serde_json::json;
+    ///
+    /// let flattener = smooth_json::Flattener { escape_keys: true, ..Default::default()};
+    /// let flattened = flattener.flatten(&json!({"a.b": 1, "a": {"b": 2}}));
+    ///
+    /// assert_eq!(flattened, json!({"a\\.b": 1, "a.b": 2}));
+    /// ```
+    pub escape_keys: bool,
+    /// What to do when two different paths flatten to the same key
+    ///
+    /// Values coming from elements of the same array are always merged into an array, since
+    /// that is how arrays are flattened; the policy only applies to keys produced by different
+    /// paths, such as `{"a": {"b": 1}, "a.b": 2}`.
+    /// # Examples
+    /// ```
+    /// use smooth_json;
+    /// use smooth_json::CollisionPolicy;
+    /// use serde_json::json;
+    ///
+    /// let flattener = smooth_json::Flattener { collision_policy: CollisionPolicy::KeepFirst, ..Default::default()};
+    /// let flattened = flattener.flatten(&json!({"a": {"b": 1}, "a.b": 2}));
+    ///
+    /// assert_eq!(flattened, json!({"a.b": 1}));
+    /// ```
+    pub collision_policy: CollisionPolicy,
+    /// How many levels of objects and arrays to descend into before applying `depth_limit`
+    ///
+    /// Values directly inside the root are at depth 1, so with a `max_depth` of 1 any object or
+    /// array found there is handled by `depth_limit` instead of being flattened. `None` (the
+    /// default) flattens to any depth.
+    /// # Examples
+    /// ```
+    /// use smooth_json;
+    /// use serde_json::json;
+    ///
+    /// let flattener = smooth_json::Flattener { max_depth: Some(1), ..Default::default()};
+    /// let flattened = flattener.flatten(&json!({"a": {"b": 1}, "c": 2}));
+    ///
+    /// assert_eq!(flattened, json!({"a": "{\"b\":1}", "c": 2}));
+    /// ```
+    pub max_depth: Option<usize>,
+    /// What to do with objects and arrays nested deeper than `max_depth`
+    /// # Examples
+    /// ```
+    /// use smooth_json;
+    /// use smooth_json::DepthLimit;
+    /// use serde_json::json;
+    ///
+    /// let flattener = smooth_json::Flattener { max_depth: Some(1), depth_limit: DepthLimit::Keep, ..Default::default()};
+    /// let flattened = flattener.flatten(&json!({"a": {"b": 1}, "c": 2}));
+    ///
+    /// assert_eq!(flattened, json!({"a": {"b": 1}, "c": 2}));
+    /// ```
+    pub depth_limit: DepthLimit,
+    /// The most keys a flattened value may have, checked by [`Flattener::try_flatten`]
+    ///
+    /// A value with more keys is reported as [`FlattenError::TooManyKeys`], while
+    /// [`Flattener::flatten`] writes every key. With [`Flattener::try_flatten_into`], keys
+    /// already in the output count too. `None` (the default) allows any number of keys.
+    /// # Examples
+    /// ```
+    /// use smooth_json::{FlattenError, Flattener};
+    /// use serde_json::json;
+    ///
+    /// let flattener = Flattener { max_keys: Some(2), ..Default::default() };
+    ///
+    /// assert_eq!(
+    ///     flattener.try_flatten(&json!({"a": 1, "b": {"c": 2, "d": 3}})),
+    ///     Err(FlattenError::TooManyKeys { path: "b.d".to_string(), limit: 2 })
+    /// );
+    /// ```
+    pub max_keys: Option<usize>,
+    /// How empty objects are represented, since they have no keys of their own to flatten
+    /// # Examples
+    /// ```
+    /// use smooth_json;
+    /// use smooth_json::EmptyObjects;
+    /// use serde_json::json;
+    ///
+    /// let flattener = smooth_json::Flattener { empty_objects: EmptyObjects::Object, ..Default::default()};
+    /// let flattened = flattener.flatten(&json!({"meta": {}, "a": 1}));
+    ///
+    /// assert_eq!(flattened, json!({"meta": {}, "a": 1}));
+    /// ```
+    pub empty_objects: EmptyObjects,
+    /// How object keys and array indices are written in flattened keys
+    ///
+    /// Array indices only appear in keys with `preserve_arrays`. The `separator` and
+    /// `escape_keys` only apply to [`KeyFormat::Dotted`] and [`KeyFormat::Bracketed`], since
+    /// JSONPath and JSON Pointer have their own syntax and escaping.
+    /// # Examples
+    /// ```
+    /// use smooth_json;
+    /// use smooth_json::KeyFormat;
+    /// use serde_json::json;
+    ///
+    /// let flattener = smooth_json::Flattener { key_format: KeyFormat::Bracketed, preserve_arrays: true, ..Default::default()};
+    /// let flattened = flattener.flatten(&json!({"a": [1, [2, 3]]}));
+    ///
+    /// assert_eq!(flattened, json!({"a[0]": 1, "a[1][0]": 2, "a[1][1]": 3}));
+    /// ```
+    pub key_format: KeyFormat,
+    /// Patterns of the values to flatten, every value when empty (the default)
+    ///
+    /// Patterns are written like flattened keys, in the `key_format` and with the `separator` of
+    /// the flattener. A `*` within a segment matches any run of characters in that segment, and
+    /// a `**` segment matches any number of segments, including none. Array indices are only
+    /// segments with `preserve_arrays`. A pattern that matches an object or array includes
+    /// everything inside it.
+    ///
+    /// Patterns are checked while walking the input, so subtrees that no pattern can match are
+    /// skipped without being visited. Objects and arrays kept whole, because of `max_depth` or
+    /// `keep_whole`, are only kept when a pattern matches them, and are kept with everything
+    /// inside them.
+    /// # Examples
+    /// ```
+    /// use smooth_json;
+    /// use serde_json::json;
+    ///
+    /// let flattener = smooth_json::Flattener { include: &["user.*", "labels.**.name"], ..Default::default()};
+    /// let flattened = flattener.flatten(&json!({
+    ///     "id": 1,
+    ///     "user": {"name": "x", "roles": ["admin"]},
+    ///     "labels": [{"id": 2, "name": "bug"}]
+    /// }));
+    ///
+    /// assert_eq!(flattened, json!({"user.name": "x", "user.roles": "admin", "labels.name": "bug"}));
+    /// ```
+    pub include: &'a [&'a str],
+    /// Patterns of the values to leave out, written like `include`
+    ///
+    /// An excluded value is left out along with everything inside it, even when it or
+    /// something inside it also matches an include pattern: `exclude` takes precedence.
+    /// # Examples
+    /// ```
+    /// use smooth_json;
+    /// use serde_json::json;
+    ///
+    /// let flattener = smooth_json::Flattener { include: &["user"], exclude: &["**.password"], ..Default::default()};
+    /// let flattened = flattener.flatten(&json!({"user": {"name": "x", "password": "y"}, "id": 1}));
+    ///
+    /// assert_eq!(flattened, json!({"user.name": "x"}));
+    /// ```
+    pub exclude: &'a [&'a str],
+    /// Patterns of objects and arrays to keep whole as JSON values instead of flattening them
+    ///
+    /// Patterns are written like `include`. A matching object or array, even an empty one, is
+    /// inserted under its key as it is, and nothing inside it is visited. Scalars are flattened
+    /// as usual.
+    /// # Examples
+    /// ```
+    /// use smooth_json;
+    /// use serde_json::json;
+    ///
+    /// let flattener = smooth_json::Flattener { keep_whole: &["extensions"], ..Default::default()};
+    /// let flattened = flattener.flatten(&json!({"id": 1, "extensions": {"a": {"b": 2}}}));
+    ///
+    /// assert_eq!(flattened, json!({"id": 1, "extensions": {"a": {"b": 2}}}));
+    /// ```
+    pub keep_whole: &'a [&'a str],
+    /// Patterns of objects and arrays to keep whole as JSON strings instead of flattening them
+    ///
+    /// Works like `keep_whole`, but the subtree is serialized to a JSON string, which is how
+    /// CSV and Parquet columns usually hold free-form JSON. Takes precedence over `keep_whole`
+    /// when both match.
+    /// # Examples
+    /// ```
+    /// use smooth_json;
+    /// use serde_json::json;
+    ///
+    /// let flattener = smooth_json::Flattener { stringify: &["payload"], ..Default::default()};
+    /// let flattened = flattener.flatten(&json!({"id": 1, "payload": {"a": [1, 2]}}));
+    ///
+    /// assert_eq!(flattened, json!({"id": 1, "payload": "{\"a\":[1,2]}"}));
+    /// ```
+    pub stringify: &'a [&'a str],
+    /// Transforms applied in order to every flattened key before it is added to the output
+    ///
+    /// Keys that end up the same are handled by `collision_policy` like any other collision.
+    /// Keys matched by `rename` are not transformed, and [`Flattener::unflatten`] cannot
+    /// rebuild the input from transformed keys.
+    /// # Examples
+    /// ```
+    /// use smooth_json;
+    /// use smooth_json::KeyTransform;
+    /// use serde_json::json;
+    ///
+    /// let flattener = smooth_json::Flattener { key_transforms: &[KeyTransform::Lowercase], ..Default::default()};
+    /// let flattened = flattener.flatten(&json!({"User": {"ID": 1}}));
+    ///
+    /// assert_eq!(flattened, json!({"user.id": 1}));
+    /// ```
+    pub key_transforms: &'a [KeyTransform],
+    /// Output names of flattened keys, as `(flattened key, output name)` pairs
+    ///
+    /// Keys are looked up as they are built, before `key_transforms`, and the output name is
+    /// used as it is. Every flattened key is compared with each pair in turn, so a long list
+    /// slows down flattening; rename a few keys here and the rest after flattening.
+    /// # Examples
+    /// ```
+    /// use smooth_json;
+    /// use serde_json::json;
+    ///
+    /// let flattener = smooth_json::Flattener { rename: &[("user.id", "user_id")], ..Default::default()};
+    /// let flattened = flattener.flatten(&json!({"user": {"id": 1, "name": "x"}}));
+    ///
+    /// assert_eq!(flattened, json!({"user_id": 1, "user.name": "x"}));
+    /// ```
+    pub rename: &'a [(&'a str, &'a str)],
+}
+
+/// Syntaxes for flattened keys
+///
+/// A value at the root of the input keeps the empty key `""` whatever the format.
+/// # Examples
+/// ```
+/// use smooth_json::{Flattener, KeyFormat};
+/// use serde_json::json;
+///
+/// let input = json!({"a": [{"b/c": 1}]});
+/// let key = |key_format| {
+///     let flattener = Flattener { key_format, preserve_arrays: true, ..Default::default() };
+///     flattener.flatten(&input).as_object().unwrap().keys().next().unwrap().clone()
+/// };
+///
+/// assert_eq!(key(KeyFormat::Dotted), "a.0.b/c");
+/// assert_eq!(key(KeyFormat::Bracketed), "a[0].b/c");
+/// assert_eq!(key(KeyFormat::JsonPath), "$.a[0]['b/c']");
+/// assert_eq!(key(KeyFormat::Pointer), "/a/0/b~1c");
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum KeyFormat {
+    /// Keys and indices joined with the separator: `a.1.0`
+    #[default]
+    Dotted,
+    /// Keys joined with the separator, indices in brackets: `a[1][0].b`. With `escape_keys`,
+    /// `[` inside keys is escaped with a backslash too
+    Bracketed,
+    /// JSONPath: `$.a[1][0]`, with keys that are not identifiers quoted as `$['a b']`
+    JsonPath,
+    /// RFC 6901 JSON Pointer: `/a/1/0`, with `~` escaped as `~0` and `/` as `~1`
+    Pointer,
+}
+
+/// Representations for empty objects in the flattened output
+/// # Examples
+/// ```
+/// use smooth_json::EmptyObjects;
+///
+/// let empty_objects = EmptyObjects::Null;
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum EmptyObjects {
+    /// Leave empty objects out of the output
+    #[default]
+    Omit,
+    /// Keep empty objects as `{}`, the same way empty arrays are kept as `[]`
+    Object,
+    /// Write `null` in place of empty objects
+    Null,
+}
+
+/// Behaviors for objects and arrays nested deeper than [`Flattener::max_depth`]
+/// # Examples
+/// ```
+/// use smooth_json::DepthLimit;
+///
+/// let depth_limit = DepthLimit::Drop;
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DepthLimit {
+    /// Serialize the remaining subtree to a JSON string
+    #[default]
+    Stringify,
+    /// Keep the remaining subtree as a nested `Value`
+    Keep,
+    /// Leave the remaining subtree out of the output
+    Drop,
+    /// Report a [`FlattenError::DepthExceeded`] from [`Flattener::try_flatten`], while
+    /// [`Flattener::flatten`] serializes the subtree like [`DepthLimit::Stringify`]
+    Error,
+}
+
+/// Strategies for resolving two different paths that flatten to the same key
+/// # Examples
+/// ```
+/// use smooth_json::CollisionPolicy;
+///
+/// let policy = CollisionPolicy::Suffix;
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CollisionPolicy {
+    /// Merge the values into an array under the shared key
+    #[default]
+    MergeIntoArray,
+    /// Keep the value from the first path and drop the later ones
+    KeepFirst,
+    /// Keep the value from the last path, replacing the earlier ones
+    KeepLast,
+    /// Report the collision as a [`FlattenError::Collision`] from [`Flattener::try_flatten`],
+    /// while [`Flattener::flatten`] merges the values into an array
+    Error,
+    /// Keep every path, writing later ones to `key_1`, `key_2`, ...
+    ///
+    /// The suffix is added before [`Flattener::key_transforms`], so suffixed keys are
+    /// transformed like any other, and after [`Flattener::rename`].
+    Suffix,
 }
 
 impl<'a> Default for Flattener<'a> {
@@ -51,6 +415,19 @@ impl<'a> Default for Flattener<'a> {
             separator: ".",
             alt_array_flattening: false,
             preserve_arrays: false,
+            escape_keys: false,
+            collision_policy: CollisionPolicy::MergeIntoArray,
+            max_depth: None,
+            depth_limit: DepthLimit::Stringify,
+            max_keys: None,
+            empty_objects: EmptyObjects::Omit,
+            key_format: KeyFormat::Dotted,
+            include: &[],
+            exclude: &[],
+            keep_whole: &[],
+            stringify: &[],
+            key_transforms: &[],
+            rename: &[],
         }
     }
 }
@@ -84,24 +461,206 @@ impl<'a> Flattener<'a> {
         }
     }
 
-    /// Builds a composite key by combining a prefix and suffix with the configured separator.
+    /// Appends an object key to a flattened key, in the configured key format.
+    fn write_key(&self, key: &mut String, segment: &str) {
+        match self.key_format {
+            KeyFormat::Dotted | KeyFormat::Bracketed => {
+                if !key.is_empty() {
+                    key.push_str(self.separator);
+                }
+                key.push_str(&self.escape_segment(segment));
+            }
+            KeyFormat::JsonPath => {
+                if key.is_empty() {
+                    key.push('$');
+                }
+                if is_identifier(segment) {
+                    key.push('.');
+                    key.push_str(segment);
+                } else {
+                    key.push_str("['");
+                    for c in segment.chars() {
+                        if c == '\\' || c == '\'' {
+                            key.push('\\');
+                        }
+                        key.push(c);
+                    }
+                    key.push_str("']");
+                }
+            }
+            KeyFormat::Pointer => {
+                key.push('/');
+                for c in segment.chars() {
+                    match c {
+                        '~' => key.push_str("~0"),
+                        '/' => key.push_str("~1"),
+                        c => key.push(c),
+                    }
+                }
+            }
+        }
+    }
+
+    /// Appends an array index to a flattened key, in the configured key format.
+    fn write_index(&self, key: &mut String, index: usize) {
+        match self.key_format {
+            KeyFormat::Dotted => {
+                if !key.is_empty() {
+                    key.push_str(self.separator);
+                }
+                write!(key, "{}", index).unwrap();
+            }
+            KeyFormat::Bracketed => write!(key, "[{}]", index).unwrap(),
+            KeyFormat::JsonPath => {
+                if key.is_empty() {
+                    key.push('$');
+                }
+                write!(key, "[{}]", index).unwrap();
+            }
+            KeyFormat::Pointer => write!(key, "/{}", index).unwrap(),
+        }
+    }
+
+    /// Escapes an object key so it can be used as a single segment of a flattened key.
+    ///
+    /// Only applies when `escape_keys` is enabled: backslashes become `\\` and the separator is
+    /// prefixed with a backslash, as is `[` in [`KeyFormat::Bracketed`].
+    fn escape_segment<'k>(&self, segment: &'k str) -> Cow<'k, str> {
+        let bracketed = self.key_format == KeyFormat::Bracketed;
+        if !self.escape_keys
+            || !(segment.contains('\\')
+                || (bracketed && segment.contains('['))
+                || (!self.separator.is_empty() && segment.contains(self.separator)))
+        {
+            return Cow::Borrowed(segment);
+        }
+
+        let mut escaped = String::with_capacity(segment.len() + 2);
+        let mut rest = segment;
+        while let Some(c) = rest.chars().next() {
+            if c == '\\' || (bracketed && c == '[') {
+                escaped.push('\\');
+                escaped.push(c);
+                rest = &rest[1..];
+            } else if !self.separator.is_empty() && rest.starts_with(self.separator) {
+                escaped.push('\\');
+                escaped.push_str(self.separator);
+                rest = &rest[self.separator.len()..];
+            } else {
+                escaped.push(c);
+                rest = &rest[c.len_utf8()..];
+            }
+        }
+        Cow::Owned(escaped)
+    }
+
+    /// Appends an object key to a flattened key, and to its source path when the collision
+    /// policy needs it (see [`Flattener::insert`]).
+    fn push_key(&self, key: &mut String, source: &mut String, segment: &str) {
+        self.write_key(key, segment);
+        if self.collision_policy != CollisionPolicy::MergeIntoArray {
+            write!(source, "{}:{}", segment.len(), segment).unwrap();
+        }
+    }
+
+    /// Appends an array index to a flattened key, and to its source path when the collision
+    /// policy needs it (see [`Flattener::insert`]).
+    fn push_index(&self, key: &mut String, source: &mut String, index: usize) {
+        self.write_index(key, index);
+        if self.collision_policy != CollisionPolicy::MergeIntoArray {
+            let digits = index.checked_ilog10().unwrap_or(0) + 1;
+            write!(source, "{}:{}", digits, index).unwrap();
+        }
+    }
+
+    /// Splits a flattened key into its segments using the configured separator and key format
+    ///
+    /// When `escape_keys` is enabled, escaped separators and backslashes are un-escaped and do
+    /// not split the key. Quoted JSONPath keys and escaped JSON Pointer keys are un-escaped too.
+    /// An empty key, which `flatten` uses for a root-level value, has no segments.
     ///
     /// # Arguments
     ///
-    /// * `prefix` - The prefix part of the key (can be empty string for root level)
-    /// * `suffix` - The suffix part of the key
+    /// * `key` - A key produced by flattening
+    ///
+    /// # Examples
+    /// ```
+    /// use smooth_json;
+    ///
+    /// let flattener = smooth_json::Flattener { escape_keys: true, ..Default::default() };
     ///
-    /// # Returns
+    /// assert_eq!(flattener.split_key("a\\.b.c"), vec!["a.b", "c"]);
     ///
-    /// A formatted key string with the separator inserted between prefix and suffix.
-    fn build_key(&self, prefix: &str, suffix: &str) -> String {
-        let mut key = String::with_capacity(prefix.len() + self.separator.len() + suffix.len());
-        if !prefix.is_empty() {
-            key.push_str(prefix);
-            key.push_str(self.separator);
+    /// let flattener = smooth_json::Flattener {
+    ///     key_format: smooth_json::KeyFormat::JsonPath,
+    ///     ..Default::default()
+    /// };
+    ///
+    /// assert_eq!(flattener.split_key("$.a[0]['b.c']"), vec!["a", "0", "b.c"]);
+    /// ```
+    pub fn split_key(&self, key: &str) -> Vec<String> {
+        if key.is_empty() {
+            return vec![];
+        }
+        match self.key_format {
+            KeyFormat::Dotted if self.separator.is_empty() => vec![key.to_string()],
+            KeyFormat::Dotted if !self.escape_keys => {
+                key.split(self.separator).map(str::to_string).collect()
+            }
+            KeyFormat::Dotted | KeyFormat::Bracketed => self.split_separated(key),
+            KeyFormat::JsonPath => split_json_path(key),
+            KeyFormat::Pointer => split_pointer(key),
+        }
+    }
+
+    /// Splits a key of [`KeyFormat::Dotted`] or [`KeyFormat::Bracketed`], un-escaping segments
+    /// when `escape_keys` is enabled.
+    fn split_separated(&self, key: &str) -> Vec<String> {
+        let bracketed = self.key_format == KeyFormat::Bracketed;
+        let separates = |rest: &str| !self.separator.is_empty() && rest.starts_with(self.separator);
+
+        let mut segments = vec![];
+        let mut segment = String::new();
+        // Whether an object key has started, so an index or separator ends a segment. Nothing
+        // has started right after an index, as the separator that follows it starts the key.
+        let mut open = !bracketed || bracket_index(key).is_none();
+        let mut rest = key;
+        while let Some(c) = rest.chars().next() {
+            let escaped = &rest[c.len_utf8()..];
+            if let Some((index, len)) = bracket_index(rest).filter(|_| bracketed) {
+                if open {
+                    segments.push(std::mem::take(&mut segment));
+                }
+                segments.push(index.to_string());
+                rest = &rest[len..];
+                open = false;
+                continue;
+            }
+
+            if self.escape_keys && c == '\\' && separates(escaped) {
+                segment.push_str(self.separator);
+                rest = &escaped[self.separator.len()..];
+            } else if self.escape_keys
+                && c == '\\'
+                && (escaped.starts_with('\\') || (bracketed && escaped.starts_with('[')))
+            {
+                segment.push_str(&escaped[..1]);
+                rest = &escaped[1..];
+            } else if separates(rest) {
+                if open {
+                    segments.push(std::mem::take(&mut segment));
+                }
+                rest = &rest[self.separator.len()..];
+            } else {
+                segment.push(c);
+                rest = escaped;
+            }
+            open = true;
+        }
+        if open {
+            segments.push(segment);
         }
-        key.push_str(suffix);
-        key
+        segments
     }
 
     /// Flattens JSON variants into a JSON object
@@ -131,200 +690,1018 @@ impl<'a> Flattener<'a> {
     ///
     /// let flattened_example = flattener.flatten(&example);
     /// ```
+    ///
+    /// This never fails: options that [`Flattener::try_flatten`] reports as errors fall back to
+    /// the default behavior, so collisions under [`CollisionPolicy::Error`] are merged into an
+    /// array and subtrees deeper than `max_depth` under [`DepthLimit::Error`] are serialized to
+    /// JSON strings.
     pub fn flatten(&self, json: &Value) -> Value {
-        let mut flattened_val = Map::<String, Value>::new();
-        match json {
-            Value::Array(obj_arr) => self.flatten_array(&mut flattened_val, "", obj_arr),
-            Value::Object(obj_val) => self.flatten_object(&mut flattened_val, None, obj_val, false),
-            _ => self.flatten_value(&mut flattened_val, "", json, false),
-        }
-        Value::Object(flattened_val)
+        self.flatten_with(Cow::Borrowed(json), false)
+            .expect("lenient flattening does not fail")
     }
 
-    fn flatten_object(
-        &self,
-        builder: &mut Map<String, Value>,
-        identifier: Option<&str>,
-        obj: &Map<String, Value>,
-        arr: bool,
-    ) {
-        for (k, v) in obj {
-            let expanded_identifier = match identifier {
-                None => k.clone(),
-                Some(id) => self.build_key(id, k),
-            };
-
-            match v {
-                Value::Object(obj_val) => {
-                    self.flatten_object(builder, Some(expanded_identifier.as_str()), obj_val, arr)
-                }
-                Value::Array(obj_arr) => {
-                    self.flatten_array(builder, expanded_identifier.as_str(), obj_arr)
-                }
-                _ => self.flatten_value(builder, expanded_identifier.as_str(), v, arr),
-            }
-        }
+    /// Flattens JSON variants into a JSON object, reporting problems found along the way
+    ///
+    /// # Arguments
+    ///
+    /// * `json` - A serde_json Value to flatten
+    ///
+    /// # Examples
+    /// ```
+    /// use smooth_json::{CollisionPolicy, FlattenError, Flattener};
+    /// use serde_json::json;
+    ///
+    /// let flattener = Flattener { collision_policy: CollisionPolicy::Error, ..Default::default() };
+    /// let example = json!({
+    ///     "a": { "b": 1 },
+    ///     "a.b": 2
+    /// });
+    ///
+    /// let err = flattener.try_flatten(&example).unwrap_err();
+    ///
+    /// assert_eq!(err, FlattenError::Collision { path: "a.b".to_string() });
+    /// ```
+    pub fn try_flatten(&self, json: &Value) -> Result<Value, FlattenError> {
+        self.flatten_with(Cow::Borrowed(json), true)
     }
 
-    fn flatten_array(&self, builder: &mut Map<String, Value>, identifier: &str, obj: &[Value]) {
-        // Empty arrays should be preserved, instead of being omitted
-        if obj.is_empty() {
-            builder.insert(identifier.to_string(), Value::Array(vec![]));
-            return;
-        }
-
-        use std::fmt::Write;
-        let mut index_buf = String::new();
-
-        for (k, v) in obj.iter().enumerate() {
-            write!(&mut index_buf, "{}", k).unwrap();
-            let with_key = self.build_key(identifier, &index_buf);
-            let current_identifier = if self.preserve_arrays {
-                with_key.as_str()
-            } else {
-                identifier
-            };
-
-            match v {
-                Value::Object(obj_val) => self.flatten_object(
-                    builder,
-                    Some(current_identifier),
-                    obj_val,
-                    self.alt_array_flattening,
-                ),
-                Value::Array(obj_arr) => self.flatten_array(builder, current_identifier, obj_arr),
-                _ => self.flatten_value(builder, current_identifier, v, self.alt_array_flattening),
-            }
+    /// Flattens JSON variants into a JSON object, moving keys and leaves out of `json` instead
+    /// of cloning them
+    ///
+    /// Behaves exactly like [`Flattener::flatten`], but avoids copying every string, number and
+    /// array when the input is no longer needed.
+    ///
+    /// # Arguments
+    ///
+    /// * `json` - A serde_json Value to flatten
+    ///
+    /// # Examples
+    /// ```
+    /// use smooth_json;
+    /// use serde_json::json;
+    ///
+    /// let flattener = smooth_json::Flattener::new();
+    /// let example = json!({
+    ///     "name": "John Doe",
+    ///     "address": {
+    ///         "city": "London"
+    ///     }
+    ///  });
+    ///
+    /// let flattened_example = flattener.flatten_owned(example);
+    ///
+    /// assert_eq!(flattened_example, json!({"name": "John Doe", "address.city": "London"}));
+    /// ```
+    pub fn flatten_owned(&self, json: Value) -> Value {
+        self.flatten_with(Cow::Owned(json), false)
+            .expect("lenient flattening does not fail")
+    }
 
-            index_buf.clear();
-        }
+    /// Flattens JSON variants into a JSON object, moving keys and leaves out of `json` and
+    /// reporting problems found along the way
+    ///
+    /// This is the consuming counterpart of [`Flattener::try_flatten`].
+    ///
+    /// # Arguments
+    ///
+    /// * `json` - A serde_json Value to flatten
+    ///
+    /// # Examples
+    /// ```
+    /// use smooth_json::{DepthLimit, FlattenError, Flattener};
+    /// use serde_json::json;
+    ///
+    /// let flattener = Flattener { max_depth: Some(1), depth_limit: DepthLimit::Error, ..Default::default() };
+    /// let err = flattener.try_flatten_owned(json!({"a": {"b": 1}})).unwrap_err();
+    ///
+    /// assert_eq!(err, FlattenError::DepthExceeded { path: "a".to_string() });
+    /// ```
+    pub fn try_flatten_owned(&self, json: Value) -> Result<Value, FlattenError> {
+        self.flatten_with(Cow::Owned(json), true)
     }
 
-    fn flatten_value(
-        &self,
-        builder: &mut Map<String, Value>,
-        identifier: &str,
-        obj: &Value,
-        arr: bool,
-    ) {
-        let key = identifier.to_string();
-
-        match builder.entry(key) {
-            serde_json::map::Entry::Occupied(mut entry) => {
-                let value = entry.get_mut();
-                if let Some(array) = value.as_array_mut() {
-                    array.push(obj.clone());
-                } else {
-                    let existing = std::mem::take(value);
-                    *value = json!(vec![existing, obj.clone()]);
-                }
-            }
-            serde_json::map::Entry::Vacant(entry) => {
-                entry.insert(if arr {
-                    json!(vec![obj.clone()])
-                } else {
-                    obj.clone()
+    /// Flattens JSON variants into an existing JSON object
+    ///
+    /// Flattened keys are added to `out` without clearing it first, so a hot loop can clear
+    /// and reuse the same map for every record, along with the key buffers held in `scratch`.
+    /// Keys already in `out` are treated as written by a different path, so they are resolved
+    /// with the `collision_policy`.
+    ///
+    /// # Arguments
+    ///
+    /// * `json` - A serde_json Value to flatten
+    /// * `out` - The object to add flattened keys to
+    /// * `scratch` - Buffers reused between calls
+    ///
+    /// # Examples
+    /// ```
+    /// use smooth_json::{Flattener, Scratch};
+    /// use serde_json::{json, Map};
+    ///
+    /// let flattener = Flattener::new();
+    /// let records = vec![json!({"a": {"b": 1}}), json!({"a": {"b": 2}})];
+    ///
+    /// let mut out = Map::new();
+    /// let mut scratch = Scratch::new();
+    /// for record in &records {
+    ///     out.clear();
+    ///     flattener.flatten_into(record, &mut out, &mut scratch);
+    ///     assert!(out.contains_key("a.b"));
+    /// }
+    /// ```
+    pub fn flatten_into(&self, json: &Value, out: &mut Map<String, Value>, scratch: &mut Scratch) {
+        self.flatten_into_with(Cow::Borrowed(json), out, scratch, false)
+            .expect("lenient flattening does not fail")
+    }
+
+    /// Flattens JSON variants into an existing JSON object, reporting problems found along the way
+    ///
+    /// This is the fallible counterpart of [`Flattener::flatten_into`]. When an error is
+    /// returned, `out` holds the keys flattened before the problem was found.
+    ///
+    /// # Arguments
+    ///
+    /// * `json` - A serde_json Value to flatten
+    /// * `out` - The object to add flattened keys to
+    /// * `scratch` - Buffers reused between calls
+    ///
+    /// # Examples
+    /// ```
+    /// use smooth_json::{CollisionPolicy, FlattenError, Flattener, Scratch};
+    /// use serde_json::{json, Map};
+    ///
+    /// let flattener = Flattener { collision_policy: CollisionPolicy::Error, ..Default::default() };
+    ///
+    /// let mut out = Map::new();
+    /// out.insert("a.b".to_string(), json!(1));
+    /// let err = flattener
+    ///     .try_flatten_into(&json!({"a": {"b": 2}}), &mut out, &mut Scratch::new())
+    ///     .unwrap_err();
+    ///
+    /// assert_eq!(err, FlattenError::Collision { path: "a.b".to_string() });
+    /// ```
+    pub fn try_flatten_into(
+        &self,
+        json: &Value,
+        out: &mut Map<String, Value>,
+        scratch: &mut Scratch,
+    ) -> Result<(), FlattenError> {
+        self.flatten_into_with(Cow::Borrowed(json), out, scratch, true)
+    }
+
+    fn flatten_with(&self, json: Cow<Value>, strict: bool) -> Result<Value, FlattenError> {
+        let mut flattened_val = Map::<String, Value>::new();
+        self.flatten_into_with(json, &mut flattened_val, &mut Scratch::new(), strict)?;
+        Ok(Value::Object(flattened_val))
+    }
+
+    fn flatten_into_with(
+        &self,
+        json: Cow<Value>,
+        out: &mut Map<String, Value>,
+        scratch: &mut Scratch,
+        strict: bool,
+    ) -> Result<(), FlattenError> {
+        scratch.sources.clear();
+        let mut builder = Builder {
+            map: out,
+            sources: &mut scratch.sources,
+            strict,
+        };
+
+        let mut walker = Walker::new(
+            self,
+            json,
+            std::mem::take(&mut scratch.key),
+            std::mem::take(&mut scratch.source),
+            std::mem::take(&mut scratch.filter),
+        );
+        let result = self.drain(&mut walker, &mut builder);
+        (scratch.key, scratch.source, scratch.filter) = walker.into_buffers();
+        result
+    }
+
+    /// Inserts every leaf the walker visits into the output.
+    fn drain(&self, walker: &mut Walker, builder: &mut Builder) -> Result<(), FlattenError> {
+        while let Some((leaf, arr)) = walker.next_leaf() {
+            match leaf {
+                Leaf::Value(obj) => {
+                    let value = walk::into_owned(obj);
+                    self.insert(builder, &walker.key, &walker.source, value, arr)?
+                }
+                Leaf::TooDeep(obj) => {
+                    self.flatten_too_deep(builder, &walker.key, &walker.source, obj, arr)?
+                }
+                Leaf::Stringify(obj) => {
+                    let stringified = Value::String(walk::to_json_string(&obj));
+                    walk::drop_value(obj);
+                    self.insert(builder, &walker.key, &walker.source, stringified, arr)?
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Handles a non-empty object or array that sits at `max_depth`.
+    fn flatten_too_deep(
+        &self,
+        builder: &mut Builder,
+        identifier: &str,
+        source: &str,
+        obj: Cow<Value>,
+        arr: bool,
+    ) -> Result<(), FlattenError> {
+        match self.depth_limit {
+            DepthLimit::Error if builder.strict => Err(FlattenError::DepthExceeded {
+                path: identifier.to_string(),
+            }),
+            DepthLimit::Stringify | DepthLimit::Error => {
+                let stringified = Value::String(walk::to_json_string(&obj));
+                walk::drop_value(obj);
+                self.insert(builder, identifier, source, stringified, arr)
+            }
+            DepthLimit::Keep => {
+                self.insert(builder, identifier, source, walk::into_owned(obj), arr)
+            }
+            DepthLimit::Drop => {
+                walk::drop_value(obj);
+                Ok(())
+            }
+        }
+    }
+
+    /// Inserts a leaf value into the output, resolving collisions with the collision policy.
+    ///
+    /// `source` identifies the path the key came from, so the policy can tell a key repeated by
+    /// the elements of an array apart from two different paths that flatten to the same key. It
+    /// is only tracked when the policy needs it.
+    fn insert(
+        &self,
+        builder: &mut Builder,
+        key: &str,
+        source: &str,
+        value: Value,
+        arr: bool,
+    ) -> Result<(), FlattenError> {
+        self.insert_key(builder, key, source, value, arr)?;
+        match self.max_keys {
+            Some(limit) if builder.strict && builder.map.len() > limit => {
+                Err(FlattenError::TooManyKeys {
+                    path: self.output_key(key).into_owned(),
+                    limit,
+                })
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn insert_key(
+        &self,
+        builder: &mut Builder,
+        key: &str,
+        source: &str,
+        value: Value,
+        arr: bool,
+    ) -> Result<(), FlattenError> {
+        let path = key;
+        let key = self.output_key(path);
+        let key = key.as_ref();
+        let lenient_merge = self.collision_policy == CollisionPolicy::Error && !builder.strict;
+        if self.collision_policy == CollisionPolicy::MergeIntoArray || lenient_merge {
+            merge(builder.map, key.to_string(), value, arr);
+            return Ok(());
+        }
+
+        let same_source = match builder.sources.get(key) {
+            Some(existing) => existing == source,
+            None => !builder.map.contains_key(key),
+        };
+        if same_source {
+            builder
+                .sources
+                .entry(key.to_string())
+                .or_insert_with(|| source.to_string());
+            merge(builder.map, key.to_string(), value, arr);
+            return Ok(());
+        }
+
+        match self.collision_policy {
+            CollisionPolicy::MergeIntoArray | CollisionPolicy::KeepFirst => {}
+            CollisionPolicy::KeepLast => {
+                builder.sources.insert(key.to_string(), source.to_string());
+                builder.map.remove(key);
+                merge(builder.map, key.to_string(), value, arr);
+            }
+            CollisionPolicy::Error => {
+                return Err(FlattenError::Collision {
+                    path: key.to_string(),
                 });
             }
+            CollisionPolicy::Suffix => {
+                for n in 1.. {
+                    let suffixed = self.suffixed_output_key(path, n);
+                    let same_source = match builder.sources.get(&suffixed) {
+                        Some(existing) => existing == source,
+                        None => !builder.map.contains_key(&suffixed),
+                    };
+                    if same_source {
+                        builder
+                            .sources
+                            .entry(suffixed.clone())
+                            .or_insert_with(|| source.to_string());
+                        merge(builder.map, suffixed, value, arr);
+                        break;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Output of a single flatten call, along with the source path each key was first written from.
+struct Builder<'m> {
+    map: &'m mut Map<String, Value>,
+    sources: &'m mut HashMap<String, String>,
+    strict: bool,
+}
+
+/// Buffers used while flattening, kept between calls to [`Flattener::flatten_into`] to avoid
+/// reallocating them, and parsing the flattener's path patterns again, for every record
+/// # Examples
+/// ```
+/// use smooth_json::Scratch;
+///
+/// let scratch = Scratch::new();
+/// ```
+#[derive(Debug, Default)]
+pub struct Scratch {
+    key: String,
+    source: String,
+    sources: HashMap<String, String>,
+    /// Patterns of the last flattener used, parsed once rather than for every record
+    filter: Filter,
+}
+
+impl Scratch {
+    /// Returns empty buffers
+    /// # Examples
+    /// ```
+    /// use smooth_json::Scratch;
+    ///
+    /// let scratch = Scratch::new();
+    /// ```
+    pub fn new() -> Self {
+        Scratch::default()
+    }
+}
+
+/// Returns whether a key can be written as `.key` in JSONPath, instead of `['key']`.
+fn is_identifier(segment: &str) -> bool {
+    let mut chars = segment.chars();
+    chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// Parses an index like `[12]` at the start of `rest`, returning its digits and length.
+fn bracket_index(rest: &str) -> Option<(&str, usize)> {
+    let digits = rest.strip_prefix('[')?;
+    let len = digits.bytes().take_while(u8::is_ascii_digit).count();
+    (len > 0 && digits[len..].starts_with(']')).then(|| (&digits[..len], len + 2))
+}
+
+/// Splits a key of [`KeyFormat::JsonPath`]. A key that does not start with `$` is a single
+/// segment.
+fn split_json_path(key: &str) -> Vec<String> {
+    let Some(mut rest) = key.strip_prefix('$') else {
+        return vec![key.to_string()];
+    };
+
+    let mut segments = vec![];
+    while !rest.is_empty() {
+        if let Some(quoted) = rest.strip_prefix("['") {
+            let mut segment = String::new();
+            let mut chars = quoted.char_indices();
+            rest = "";
+            while let Some((i, c)) = chars.next() {
+                match c {
+                    '\\' => segment.extend(chars.next().map(|(_, c)| c)),
+                    '\'' if quoted[i + 1..].starts_with(']') => {
+                        rest = &quoted[i + 2..];
+                        break;
+                    }
+                    c => segment.push(c),
+                }
+            }
+            segments.push(segment);
+        } else if let Some((index, len)) = bracket_index(rest) {
+            segments.push(index.to_string());
+            rest = &rest[len..];
+        } else if let Some(name) = rest.strip_prefix('.') {
+            let end = name.find(['.', '[']).unwrap_or(name.len());
+            segments.push(name[..end].to_string());
+            rest = &name[end..];
+        } else {
+            segments.push(rest.to_string());
+            break;
+        }
+    }
+    segments
+}
+
+/// Splits a key of [`KeyFormat::Pointer`], un-escaping `~1` and then `~0` as RFC 6901 says. A
+/// key that does not start with `/` is a single segment.
+fn split_pointer(key: &str) -> Vec<String> {
+    match key.strip_prefix('/') {
+        Some(rest) => rest
+            .split('/')
+            .map(|segment| segment.replace("~1", "/").replace("~0", "~"))
+            .collect(),
+        None => vec![key.to_string()],
+    }
+}
+
+/// Adds a value to the output, turning repeated keys into an array.
+fn merge(builder: &mut Map<String, Value>, key: String, obj: Value, arr: bool) {
+    // Empty arrays replace whatever was there, instead of being merged
+    if obj.as_array().is_some_and(|a| a.is_empty()) {
+        builder.insert(key, obj);
+        return;
+    }
+
+    match builder.entry(key) {
+        serde_json::map::Entry::Occupied(mut entry) => {
+            let value = entry.get_mut();
+            if let Some(array) = value.as_array_mut() {
+                array.push(obj);
+            } else {
+                let existing = std::mem::take(value);
+                *value = json!(vec![existing, obj]);
+            }
+        }
+        serde_json::map::Entry::Vacant(entry) => {
+            entry.insert(if arr { json!(vec![obj]) } else { obj });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use serde_json::json;
+
+    #[test]
+    fn flattener_is_send_and_sync() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<Flattener>();
+        assert_send_sync::<Scratch>();
+    }
+
+    #[test]
+    fn serde_example() {
+        let flattener = Flattener::new();
+        let base: Value = json!({
+            "name": "John Doe",
+            "age": 43,
+            "address": {
+                "street": "10 Downing Street",
+                "city": "London"
+            },
+            "phones": [
+                "+44 1234567",
+                "+44 2345678"
+            ]
+        });
+
+        let flat = flattener.flatten(&base);
+
+        assert_eq!(
+            flat,
+            json!({
+                "name": "John Doe",
+                "age": 43,
+                "address.street": "10 Downing Street",
+                "address.city": "London",
+                "phones": [
+                    "+44 1234567",
+                    "+44 2345678"
+                ]
+            })
+        );
+    }
+
+    #[test]
+    fn collision_object() {
+        let flattener = Flattener::new();
+        let base: Value = json!({
+          "a": {
+            "b": "c",
+          },
+          "a.b": "d",
+        });
+        let flat = flattener.flatten(&base);
+
+        assert_eq!(
+            flat,
+            json!({
+                "a.b": ["c", "d"],
+            })
+        );
+    }
+
+    #[test]
+    fn collision_array() {
+        let flattener = Flattener::new();
+        let flattener_alt = Flattener {
+            alt_array_flattening: true,
+            ..Default::default()
+        };
+
+        let base: Value = json!({
+          "a": [
+            { "b": "c" },
+            { "b": "d", "c": "e" },
+            [35],
+          ],
+          "a.b": "f",
+        });
+
+        let flat = flattener.flatten(&base);
+        let flat_alt = flattener_alt.flatten(&base);
+
+        assert_eq!(
+            flat,
+            json!({
+                "a.b": ["c", "d", "f"],
+                "a.c": "e",
+                "a": 35,
+            })
+        );
+
+        assert_eq!(
+            flat_alt,
+            json!({
+                "a.b": ["c", "d", "f"],
+                "a.c": ["e"],
+                "a": [35],
+            })
+        );
+    }
+
+    #[test]
+    fn collision_policies() {
+        let base: Value = json!({
+          "a": [
+            { "b": "c" },
+            { "b": "d", "c": "e" },
+          ],
+          "a.b": "f",
+        });
+
+        let flatten_with = |collision_policy| {
+            Flattener {
+                collision_policy,
+                ..Default::default()
+            }
+            .flatten(&base)
+        };
+
+        assert_eq!(
+            flatten_with(CollisionPolicy::MergeIntoArray),
+            json!({ "a.b": ["c", "d", "f"], "a.c": "e" })
+        );
+        assert_eq!(
+            flatten_with(CollisionPolicy::KeepFirst),
+            json!({ "a.b": ["c", "d"], "a.c": "e" })
+        );
+        assert_eq!(
+            flatten_with(CollisionPolicy::KeepLast),
+            json!({ "a.b": "f", "a.c": "e" })
+        );
+        assert_eq!(
+            flatten_with(CollisionPolicy::Suffix),
+            json!({ "a.b": ["c", "d"], "a.b_1": "f", "a.c": "e" })
+        );
+    }
+
+    #[test]
+    fn collision_suffix_groups_by_path() {
+        let flattener = Flattener {
+            collision_policy: CollisionPolicy::Suffix,
+            alt_array_flattening: true,
+            ..Default::default()
+        };
+
+        let base: Value = json!({
+          "a": { "b": "c" },
+          "a.b": [{ "x": 1 }],
+          "a.b.x": [2, 3],
+        });
+
+        assert_eq!(
+            flattener.flatten(&base),
+            json!({ "a.b": "c", "a.b.x": [1], "a.b.x_1": [2, 3] })
+        );
+    }
+
+    #[test]
+    fn collision_policy_ignores_arrays() {
+        let flattener = Flattener {
+            collision_policy: CollisionPolicy::Error,
+            ..Default::default()
+        };
+
+        let input: Value = json!({
+            "x": "value1",
+            "data": [
+                { "x": "value2" },
+                { "x": "value3" },
+                [{ "x": "value4" }]
+            ]
+        });
+
+        assert_eq!(
+            flattener.flatten(&input),
+            json!({ "x": "value1", "data.x": ["value2", "value3", "value4"] })
+        );
+    }
+
+    #[test]
+    fn collision_policy_error() {
+        let flattener = Flattener {
+            collision_policy: CollisionPolicy::Error,
+            ..Default::default()
+        };
+        let input = json!({ "a": { "b": "c" }, "a.b": "d" });
+
+        assert_eq!(
+            flattener.try_flatten(&input),
+            Err(FlattenError::Collision {
+                path: "a.b".to_string()
+            })
+        );
+        assert_eq!(flattener.flatten(&input), json!({ "a.b": ["c", "d"] }));
+    }
+
+    #[test]
+    fn try_flatten_matches_flatten() {
+        let flattener = Flattener {
+            collision_policy: CollisionPolicy::Error,
+            ..Default::default()
+        };
+        let input = json!({
+            "a": [{ "b": "c" }, { "b": "d" }],
+            "e": { "f": [] }
+        });
+
+        assert_eq!(
+            flattener.try_flatten(&input).unwrap(),
+            flattener.flatten(&input)
+        );
+    }
+
+    #[test]
+    fn max_keys_limits() {
+        let input = json!({
+            "a": [{ "b": 1 }, { "b": 2 }],
+            "a.b": 3,
+            "c": { "d": 4 }
+        });
+        let flattener = |collision_policy| Flattener {
+            collision_policy,
+            max_keys: Some(1),
+            ..Default::default()
+        };
+
+        // Values merged into a key that is already there do not add to it
+        let merged = flattener(CollisionPolicy::MergeIntoArray);
+        assert_eq!(
+            merged.try_flatten(&input),
+            Err(FlattenError::TooManyKeys {
+                path: "c.d".to_string(),
+                limit: 1
+            })
+        );
+        assert_eq!(merged.flatten(&input), json!({"a.b": [1, 2, 3], "c.d": 4}));
+
+        assert_eq!(
+            flattener(CollisionPolicy::Suffix).try_flatten(&input),
+            Err(FlattenError::TooManyKeys {
+                path: "a.b".to_string(),
+                limit: 1
+            })
+        );
+
+        let mut out = Map::new();
+        out.insert("x".to_string(), json!(0));
+        let result = merged.try_flatten_into(&json!({"a": 1}), &mut out, &mut Scratch::new());
+        assert_eq!(
+            result,
+            Err(FlattenError::TooManyKeys {
+                path: "a".to_string(),
+                limit: 1
+            })
+        );
+    }
+
+    #[test]
+    fn max_depth_limits() {
+        let input: Value = json!({
+            "a": {
+                "b": { "c": 1 },
+                "d": [{ "e": 2 }],
+                "f": [],
+                "g": 3
+            },
+            "h": 4
+        });
+
+        let flatten_with = |depth_limit| {
+            Flattener {
+                max_depth: Some(2),
+                depth_limit,
+                ..Default::default()
+            }
+            .try_flatten(&input)
+        };
+
+        assert_eq!(
+            flatten_with(DepthLimit::Stringify).unwrap(),
+            json!({
+                "a.b": "{\"c\":1}",
+                "a.d": "[{\"e\":2}]",
+                "a.f": [],
+                "a.g": 3,
+                "h": 4
+            })
+        );
+        assert_eq!(
+            flatten_with(DepthLimit::Keep).unwrap(),
+            json!({
+                "a.b": { "c": 1 },
+                "a.d": [{ "e": 2 }],
+                "a.f": [],
+                "a.g": 3,
+                "h": 4
+            })
+        );
+        assert_eq!(
+            flatten_with(DepthLimit::Drop).unwrap(),
+            json!({ "a.f": [], "a.g": 3, "h": 4 })
+        );
+        assert_eq!(
+            flatten_with(DepthLimit::Error),
+            Err(FlattenError::DepthExceeded {
+                path: "a.b".to_string()
+            })
+        );
+    }
+
+    #[test]
+    fn max_depth_counts_arrays() {
+        let flattener = Flattener {
+            max_depth: Some(3),
+            depth_limit: DepthLimit::Keep,
+            alt_array_flattening: true,
+            ..Default::default()
+        };
+
+        let input: Value = json!({
+            "a": [{ "b": { "c": 1 } }, [[2]], 3]
+        });
+
+        assert_eq!(
+            flattener.flatten(&input),
+            json!({ "a.b": [{ "c": 1 }], "a": [[2], 3] })
+        );
+        assert_eq!(
+            Flattener {
+                max_depth: Some(0),
+                ..Default::default()
+            }
+            .flatten(&input),
+            json!({ "": "{\"a\":[{\"b\":{\"c\":1}},[[2]],3]}" })
+        );
+    }
+
+    /// Unwraps a deeply nested value one level at a time, since dropping it in one go would
+    /// recurse once per level.
+    fn drop_deep(mut value: Value) {
+        loop {
+            value = match value {
+                Value::Object(mut obj) => match obj.remove("a") {
+                    Some(inner) => inner,
+                    None => break,
+                },
+                Value::Array(mut arr) => match arr.pop() {
+                    Some(inner) => inner,
+                    None => break,
+                },
+                _ => break,
+            };
+        }
+    }
+
+    const DEPTH: usize = 100_000;
+
+    /// Nests `1` in `DEPTH` alternating objects and arrays. Built by hand, since `json!` would
+    /// serialize the inner value recursively.
+    fn deep() -> Value {
+        let mut input = json!(1);
+        for i in 0..DEPTH {
+            input = if i % 2 == 0 {
+                Value::Object(Map::from_iter([("a".to_string(), input)]))
+            } else {
+                Value::Array(vec![input])
+            };
+        }
+        input
+    }
+
+    /// The JSON text of `deep()`.
+    fn deep_text() -> String {
+        let mut text = String::new();
+        for i in (0..DEPTH).rev() {
+            text.push_str(if i % 2 == 0 { "{\"a\":" } else { "[" });
+        }
+        text.push('1');
+        for i in 0..DEPTH {
+            text.push(if i % 2 == 0 { '}' } else { ']' });
+        }
+        text
+    }
+
+    /// Checks that a value has the shape of `deep()` without recursing, and drops it.
+    fn assert_deep(mut value: Value) {
+        for i in (0..DEPTH).rev() {
+            value = match (i % 2, value) {
+                (0, Value::Object(mut obj)) if obj.len() == 1 => obj.remove("a").unwrap(),
+                (1, Value::Array(mut arr)) if arr.len() == 1 => arr.pop().unwrap(),
+                _ => panic!("unexpected value at level {}", i),
+            };
+        }
+        assert_eq!(value, json!(1));
+    }
+
+    #[test]
+    fn deep_nesting() {
+        let input = deep();
+
+        let flat = Flattener::new().flatten(&input);
+        let key = vec!["a"; DEPTH / 2].join(".");
+        assert_eq!(flat, json!({ key: 1 }));
+
+        let flat = Flattener {
+            preserve_arrays: true,
+            ..Default::default()
+        }
+        .flatten(&input);
+        let key = vec!["0.a"; DEPTH / 2].join(".");
+        assert_eq!(flat, json!({ key: 1 }));
+
+        drop_deep(input);
+    }
+
+    #[test]
+    fn deep_nesting_past_max_depth() {
+        let record = || Value::Object(Map::from_iter([("x".to_string(), deep())]));
+        let input = record();
+        let flattener = |depth_limit| Flattener {
+            max_depth: Some(1),
+            depth_limit,
+            ..Default::default()
+        };
+
+        for depth_limit in [DepthLimit::Stringify, DepthLimit::Error] {
+            let flat = flattener(depth_limit).flatten(&input);
+            assert_eq!(flat, json!({"x": deep_text()}));
         }
-    }
-}
+        assert_eq!(
+            flattener(DepthLimit::Error).try_flatten(&input),
+            Err(FlattenError::DepthExceeded {
+                path: "x".to_string()
+            })
+        );
+        assert_eq!(flattener(DepthLimit::Drop).flatten(&input), json!({}));
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+        let Value::Object(mut flat) = flattener(DepthLimit::Keep).flatten(&input) else {
+            unreachable!()
+        };
+        assert_deep(flat.remove("x").unwrap());
 
-    use serde_json::json;
+        // Owned subtrees past `max_depth` are dropped without recursing as well
+        let stringify = flattener(DepthLimit::Stringify);
+        let flat = stringify.flatten_owned(record());
+        assert_eq!(flat, json!({"x": deep_text()}));
+        assert_eq!(
+            flattener(DepthLimit::Drop).flatten_owned(record()),
+            json!({})
+        );
+        let Value::Object(mut flat) = flattener(DepthLimit::Keep).flatten_owned(input) else {
+            unreachable!()
+        };
+        assert_deep(flat.remove("x").unwrap());
+    }
 
     #[test]
-    fn serde_example() {
-        let flattener = Flattener::new();
-        let base: Value = json!({
-            "name": "John Doe",
-            "age": 43,
-            "address": {
-                "street": "10 Downing Street",
-                "city": "London"
-            },
-            "phones": [
-                "+44 1234567",
-                "+44 2345678"
-            ]
+    fn empty_objects() {
+        let input: Value = json!({
+            "meta": {},
+            "a": [{}, { "b": {} }],
+            "c": 1
         });
 
-        let flat = flattener.flatten(&base);
+        let flatten_with = |empty_objects, alt_array_flattening| {
+            Flattener {
+                empty_objects,
+                alt_array_flattening,
+                ..Default::default()
+            }
+            .flatten(&input)
+        };
 
+        assert_eq!(flatten_with(EmptyObjects::Omit, false), json!({ "c": 1 }));
         assert_eq!(
-            flat,
-            json!({
-                "name": "John Doe",
-                "age": 43,
-                "address.street": "10 Downing Street",
-                "address.city": "London",
-                "phones": [
-                    "+44 1234567",
-                    "+44 2345678"
-                ]
-            })
+            flatten_with(EmptyObjects::Object, false),
+            json!({ "meta": {}, "a": {}, "a.b": {}, "c": 1 })
+        );
+        assert_eq!(
+            flatten_with(EmptyObjects::Object, true),
+            json!({ "meta": {}, "a": {}, "a.b": {}, "c": 1 })
+        );
+        assert_eq!(
+            flatten_with(EmptyObjects::Null, false),
+            json!({ "meta": null, "a": null, "a.b": null, "c": 1 })
+        );
+        assert_eq!(
+            flatten_with(EmptyObjects::Null, true),
+            json!({ "meta": null, "a": [null], "a.b": [null], "c": 1 })
         );
     }
 
     #[test]
-    fn collision_object() {
-        let flattener = Flattener::new();
-        let base: Value = json!({
-          "a": {
-            "b": "c",
-          },
-          "a.b": "d",
+    fn flatten_owned_matches_flatten() {
+        let input: Value = json!({
+            "a": [
+                "b",
+                ["c", "d"],
+                { "e": ["f", "g"], "h": {} },
+                [{ "i": "j" }, { "e": ["k", { "z": "y" }] }],
+            ],
+            "a.e": "l",
+            "m": { "n": { "o": [] } }
         });
-        let flat = flattener.flatten(&base);
 
-        assert_eq!(
-            flat,
-            json!({
-                "a.b": ["c", "d"],
-            })
-        );
+        for flattener in [
+            Flattener::new(),
+            Flattener {
+                alt_array_flattening: true,
+                ..Default::default()
+            },
+            Flattener {
+                preserve_arrays: true,
+                empty_objects: EmptyObjects::Null,
+                ..Default::default()
+            },
+            Flattener {
+                collision_policy: CollisionPolicy::Suffix,
+                max_depth: Some(2),
+                depth_limit: DepthLimit::Keep,
+                ..Default::default()
+            },
+            Flattener {
+                include: &["a.*", "m"],
+                exclude: &["a.e"],
+                ..Default::default()
+            },
+        ] {
+            assert_eq!(
+                flattener.flatten_owned(input.clone()),
+                flattener.flatten(&input)
+            );
+        }
     }
 
     #[test]
-    fn collision_array() {
-        let flattener = Flattener::new();
-        let flattener_alt = Flattener {
-            alt_array_flattening: true,
+    fn flatten_into_reuses_map() {
+        let flattener = Flattener {
+            collision_policy: CollisionPolicy::Suffix,
             ..Default::default()
         };
+        let mut out = Map::new();
+        let mut scratch = Scratch::new();
 
-        let base: Value = json!({
-          "a": [
-            { "b": "c" },
-            { "b": "d", "c": "e" },
-            [35],
-          ],
-          "a.b": "f",
-        });
-
-        let flat = flattener.flatten(&base);
-        let flat_alt = flattener_alt.flatten(&base);
-
-        assert_eq!(
-            flat,
-            json!({
-                "a.b": ["c", "d", "f"],
-                "a.c": "e",
-                "a": 35,
-            })
-        );
+        flattener.flatten_into(&json!({ "a": { "b": 1 } }), &mut out, &mut scratch);
+        flattener.flatten_into(&json!({ "a": { "b": 2 } }), &mut out, &mut scratch);
+        assert_eq!(Value::Object(out.clone()), json!({ "a.b": 1, "a.b_1": 2 }));
 
-        assert_eq!(
-            flat_alt,
-            json!({
-                "a.b": ["c", "d", "f"],
-                "a.c": ["e"],
-                "a": [35],
-            })
-        );
+        out.clear();
+        let input = json!({ "c": [{ "d": 3 }, { "d": 4 }], "c.d": 5 });
+        flattener.flatten_into(&input, &mut out, &mut scratch);
+        assert_eq!(Value::Object(out), flattener.flatten(&input));
     }
 
     #[test]
@@ -640,6 +2017,324 @@ mod tests {
         }
     }
 
+    #[test]
+    fn keys_with_separator_escaped() {
+        let flattener = Flattener {
+            escape_keys: true,
+            ..Default::default()
+        };
+
+        let input: Value = json!({
+            "a.b": "value1",
+            "a": {
+                "b": "value2",
+                "c\\d": "value3"
+            },
+            "e": [{ "f.g": "value4" }]
+        });
+
+        let result = flattener.flatten(&input);
+
+        assert_eq!(
+            result,
+            json!({
+                "a\\.b": "value1",
+                "a.b": "value2",
+                "a.c\\\\d": "value3",
+                "e.f\\.g": "value4"
+            })
+        );
+
+        assert_eq!(flattener.split_key("a\\.b"), vec!["a.b"]);
+        assert_eq!(flattener.split_key("a.c\\\\d"), vec!["a", "c\\d"]);
+        assert_eq!(flattener.split_key("e.f\\.g"), vec!["e", "f.g"]);
+    }
+
+    #[test]
+    fn escaped_multi_char_separator() {
+        let flattener = Flattener {
+            separator: "::",
+            escape_keys: true,
+            ..Default::default()
+        };
+
+        let input: Value = json!({ "a::b": { "c:d": 1 } });
+        let result = flattener.flatten(&input);
+
+        assert_eq!(result, json!({ "a\\::b::c:d": 1 }));
+        assert_eq!(flattener.split_key("a\\::b::c:d"), vec!["a::b", "c:d"]);
+    }
+
+    #[test]
+    fn key_formats() {
+        let input = json!({
+            "a": [1, {"b/c~d": [2]}],
+            "it's": {"x y": 3, "_k9": 4},
+        });
+        let flatten = |key_format| {
+            let flattener = Flattener {
+                key_format,
+                preserve_arrays: true,
+                ..Default::default()
+            };
+            flattener.flatten(&input)
+        };
+
+        assert_eq!(
+            flatten(KeyFormat::Dotted),
+            json!({"a.0": 1, "a.1.b/c~d.0": 2, "it's.x y": 3, "it's._k9": 4})
+        );
+        assert_eq!(
+            flatten(KeyFormat::Bracketed),
+            json!({"a[0]": 1, "a[1].b/c~d[0]": 2, "it's.x y": 3, "it's._k9": 4})
+        );
+        assert_eq!(
+            flatten(KeyFormat::JsonPath),
+            json!({
+                "$.a[0]": 1,
+                "$.a[1]['b/c~d'][0]": 2,
+                "$['it\\'s']['x y']": 3,
+                "$['it\\'s']._k9": 4
+            })
+        );
+        assert_eq!(
+            flatten(KeyFormat::Pointer),
+            json!({"/a/0": 1, "/a/1/b~1c~0d/0": 2, "/it's/x y": 3, "/it's/_k9": 4})
+        );
+
+        // Without preserve_arrays, only object keys are written
+        let flattener = Flattener {
+            key_format: KeyFormat::Pointer,
+            ..Default::default()
+        };
+        assert_eq!(
+            flattener.flatten(&json!({"a": [{"b": 1}, {"b": 2}]})),
+            json!({"/a/b": [1, 2]})
+        );
+        assert_eq!(flattener.flatten(&json!(1)), json!({"": 1}));
+    }
+
+    #[test]
+    fn include_and_exclude() {
+        let input = json!({
+            "id": 1,
+            "user": {"name": "Ada", "password": "x", "address": {"city": "London"}},
+            "labels": [
+                {"id": 2, "name": "bug", "meta": {"name": "m"}},
+                {"id": 3, "name": "ui"}
+            ]
+        });
+        let flatten = |include, exclude| {
+            let flattener = Flattener {
+                include,
+                exclude,
+                ..Default::default()
+            };
+            flattener.flatten(&input)
+        };
+
+        assert_eq!(
+            flatten(&["user.*"], &[]),
+            json!({"user.name": "Ada", "user.password": "x", "user.address.city": "London"})
+        );
+        assert_eq!(
+            flatten(&["labels.**.name", "id"], &[]),
+            json!({"id": 1, "labels.name": ["bug", "ui"], "labels.meta.name": "m"})
+        );
+        assert_eq!(
+            flatten(&["*.na*"], &[]),
+            json!({"user.name": "Ada", "labels.name": ["bug", "ui"]})
+        );
+        assert_eq!(
+            flatten(&[], &["labels", "*.password"]),
+            json!({"id": 1, "user.name": "Ada", "user.address.city": "London"})
+        );
+
+        // Exclude takes precedence, whichever pattern is more specific
+        assert_eq!(
+            flatten(&["user"], &["user.password", "**.city"]),
+            json!({"user.name": "Ada"})
+        );
+        assert_eq!(flatten(&["user.password"], &["user"]), json!({}));
+        assert_eq!(flatten(&["**"], &["**"]), json!({}));
+
+        // Patterns follow the key format, and match indices with preserve_arrays
+        let flattener = Flattener {
+            key_format: KeyFormat::Pointer,
+            preserve_arrays: true,
+            include: &["/labels/*/name"],
+            exclude: &["/labels/1"],
+            ..Default::default()
+        };
+        assert_eq!(flattener.flatten(&input), json!({"/labels/0/name": "bug"}));
+        let flattener = Flattener {
+            key_format: KeyFormat::JsonPath,
+            preserve_arrays: true,
+            include: &["$.labels[*].id"],
+            ..Default::default()
+        };
+        assert_eq!(
+            flattener.flatten(&input),
+            json!({"$.labels[0].id": 2, "$.labels[1].id": 3})
+        );
+    }
+
+    #[test]
+    fn excluded_subtrees_are_not_visited() {
+        let flattener = Flattener {
+            exclude: &["deep"],
+            collision_policy: CollisionPolicy::Error,
+            max_depth: Some(2),
+            depth_limit: DepthLimit::Error,
+            ..Default::default()
+        };
+        let input = json!({"a": 1, "deep": {"b": {"c": 1}}, "deep.b": 2});
+
+        assert_eq!(
+            flattener.try_flatten(&input),
+            Ok(json!({"a": 1, "deep.b": 2}))
+        );
+        assert_eq!(
+            flattener.try_flatten_owned(input.clone()),
+            Ok(json!({"a": 1, "deep.b": 2}))
+        );
+
+        // An object kept whole by max_depth is only kept when it is included
+        let flattener = Flattener {
+            include: &["a.b.c", "d"],
+            max_depth: Some(1),
+            depth_limit: DepthLimit::Keep,
+            ..Default::default()
+        };
+        let input = json!({"a": {"b": {"c": 1}}, "d": {"e": 2}});
+        assert_eq!(flattener.flatten(&input), json!({"d": {"e": 2}}));
+    }
+
+    #[test]
+    fn keep_whole_and_stringify() {
+        let input = json!({
+            "id": 1,
+            "payload": {"a": [1, {"b": 2}]},
+            "extensions": [],
+            "events": [{"data": {"x": 1}}, {"data": {}}],
+            "meta": {"n": 5}
+        });
+        let flattener = Flattener {
+            keep_whole: &["extensions", "events.data", "meta.n", "payload"],
+            stringify: &["payload"],
+            ..Default::default()
+        };
+
+        assert_eq!(
+            flattener.flatten(&input),
+            json!({
+                "id": 1,
+                "payload": "{\"a\":[1,{\"b\":2}]}",
+                "extensions": [],
+                "events.data": [{"x": 1}, {}],
+                "meta.n": 5
+            })
+        );
+        assert_eq!(
+            flattener.flatten_owned(input.clone()),
+            flattener.flatten(&input)
+        );
+
+        // Kept whole before max_depth is reached, and only when included
+        let flattener = Flattener {
+            keep_whole: &["a", "c"],
+            include: &["a", "c.d"],
+            max_depth: Some(1),
+            depth_limit: DepthLimit::Drop,
+            ..Default::default()
+        };
+        assert_eq!(
+            flattener.flatten(&json!({"a": {"b": 1}, "c": {"d": 1}})),
+            json!({"a": {"b": 1}})
+        );
+    }
+
+    #[test]
+    fn keep_whole_and_stringify_deep_nesting() {
+        let record = || Value::Object(Map::from_iter([("x".to_string(), deep())]));
+        let input = record();
+
+        let keeping = Flattener {
+            keep_whole: &["x"],
+            ..Default::default()
+        };
+        let Value::Object(mut flat) = keeping.flatten(&input) else {
+            unreachable!()
+        };
+        assert_deep(flat.remove("x").unwrap());
+        let Value::Object(mut flat) = keeping.flatten_owned(record()) else {
+            unreachable!()
+        };
+        assert_deep(flat.remove("x").unwrap());
+
+        let stringifying = Flattener {
+            stringify: &["x"],
+            ..Default::default()
+        };
+        assert_eq!(stringifying.flatten(&input), json!({"x": deep_text()}));
+        assert_eq!(
+            stringifying.flatten_owned(record()),
+            json!({"x": deep_text()})
+        );
+
+        // Owned subtrees left out by a pattern are dropped without recursing as well
+        let excluding = Flattener {
+            exclude: &["x"],
+            ..Default::default()
+        };
+        assert_eq!(excluding.flatten_owned(input), json!({}));
+    }
+
+    #[test]
+    fn split_key_formats() {
+        let segments = ["a", "0", "b/c~d", "it's", "x.y", "[1]", "", "\\"];
+        for key_format in [
+            KeyFormat::Bracketed,
+            KeyFormat::JsonPath,
+            KeyFormat::Pointer,
+        ] {
+            let flattener = Flattener {
+                key_format,
+                escape_keys: true,
+                ..Default::default()
+            };
+            let mut key = String::new();
+            flattener.write_key(&mut key, segments[0]);
+            flattener.write_index(&mut key, 0);
+            for segment in &segments[2..] {
+                flattener.write_key(&mut key, segment);
+            }
+
+            assert_eq!(flattener.split_key(&key), segments, "{}", key);
+        }
+
+        let bracketed = Flattener {
+            key_format: KeyFormat::Bracketed,
+            ..Default::default()
+        };
+        assert_eq!(bracketed.split_key("[0][1].a"), vec!["0", "1", "a"]);
+        assert_eq!(bracketed.split_key("a[x].b[2]"), vec!["a[x]", "b", "2"]);
+
+        let json_path = Flattener {
+            key_format: KeyFormat::JsonPath,
+            ..Default::default()
+        };
+        assert_eq!(json_path.split_key("$[3].a"), vec!["3", "a"]);
+        assert_eq!(json_path.split_key("a.b"), vec!["a.b"]);
+
+        let pointer = Flattener {
+            key_format: KeyFormat::Pointer,
+            ..Default::default()
+        };
+        assert_eq!(pointer.split_key("/~01/~10"), vec!["~1", "/0"]);
+        assert_eq!(pointer.split_key("a/b"), vec!["a/b"]);
+    }
+
     #[test]
     fn null_values() {
         let flattener = Flattener::new();
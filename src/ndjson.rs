@@ -0,0 +1,287 @@
+//! Streaming flattening of newline-delimited JSON.
+
+use std::borrow::Cow;
+use std::fmt;
+use std::io;
+use std::io::BufRead;
+
+use serde_json::Map;
+use serde_json::Value;
+
+use crate::FlattenError;
+use crate::Flattener;
+use crate::Scratch;
+
+/// Errors that can occur on a line of newline-delimited JSON
+///
+/// Every variant carries the 1-based line number it was found on, so malformed lines can be
+/// reported or skipped.
+/// # Examples
+/// ```
+/// use smooth_json::{Flattener, NdjsonError};
+///
+/// let flattener = Flattener::new();
+/// let input = "{\"a\": 1}\nnot json\n";
+///
+/// for record in flattener.flatten_ndjson(input.as_bytes()) {
+///     if let Err(NdjsonError::Parse { line, .. }) = record {
+///         assert_eq!(line, 2);
+///     }
+/// }
+/// ```
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum NdjsonError {
+    /// Reading from the underlying reader failed, which ends the stream
+    Io {
+        /// The line being read
+        line: usize,
+        /// The error returned by the reader
+        source: io::Error,
+    },
+    /// The line is not valid JSON
+    Parse {
+        /// The line that failed to parse
+        line: usize,
+        /// The error returned by serde_json
+        source: serde_json::Error,
+    },
+    /// The line is valid JSON, but flattening it reported a problem
+    Flatten {
+        /// The line that failed to flatten
+        line: usize,
+        /// The error returned by [`Flattener::try_flatten`]
+        source: FlattenError,
+    },
+}
+
+impl NdjsonError {
+    /// Returns the 1-based line number the error was found on
+    /// # Examples
+    /// ```
+    /// use smooth_json::Flattener;
+    ///
+    /// let flattener = Flattener::new();
+    /// let mut records = flattener.flatten_ndjson("\n{".as_bytes());
+    ///
+    /// assert_eq!(records.next().unwrap().unwrap_err().line(), 2);
+    /// ```
+    pub fn line(&self) -> usize {
+        match self {
+            NdjsonError::Io { line, .. }
+            | NdjsonError::Parse { line, .. }
+            | NdjsonError::Flatten { line, .. } => *line,
+        }
+    }
+}
+
+impl fmt::Display for NdjsonError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NdjsonError::Io { line, source } => write!(f, "line {}: {}", line, source),
+            NdjsonError::Parse { line, source } => write!(f, "line {}: {}", line, source),
+            NdjsonError::Flatten { line, source } => write!(f, "line {}: {}", line, source),
+        }
+    }
+}
+
+impl std::error::Error for NdjsonError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            NdjsonError::Io { source, .. } => Some(source),
+            NdjsonError::Parse { source, .. } => Some(source),
+            NdjsonError::Flatten { source, .. } => Some(source),
+        }
+    }
+}
+
+/// Iterator over the flattened records of a newline-delimited JSON stream, created by
+/// [`Flattener::flatten_ndjson`]
+/// # Examples
+/// ```
+/// use smooth_json::{Flattener, NdjsonRecords};
+///
+/// let flattener = Flattener::new();
+/// let records: NdjsonRecords<&[u8]> = flattener.flatten_ndjson("{\"a\": 1}".as_bytes());
+/// ```
+pub struct NdjsonRecords<'f, 'a, R> {
+    flattener: &'f Flattener<'a>,
+    reader: R,
+    line: usize,
+    buf: Vec<u8>,
+    scratch: Scratch,
+    done: bool,
+}
+
+impl<'f, 'a, R: BufRead> Iterator for NdjsonRecords<'f, 'a, R> {
+    type Item = Result<Map<String, Value>, NdjsonError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while !self.done {
+            self.line += 1;
+            self.buf.clear();
+
+            match self.reader.read_until(b'\n', &mut self.buf) {
+                Ok(0) => self.done = true,
+                Ok(_) if self.buf.iter().all(u8::is_ascii_whitespace) => {}
+                Ok(_) => {
+                    return Some(flatten_line(
+                        self.flattener,
+                        self.line,
+                        &self.buf,
+                        &mut self.scratch,
+                    ));
+                }
+                Err(source) => {
+                    // The reader may fail the same way forever, so stop after reporting it
+                    self.done = true;
+                    return Some(Err(NdjsonError::Io {
+                        line: self.line,
+                        source,
+                    }));
+                }
+            }
+        }
+        None
+    }
+}
+
+/// Parses and flattens a non-blank line of newline-delimited JSON.
+pub(crate) fn flatten_line(
+    flattener: &Flattener,
+    line: usize,
+    buf: &[u8],
+    scratch: &mut Scratch,
+) -> Result<Map<String, Value>, NdjsonError> {
+    let json: Value =
+        serde_json::from_slice(buf).map_err(|source| NdjsonError::Parse { line, source })?;
+
+    let mut flattened = Map::new();
+    flattener
+        .flatten_into_with(Cow::Owned(json), &mut flattened, scratch, true)
+        .map_err(|source| NdjsonError::Flatten { line, source })?;
+    Ok(flattened)
+}
+
+impl<'a> Flattener<'a> {
+    /// Flattens every record of a newline-delimited JSON stream
+    ///
+    /// Each non-blank line is parsed and flattened like [`Flattener::try_flatten`]. A line that
+    /// fails to parse or flatten yields an error carrying its line number, and the stream
+    /// carries on with the next line, so malformed lines can be skipped or reported. Errors from
+    /// the reader itself end the stream.
+    ///
+    /// # Arguments
+    ///
+    /// * `reader` - A buffered reader over newline-delimited JSON
+    ///
+    /// # Examples
+    /// ```
+    /// use smooth_json;
+    /// use serde_json::json;
+    ///
+    /// let flattener = smooth_json::Flattener::new();
+    /// let input = r#"{"user": {"name": "Alice"}}
+    /// {"user": {"name": "Bob"}}
+    /// "#;
+    ///
+    /// let names: Vec<_> = flattener
+    ///     .flatten_ndjson(input.as_bytes())
+    ///     .filter_map(Result::ok)
+    ///     .map(|record| record["user.name"].clone())
+    ///     .collect();
+    ///
+    /// assert_eq!(names, vec![json!("Alice"), json!("Bob")]);
+    /// ```
+    pub fn flatten_ndjson<'f, R: BufRead>(&'f self, reader: R) -> NdjsonRecords<'f, 'a, R> {
+        NdjsonRecords {
+            flattener: self,
+            reader,
+            line: 0,
+            buf: Vec::new(),
+            scratch: Scratch::new(),
+            done: false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use serde_json::json;
+
+    use crate::CollisionPolicy;
+
+    #[test]
+    fn ndjson_records() {
+        let flattener = Flattener::new();
+        let input = "{\"a\": {\"b\": 1}}\r\n\n  \n[{\"c\": 2}, {\"c\": 3}]\n\"d\"";
+
+        let records: Vec<_> = flattener
+            .flatten_ndjson(input.as_bytes())
+            .map(|record| Value::Object(record.unwrap()))
+            .collect();
+
+        assert_eq!(
+            records,
+            vec![json!({"a.b": 1}), json!({"c": [2, 3]}), json!({"": "d"})]
+        );
+    }
+
+    #[test]
+    fn ndjson_errors_carry_line_numbers() {
+        let flattener = Flattener {
+            collision_policy: CollisionPolicy::Error,
+            ..Default::default()
+        };
+        let input = b"{\"a\": 1}\n{\"a\": \n\n{\"a\": {\"b\": 1}, \"a.b\": 2}\n\xff\n{\"a\": 2}\n";
+
+        let records: Vec<_> = flattener.flatten_ndjson(&input[..]).collect();
+
+        assert_eq!(records.len(), 5);
+        assert_eq!(
+            records[0].as_ref().unwrap(),
+            json!({"a": 1}).as_object().unwrap()
+        );
+        assert!(matches!(
+            records[1],
+            Err(NdjsonError::Parse { line: 2, .. })
+        ));
+        assert!(matches!(
+            &records[2],
+            Err(NdjsonError::Flatten {
+                line: 4,
+                source: FlattenError::Collision { .. }
+            })
+        ));
+        assert!(matches!(
+            records[3],
+            Err(NdjsonError::Parse { line: 5, .. })
+        ));
+        assert_eq!(
+            records[4].as_ref().unwrap(),
+            json!({"a": 2}).as_object().unwrap()
+        );
+    }
+
+    #[test]
+    fn ndjson_stops_after_io_error() {
+        struct Failing;
+
+        impl io::Read for Failing {
+            fn read(&mut self, _: &mut [u8]) -> io::Result<usize> {
+                Err(io::Error::other("disk on fire"))
+            }
+        }
+
+        let flattener = Flattener::new();
+        let mut records = flattener.flatten_ndjson(io::BufReader::new(Failing));
+
+        assert!(matches!(
+            records.next(),
+            Some(Err(NdjsonError::Io { line: 1, .. }))
+        ));
+        assert!(records.next().is_none());
+    }
+}
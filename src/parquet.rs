@@ -0,0 +1,463 @@
+//! Parquet output of flattened records, enabled by the `parquet` feature.
+
+use std::fs::File;
+use std::io;
+use std::path::Path;
+use std::sync::Arc;
+
+use ::parquet::arrow::ArrowWriter;
+use ::parquet::basic::Compression;
+use ::parquet::errors::ParquetError;
+use ::parquet::file::properties::WriterProperties;
+use arrow_schema::SchemaRef;
+use serde_json::Map;
+use serde_json::Value;
+
+use crate::Flattener;
+use crate::Scratch;
+use crate::arrow::BatchBuilder;
+
+/// Rows converted to Arrow at a time. Row groups are still cut at `row_group_size`, since the
+/// Arrow writer splits batches across them.
+const BATCH_SIZE: usize = 1024;
+
+/// Options for writing flattened records as Parquet
+/// # Examples
+/// ```
+/// use smooth_json::ParquetOptions;
+/// use parquet::basic::{Compression, ZstdLevel};
+///
+/// let options = ParquetOptions {
+///     row_group_size: 10_000,
+///     compression: Compression::ZSTD(ZstdLevel::default()),
+/// };
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ParquetOptions {
+    /// Maximum number of rows in a row group, 1048576 by default
+    pub row_group_size: usize,
+    /// Compression codec of every column, `SNAPPY` by default
+    ///
+    /// Every codec but `LZO` can be written; `LZO` is rejected before anything is written.
+    pub compression: Compression,
+}
+
+impl ParquetOptions {
+    /// Rejects codecs the `parquet` crate cannot write, before a file is created for them.
+    fn check(&self) -> Result<(), ParquetError> {
+        match self.compression {
+            Compression::LZO => Err(ParquetError::NYI(
+                "LZO compression is not supported".to_string(),
+            )),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl Default for ParquetOptions {
+    fn default() -> Self {
+        ParquetOptions {
+            row_group_size: 1024 * 1024,
+            compression: Compression::SNAPPY,
+        }
+    }
+}
+
+/// Writes records as Parquet rows with a fixed schema, created by [`Flattener::parquet_writer`]
+///
+/// Each record is flattened, then converted like [`Flattener::record_batch_with_schema`].
+/// [`ParquetWriter::into_inner`] must be called once every record is written, to write the
+/// last row group and the footer of the file.
+/// # Examples
+/// ```
+/// use std::sync::Arc;
+///
+/// use smooth_json::{Flattener, ParquetOptions};
+/// use arrow_schema::{DataType, Field, Schema};
+/// use serde_json::json;
+///
+/// let flattener = Flattener::new();
+/// let schema = Arc::new(Schema::new(vec![Field::new("user.name", DataType::Utf8, true)]));
+/// let mut writer = flattener
+///     .parquet_writer(Vec::new(), schema, ParquetOptions::default())
+///     .unwrap();
+///
+/// writer.write_record(&json!({"user": {"name": "Alice"}})).unwrap();
+///
+/// let bytes = writer.into_inner().unwrap();
+/// assert_eq!(&bytes[..4], b"PAR1");
+/// ```
+pub struct ParquetWriter<'f, 'a, W: io::Write + Send> {
+    flattener: &'f Flattener<'a>,
+    writer: ArrowWriter<W>,
+    builder: BatchBuilder,
+    record: Map<String, Value>,
+    scratch: Scratch,
+}
+
+impl<'f, 'a, W: io::Write + Send> ParquetWriter<'f, 'a, W> {
+    /// Flattens a record and writes it as a row
+    ///
+    /// A record with a value that does not fit the schema, or without a value for a field that
+    /// is not nullable, is not written, and the writer can carry on with the next one.
+    ///
+    /// # Arguments
+    ///
+    /// * `record` - A serde_json Value to flatten
+    ///
+    /// # Examples
+    /// ```
+    /// use std::sync::Arc;
+    ///
+    /// use smooth_json::{Flattener, ParquetOptions};
+    /// use arrow_schema::{DataType, Field, Schema};
+    /// use serde_json::json;
+    ///
+    /// let flattener = Flattener::new();
+    /// let schema = Arc::new(Schema::new(vec![Field::new("a", DataType::Int64, false)]));
+    /// let mut writer = flattener
+    ///     .parquet_writer(Vec::new(), schema, ParquetOptions::default())
+    ///     .unwrap();
+    ///
+    /// assert!(writer.write_record(&json!({"a": 1})).is_ok());
+    /// assert!(writer.write_record(&json!({"a": "one"})).is_err());
+    /// assert!(writer.write_record(&json!({"b": 1})).is_err());
+    /// assert!(writer.write_record(&json!({"a": 2})).is_ok());
+    /// writer.into_inner().unwrap();
+    /// ```
+    pub fn write_record(&mut self, record: &Value) -> Result<(), ParquetError> {
+        self.record.clear();
+        self.flattener
+            .flatten_into(record, &mut self.record, &mut self.scratch);
+        self.write_flattened()
+    }
+
+    fn write_flattened(&mut self) -> Result<(), ParquetError> {
+        self.builder.append(&self.record)?;
+        if self.builder.len() >= BATCH_SIZE {
+            self.writer.write(&self.builder.finish()?)?;
+        }
+        Ok(())
+    }
+
+    /// Writes the rows left, the footer of the file, and returns the underlying writer
+    /// # Examples
+    /// ```
+    /// use std::sync::Arc;
+    ///
+    /// use smooth_json::{Flattener, ParquetOptions};
+    /// use arrow_schema::Schema;
+    ///
+    /// let flattener = Flattener::new();
+    /// let schema = Arc::new(Schema::empty());
+    /// let writer = flattener
+    ///     .parquet_writer(Vec::new(), schema, ParquetOptions::default())
+    ///     .unwrap();
+    ///
+    /// assert!(writer.into_inner().unwrap().ends_with(b"PAR1"));
+    /// ```
+    pub fn into_inner(mut self) -> Result<W, ParquetError> {
+        if self.builder.len() > 0 {
+            self.writer.write(&self.builder.finish()?)?;
+        }
+        self.writer.into_inner()
+    }
+}
+
+impl<'a> Flattener<'a> {
+    /// Returns a writer of flattened records as Parquet, with a schema declared upfront
+    ///
+    /// Records are written as they come, so they can be streamed from a source of any size.
+    /// Use [`Flattener::write_parquet`] to infer the schema from the records instead.
+    ///
+    /// # Arguments
+    ///
+    /// * `writer` - Where the Parquet file is written to
+    /// * `schema` - The Arrow schema of the file, see [`Flattener::record_batch_with_schema`]
+    /// * `options` - How the file is written
+    ///
+    /// # Examples
+    /// ```
+    /// use std::sync::Arc;
+    ///
+    /// use smooth_json::{Flattener, ParquetOptions};
+    /// use arrow_schema::{DataType, Field, Schema};
+    /// use serde_json::json;
+    ///
+    /// let flattener = Flattener::new();
+    /// let schema = Arc::new(Schema::new(vec![Field::new("a.b", DataType::Int64, true)]));
+    /// let options = ParquetOptions { row_group_size: 2, ..Default::default() };
+    ///
+    /// let mut writer = flattener.parquet_writer(Vec::new(), schema, options).unwrap();
+    /// for i in 0..5 {
+    ///     writer.write_record(&json!({"a": {"b": i}})).unwrap();
+    /// }
+    /// writer.into_inner().unwrap();
+    /// ```
+    pub fn parquet_writer<'f, W: io::Write + Send>(
+        &'f self,
+        writer: W,
+        schema: SchemaRef,
+        options: ParquetOptions,
+    ) -> Result<ParquetWriter<'f, 'a, W>, ParquetError> {
+        options.check()?;
+        let properties = WriterProperties::builder()
+            .set_max_row_group_size(options.row_group_size)
+            .set_compression(options.compression)
+            .build();
+
+        Ok(ParquetWriter {
+            flattener: self,
+            writer: ArrowWriter::try_new(writer, schema.clone(), Some(properties))?,
+            builder: BatchBuilder::new(schema)?,
+            record: Map::new(),
+            scratch: Scratch::new(),
+        })
+    }
+
+    /// Writes records to a Parquet file, with a column for every key found in them
+    ///
+    /// The schema is inferred like [`Flattener::record_batch`], so the records are flattened
+    /// and held in memory before the file is written.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - The file to create, or to overwrite
+    /// * `records` - The records to flatten
+    /// * `options` - How the file is written
+    ///
+    /// # Examples
+    /// ```no_run
+    /// use smooth_json::{Flattener, ParquetOptions};
+    /// use serde_json::json;
+    ///
+    /// let flattener = Flattener::new();
+    /// let records = vec![json!({"id": 1, "tags": ["a", "b"]}), json!({"id": 2})];
+    ///
+    /// flattener.write_parquet("records.parquet", &records, ParquetOptions::default()).unwrap();
+    /// ```
+    pub fn write_parquet<'r, P, I>(
+        &self,
+        path: P,
+        records: I,
+        options: ParquetOptions,
+    ) -> Result<(), ParquetError>
+    where
+        P: AsRef<Path>,
+        I: IntoIterator<Item = &'r Value>,
+    {
+        options.check()?;
+        let (flattened, schema) = self.flatten_with_schema(records);
+        let schema = Arc::new(schema);
+        let mut writer = self.parquet_writer(File::create(path)?, schema, options)?;
+        for record in flattened {
+            writer.record = record;
+            writer.write_flattened()?;
+        }
+        writer.into_inner()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use ::parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+    use ::parquet::basic::ZstdLevel;
+    use arrow_array::RecordBatch;
+    use arrow_schema::DataType;
+    use arrow_schema::Field;
+    use arrow_schema::Schema;
+    use serde_json::json;
+
+    /// A file in the temporary directory, removed when dropped.
+    struct TempFile(std::path::PathBuf);
+
+    impl TempFile {
+        fn new(name: &str) -> Self {
+            let file_name = format!("smooth-json-{}-{}.parquet", std::process::id(), name);
+            TempFile(std::env::temp_dir().join(file_name))
+        }
+    }
+
+    impl Drop for TempFile {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.0);
+        }
+    }
+
+    fn records() -> Vec<Value> {
+        (0..10)
+            .map(|i| {
+                json!({
+                    "id": i,
+                    "user": {"name": format!("user{}", i), "score": i as f64 / 2.0},
+                    "tags": (0..i % 3).map(|t| format!("t{}", t)).collect::<Vec<_>>(),
+                    "flag": if i % 2 == 0 { json!(true) } else { json!(null) },
+                })
+            })
+            .collect()
+    }
+
+    fn read(file: &TempFile) -> (Vec<RecordBatch>, Vec<(i64, Compression)>) {
+        let reader =
+            ParquetRecordBatchReaderBuilder::try_new(File::open(&file.0).unwrap()).unwrap();
+        let row_groups = reader
+            .metadata()
+            .row_groups()
+            .iter()
+            .map(|rg| (rg.num_rows(), rg.column(0).compression()))
+            .collect();
+        let batches = reader.build().unwrap().map(Result::unwrap).collect();
+        (batches, row_groups)
+    }
+
+    #[test]
+    fn parquet_round_trip() {
+        let flattener = Flattener::new();
+        let records = records();
+        let file = TempFile::new("round-trip");
+        let options = ParquetOptions {
+            row_group_size: 4,
+            compression: Compression::ZSTD(ZstdLevel::default()),
+        };
+
+        flattener.write_parquet(&file.0, &records, options).unwrap();
+        let (batches, row_groups) = read(&file);
+
+        let zstd = Compression::ZSTD(ZstdLevel::default());
+        assert_eq!(row_groups, vec![(4, zstd), (4, zstd), (2, zstd)]);
+
+        let expected = flattener.record_batch(&records).unwrap();
+        let mut offset = 0;
+        for batch in &batches {
+            assert_eq!(*batch, expected.slice(offset, batch.num_rows()));
+            offset += batch.num_rows();
+        }
+        assert_eq!(offset, records.len());
+    }
+
+    #[test]
+    fn parquet_compression_codecs() {
+        let flattener = Flattener::new();
+        let records = records();
+        let file = TempFile::new("codecs");
+
+        for compression in [
+            Compression::UNCOMPRESSED,
+            Compression::GZIP(Default::default()),
+            Compression::BROTLI(Default::default()),
+            Compression::LZ4_RAW,
+        ] {
+            let options = ParquetOptions {
+                compression,
+                ..Default::default()
+            };
+            flattener.write_parquet(&file.0, &records, options).unwrap();
+            let (batches, row_groups) = read(&file);
+            assert_eq!(row_groups, vec![(10, compression)]);
+            assert_eq!(batches[0], flattener.record_batch(&records).unwrap());
+        }
+
+        let _ = std::fs::remove_file(&file.0);
+        let options = ParquetOptions {
+            compression: Compression::LZO,
+            ..Default::default()
+        };
+        assert!(matches!(
+            flattener.write_parquet(&file.0, &records, options),
+            Err(ParquetError::NYI(_))
+        ));
+        assert!(!file.0.exists());
+    }
+
+    #[test]
+    fn parquet_writer_batches_rows() {
+        let flattener = Flattener::new();
+        let records = records();
+        let expected = flattener.record_batch(&records).unwrap();
+        let file = TempFile::new("writer");
+
+        let mut writer = flattener
+            .parquet_writer(
+                File::create(&file.0).unwrap(),
+                expected.schema(),
+                ParquetOptions::default(),
+            )
+            .unwrap();
+        for _ in 0..250 {
+            for record in &records {
+                writer.write_record(record).unwrap();
+            }
+        }
+        writer.into_inner().unwrap();
+
+        let (batches, row_groups) = read(&file);
+        assert_eq!(row_groups, vec![(2500, Compression::SNAPPY)]);
+        let rows: usize = batches.iter().map(RecordBatch::num_rows).sum();
+        assert_eq!(rows, 2500);
+        assert_eq!(batches[0].slice(0, 10), expected);
+    }
+
+    #[test]
+    fn parquet_writer_skips_rejected_records() {
+        let flattener = Flattener::new();
+        let schema = Arc::new(Schema::new(vec![
+            Field::new("a", DataType::Utf8, true),
+            Field::new("b", DataType::Int64, true),
+        ]));
+        let file = TempFile::new("rejected");
+
+        let mut writer = flattener
+            .parquet_writer(
+                File::create(&file.0).unwrap(),
+                schema.clone(),
+                ParquetOptions::default(),
+            )
+            .unwrap();
+        assert!(writer.write_record(&json!({"a": "x", "b": "one"})).is_err());
+        writer.write_record(&json!({"a": "y", "b": 2})).unwrap();
+        writer.into_inner().unwrap();
+
+        let (batches, _) = read(&file);
+        let expected = flattener
+            .record_batch_with_schema(&[json!({"a": "y", "b": 2})], schema)
+            .unwrap();
+        assert_eq!(batches, vec![expected]);
+    }
+
+    #[test]
+    fn parquet_writer_skips_nulls_in_non_nullable_fields() {
+        let flattener = Flattener::new();
+        let schema = Arc::new(Schema::new(vec![
+            Field::new("a", DataType::Int64, false),
+            Field::new("b", DataType::new_list(DataType::Int64, false), true),
+        ]));
+        let file = TempFile::new("non-nullable");
+
+        let mut writer = flattener
+            .parquet_writer(
+                File::create(&file.0).unwrap(),
+                schema.clone(),
+                ParquetOptions::default(),
+            )
+            .unwrap();
+        assert!(writer.write_record(&json!({"a": null})).is_err());
+        assert!(writer.write_record(&json!({})).is_err());
+        assert!(
+            writer
+                .write_record(&json!({"a": 1, "b": [2, null]}))
+                .is_err()
+        );
+        let good = [json!({"a": 1, "b": [2, 3]}), json!({"a": 2})];
+        for record in &good {
+            writer.write_record(record).unwrap();
+        }
+        writer.into_inner().unwrap();
+
+        let (batches, _) = read(&file);
+        let expected = flattener.record_batch_with_schema(&good, schema).unwrap();
+        assert_eq!(batches, vec![expected]);
+    }
+}
@@ -0,0 +1,256 @@
+//! Parallel flattening of many records, enabled by the `rayon` feature.
+
+use std::collections::VecDeque;
+use std::io::BufRead;
+
+use ::rayon::prelude::*;
+use serde_json::Map;
+use serde_json::Value;
+
+use crate::Flattener;
+use crate::NdjsonError;
+use crate::Scratch;
+use crate::ndjson::flatten_line;
+
+/// Lines read before they are flattened in parallel, bounding how much of the stream is held
+/// in memory at once.
+const LINES_PER_BATCH: usize = 1024;
+
+/// Iterator over the flattened records of a newline-delimited JSON stream, flattened in
+/// parallel, created by [`Flattener::par_flatten_ndjson`]
+///
+/// Records and errors come out in the same order as the lines they were read from, exactly
+/// like [`crate::NdjsonRecords`].
+/// # Examples
+/// ```
+/// use smooth_json::{Flattener, ParNdjsonRecords};
+///
+/// let flattener = Flattener::new();
+/// let records: ParNdjsonRecords<&[u8]> = flattener.par_flatten_ndjson("{\"a\": 1}".as_bytes());
+/// ```
+pub struct ParNdjsonRecords<'f, 'a, R> {
+    flattener: &'f Flattener<'a>,
+    reader: R,
+    line: usize,
+    /// Lines read but not flattened yet, with their line numbers
+    lines: Vec<(usize, Vec<u8>)>,
+    /// Records flattened but not returned yet
+    ready: VecDeque<Result<Map<String, Value>, NdjsonError>>,
+    done: bool,
+}
+
+impl<'f, 'a, R: BufRead> Iterator for ParNdjsonRecords<'f, 'a, R> {
+    type Item = Result<Map<String, Value>, NdjsonError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.ready.is_empty() && !self.done {
+            self.flatten_batch();
+        }
+        self.ready.pop_front()
+    }
+}
+
+impl<'f, 'a, R: BufRead> ParNdjsonRecords<'f, 'a, R> {
+    /// Reads up to [`LINES_PER_BATCH`] lines and flattens them in parallel.
+    fn flatten_batch(&mut self) {
+        let mut failed = None;
+        while self.lines.len() < LINES_PER_BATCH {
+            self.line += 1;
+            let mut buf = Vec::new();
+
+            match self.reader.read_until(b'\n', &mut buf) {
+                Ok(0) => self.done = true,
+                Ok(_) if buf.iter().all(u8::is_ascii_whitespace) => continue,
+                Ok(_) => {
+                    self.lines.push((self.line, buf));
+                    continue;
+                }
+                Err(source) => {
+                    // The reader may fail the same way forever, so stop after reporting it
+                    self.done = true;
+                    failed = Some(NdjsonError::Io {
+                        line: self.line,
+                        source,
+                    });
+                }
+            }
+            break;
+        }
+
+        let flattener = self.flattener;
+        let records: Vec<_> = self
+            .lines
+            .par_drain(..)
+            .map_init(Scratch::new, |scratch, (line, buf)| {
+                flatten_line(flattener, line, &buf, scratch)
+            })
+            .collect();
+        self.ready.extend(records);
+        self.ready.extend(failed.map(Err));
+    }
+}
+
+impl<'a> Flattener<'a> {
+    /// Flattens many records in parallel on the rayon thread pool, like [`Flattener::flatten`]
+    ///
+    /// The flattened records are returned in the same order as `records`. Each thread reuses
+    /// its own [`Scratch`] buffers across the records it flattens.
+    ///
+    /// # Arguments
+    ///
+    /// * `records` - The serde_json Values to flatten
+    ///
+    /// # Examples
+    /// ```
+    /// use smooth_json::Flattener;
+    /// use serde_json::json;
+    ///
+    /// let flattener = Flattener::new();
+    /// let records: Vec<_> = (0..1000).map(|i| json!({"user": {"id": i}})).collect();
+    ///
+    /// let flattened = flattener.flatten_batch(&records);
+    ///
+    /// assert_eq!(flattened[999], json!({"user.id": 999}));
+    /// ```
+    pub fn flatten_batch(&self, records: &[Value]) -> Vec<Value> {
+        records
+            .par_iter()
+            .map_init(Scratch::new, |scratch, record| {
+                let mut out = Map::new();
+                self.flatten_into(record, &mut out, scratch);
+                Value::Object(out)
+            })
+            .collect()
+    }
+
+    /// Flattens every record of a newline-delimited JSON stream in parallel
+    ///
+    /// Lines are read in batches on the calling thread, then parsed and flattened on the rayon
+    /// thread pool. Records, errors and line numbers are the same, and come in the same order,
+    /// as with [`Flattener::flatten_ndjson`].
+    ///
+    /// # Arguments
+    ///
+    /// * `reader` - A buffered reader over newline-delimited JSON
+    ///
+    /// # Examples
+    /// ```
+    /// use smooth_json::Flattener;
+    /// use serde_json::json;
+    ///
+    /// let flattener = Flattener::new();
+    /// let input: String = (0..5000).map(|i| format!("{{\"a\": {{\"b\": {}}}}}\n", i)).collect();
+    ///
+    /// let values: Vec<_> = flattener
+    ///     .par_flatten_ndjson(input.as_bytes())
+    ///     .map(|record| record.unwrap()["a.b"].clone())
+    ///     .collect();
+    ///
+    /// assert_eq!(values.len(), 5000);
+    /// assert_eq!(values[4321], json!(4321));
+    /// ```
+    pub fn par_flatten_ndjson<'f, R: BufRead>(&'f self, reader: R) -> ParNdjsonRecords<'f, 'a, R> {
+        ParNdjsonRecords {
+            flattener: self,
+            reader,
+            line: 0,
+            lines: Vec::new(),
+            ready: VecDeque::new(),
+            done: false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io;
+
+    use ::rayon::ThreadPoolBuilder;
+    use serde_json::json;
+
+    use crate::CollisionPolicy;
+
+    #[test]
+    fn flatten_batch_matches_flatten() {
+        let flattener = Flattener {
+            alt_array_flattening: true,
+            ..Default::default()
+        };
+        let records: Vec<_> = (0..500)
+            .map(|i| json!({"id": i, "items": [{"name": format!("n{}", i)}, {"name": null}]}))
+            .collect();
+
+        let pool = ThreadPoolBuilder::new().num_threads(4).build().unwrap();
+        let flattened = pool.install(|| flattener.flatten_batch(&records));
+
+        let expected: Vec<_> = records.iter().map(|r| flattener.flatten(r)).collect();
+        assert_eq!(flattened, expected);
+        assert!(flattener.flatten_batch(&[]).is_empty());
+    }
+
+    #[test]
+    fn par_ndjson_matches_ndjson() {
+        let flattener = Flattener {
+            collision_policy: CollisionPolicy::Error,
+            ..Default::default()
+        };
+        let mut input = String::new();
+        for i in 0..3000 {
+            match i % 7 {
+                0 => input.push('\n'),
+                1 => input.push_str("{\"a\": \n"),
+                2 => input.push_str("{\"a\": {\"b\": 1}, \"a.b\": 2}\n"),
+                _ => input.push_str(&format!("{{\"a\": {{\"b\": {}}}}}\n", i)),
+            }
+        }
+
+        // NdjsonError is not PartialEq, so errors are compared by their message and line
+        let describe = |record: Result<_, NdjsonError>| record.map_err(|e| e.to_string());
+        let expected: Vec<_> = flattener
+            .flatten_ndjson(input.as_bytes())
+            .map(describe)
+            .collect();
+
+        let pool = ThreadPoolBuilder::new().num_threads(4).build().unwrap();
+        let records: Vec<_> = pool.install(|| {
+            flattener
+                .par_flatten_ndjson(input.as_bytes())
+                .map(describe)
+                .collect()
+        });
+
+        assert_eq!(records.len(), 3000 - 3000 / 7 - 1);
+        assert_eq!(records, expected);
+    }
+
+    #[test]
+    fn par_ndjson_reports_io_error_last() {
+        struct FailsAfter<'i>(&'i [u8]);
+
+        impl io::Read for FailsAfter<'_> {
+            fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+                if self.0.is_empty() {
+                    return Err(io::Error::other("disk on fire"));
+                }
+                let n = self.0.len().min(buf.len());
+                buf[..n].copy_from_slice(&self.0[..n]);
+                self.0 = &self.0[n..];
+                Ok(n)
+            }
+        }
+
+        let flattener = Flattener::new();
+        let reader = io::BufReader::new(FailsAfter(b"{\"a\": 1}\n{\"a\": 2}\n"));
+        let mut records = flattener.par_flatten_ndjson(reader);
+
+        assert_eq!(records.next().unwrap().unwrap()["a"], json!(1));
+        assert_eq!(records.next().unwrap().unwrap()["a"], json!(2));
+        assert!(matches!(
+            records.next(),
+            Some(Err(NdjsonError::Io { line: 3, .. }))
+        ));
+        assert!(records.next().is_none());
+    }
+}
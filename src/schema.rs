@@ -0,0 +1,545 @@
+//! Column names and types inferred across many flattened records.
+
+use std::collections::BTreeSet;
+use std::collections::HashMap;
+use std::fmt;
+
+use serde_json::Map;
+use serde_json::Value;
+
+/// The JSON type of a flattened value
+///
+/// The types form a lattice that [`JsonType::widen`] walks up when a column holds values of
+/// more than one type:
+///
+/// * `Null` is the bottom: widening it with any type gives that type
+/// * `Int` widens to `Float`
+/// * `Array(a)` and `Array(b)` widen to an array of `a` widened with `b`. The element type of
+///   an empty array is `Null`
+/// * Any other type `t` and `Array(a)` widen to an array of `t` widened with `a`, since
+///   flattening turns an array with a single element into that element
+/// * `Mixed` is the top: any other pair of distinct types widens to it, and an array of
+///   `Mixed` elements is itself `Mixed`
+///
+/// `Object` is only seen for objects that are kept whole, such as empty objects kept by
+/// [`crate::EmptyObjects::Object`] or subtrees kept by [`crate::DepthLimit::Keep`].
+/// # Examples
+/// ```
+/// use smooth_json::JsonType;
+/// use serde_json::json;
+///
+/// let ints = JsonType::of(&json!([1, 2]));
+/// let floats = JsonType::of(&json!([1.5, null]));
+///
+/// assert_eq!(ints.widen(&floats), JsonType::Array(Box::new(JsonType::Float)));
+/// assert_eq!(JsonType::Bool.widen(&JsonType::Int), JsonType::Mixed);
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub enum JsonType {
+    /// Only `null`
+    #[default]
+    Null,
+    /// `true` or `false`
+    Bool,
+    /// A number without a fractional part that fits in an `i64` or `u64`
+    Int,
+    /// Any other number
+    Float,
+    /// A string
+    String,
+    /// An array whose elements have the given type, which is never `Mixed`
+    Array(Box<JsonType>),
+    /// An object kept whole
+    Object,
+    /// Values of incompatible types
+    Mixed,
+}
+
+impl JsonType {
+    /// Returns the type of a value
+    /// # Examples
+    /// ```
+    /// use smooth_json::JsonType;
+    /// use serde_json::json;
+    ///
+    /// assert_eq!(JsonType::of(&json!(1)), JsonType::Int);
+    /// assert_eq!(JsonType::of(&json!([1, 1.5])), JsonType::Array(Box::new(JsonType::Float)));
+    /// ```
+    pub fn of(value: &Value) -> JsonType {
+        match value {
+            Value::Null => JsonType::Null,
+            Value::Bool(_) => JsonType::Bool,
+            Value::Number(n) if n.is_f64() => JsonType::Float,
+            Value::Number(_) => JsonType::Int,
+            Value::String(_) => JsonType::String,
+            Value::Array(elements) => {
+                let element = elements
+                    .iter()
+                    .fold(JsonType::Null, |acc, e| acc.widen(&JsonType::of(e)));
+                JsonType::array_of(element)
+            }
+            Value::Object(_) => JsonType::Object,
+        }
+    }
+
+    /// Returns the narrowest type that holds values of both types, see [`JsonType`]
+    /// # Examples
+    /// ```
+    /// use smooth_json::JsonType;
+    ///
+    /// assert_eq!(JsonType::Null.widen(&JsonType::String), JsonType::String);
+    /// assert_eq!(JsonType::Int.widen(&JsonType::Float), JsonType::Float);
+    /// assert_eq!(JsonType::Int.widen(&JsonType::String), JsonType::Mixed);
+    /// ```
+    pub fn widen(&self, other: &JsonType) -> JsonType {
+        match (self, other) {
+            (a, b) if a == b => a.clone(),
+            (JsonType::Null, t) | (t, JsonType::Null) => t.clone(),
+            (JsonType::Int, JsonType::Float) | (JsonType::Float, JsonType::Int) => JsonType::Float,
+            (JsonType::Mixed, _) | (_, JsonType::Mixed) => JsonType::Mixed,
+            (JsonType::Array(a), JsonType::Array(b)) => JsonType::array_of(a.widen(b)),
+            (JsonType::Array(a), t) | (t, JsonType::Array(a)) => JsonType::array_of(a.widen(t)),
+            _ => JsonType::Mixed,
+        }
+    }
+
+    fn array_of(element: JsonType) -> JsonType {
+        match element {
+            JsonType::Mixed => JsonType::Mixed,
+            element => JsonType::Array(Box::new(element)),
+        }
+    }
+}
+
+impl fmt::Display for JsonType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            JsonType::Null => write!(f, "null"),
+            JsonType::Bool => write!(f, "bool"),
+            JsonType::Int => write!(f, "int"),
+            JsonType::Float => write!(f, "float"),
+            JsonType::String => write!(f, "string"),
+            JsonType::Array(element) => write!(f, "array<{}>", element),
+            JsonType::Object => write!(f, "object"),
+            JsonType::Mixed => write!(f, "mixed"),
+        }
+    }
+}
+
+/// A column seen by a [`SchemaInferrer`]
+/// # Examples
+/// ```
+/// use smooth_json::{JsonType, SchemaInferrer};
+/// use serde_json::json;
+///
+/// let mut inferrer = SchemaInferrer::new();
+/// inferrer.observe(&json!({"a": 1}));
+/// inferrer.observe(&json!({"a": 2.5, "b": "x"}));
+///
+/// let a = inferrer.column("a").unwrap();
+/// assert_eq!(a.data_type(), &JsonType::Float);
+/// assert!(!a.is_nullable());
+/// assert!(inferrer.column("b").unwrap().is_nullable());
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Column {
+    name: String,
+    types: BTreeSet<JsonType>,
+    data_type: JsonType,
+    nullable: bool,
+    /// Number of the last record the column was seen in
+    last_record: usize,
+}
+
+impl Column {
+    /// Returns the flattened key of the column
+    /// # Examples
+    /// ```
+    /// use smooth_json::SchemaInferrer;
+    /// use serde_json::json;
+    ///
+    /// let mut inferrer = SchemaInferrer::new();
+    /// inferrer.observe(&json!({"a.b": 1}));
+    ///
+    /// assert_eq!(inferrer.columns()[0].name(), "a.b");
+    /// ```
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Returns every type a value of the column has had
+    /// # Examples
+    /// ```
+    /// use smooth_json::{JsonType, SchemaInferrer};
+    /// use serde_json::json;
+    ///
+    /// let mut inferrer = SchemaInferrer::new();
+    /// inferrer.observe(&json!({"a": 1}));
+    /// inferrer.observe(&json!({"a": null}));
+    ///
+    /// let types: Vec<_> = inferrer.columns()[0].types().iter().collect();
+    /// assert_eq!(types, vec![&JsonType::Null, &JsonType::Int]);
+    /// ```
+    pub fn types(&self) -> &BTreeSet<JsonType> {
+        &self.types
+    }
+
+    /// Returns the type every value of the column fits in, by widening its [`Column::types`]
+    /// # Examples
+    /// ```
+    /// use smooth_json::{JsonType, SchemaInferrer};
+    /// use serde_json::json;
+    ///
+    /// let mut inferrer = SchemaInferrer::new();
+    /// inferrer.observe(&json!({"a": [1]}));
+    /// inferrer.observe(&json!({"a": []}));
+    ///
+    /// assert_eq!(inferrer.columns()[0].data_type(), &JsonType::Array(Box::new(JsonType::Int)));
+    /// ```
+    pub fn data_type(&self) -> &JsonType {
+        &self.data_type
+    }
+
+    /// Returns whether the column was `null` or missing in any record
+    /// # Examples
+    /// ```
+    /// use smooth_json::SchemaInferrer;
+    /// use serde_json::json;
+    ///
+    /// let mut inferrer = SchemaInferrer::new();
+    /// inferrer.observe(&json!({"a": 1, "b": 1}));
+    /// inferrer.observe(&json!({"a": 2}));
+    ///
+    /// assert!(!inferrer.column("a").unwrap().is_nullable());
+    /// assert!(inferrer.column("b").unwrap().is_nullable());
+    /// ```
+    pub fn is_nullable(&self) -> bool {
+        self.nullable
+    }
+}
+
+/// Accumulates the columns of many flattened records
+///
+/// Columns are kept in the order they were first seen in, along with the types of their
+/// values, and whether they were ever `null` or missing from a record.
+/// # Examples
+/// ```
+/// use smooth_json::{Flattener, JsonType, SchemaInferrer};
+/// use serde_json::json;
+///
+/// let flattener = Flattener::new();
+/// let records = vec![
+///     json!({"user": {"name": "Alice", "age": 30}}),
+///     json!({"user": {"name": "Bob", "age": 41.5, "email": null}}),
+/// ];
+///
+/// let mut inferrer = SchemaInferrer::new();
+/// for record in &records {
+///     inferrer.observe(&flattener.flatten(record));
+/// }
+///
+/// let columns: Vec<_> = inferrer
+///     .columns()
+///     .iter()
+///     .map(|column| (column.name(), column.data_type().to_string(), column.is_nullable()))
+///     .collect();
+/// assert_eq!(
+///     columns,
+///     vec![
+///         ("user.age", "float".to_string(), false),
+///         ("user.name", "string".to_string(), false),
+///         ("user.email", "null".to_string(), true),
+///     ]
+/// );
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct SchemaInferrer {
+    columns: Vec<Column>,
+    index: HashMap<String, usize>,
+    records: usize,
+}
+
+impl SchemaInferrer {
+    /// Returns an inferrer that has not seen any records
+    /// # Examples
+    /// ```
+    /// use smooth_json::SchemaInferrer;
+    ///
+    /// let inferrer = SchemaInferrer::new();
+    /// assert!(inferrer.columns().is_empty());
+    /// ```
+    pub fn new() -> Self {
+        SchemaInferrer::default()
+    }
+
+    /// Adds the columns of a flattened record
+    ///
+    /// A value that is not an object is seen as a record with a single column named `""`, the
+    /// key [`crate::Flattener::flatten`] gives it.
+    ///
+    /// # Arguments
+    ///
+    /// * `record` - The output of [`crate::Flattener::flatten`]
+    ///
+    /// # Examples
+    /// ```
+    /// use smooth_json::SchemaInferrer;
+    /// use serde_json::json;
+    ///
+    /// let mut inferrer = SchemaInferrer::new();
+    /// inferrer.observe(&json!({"a": 1}));
+    ///
+    /// assert_eq!(inferrer.records(), 1);
+    /// ```
+    pub fn observe(&mut self, record: &Value) {
+        match record {
+            Value::Object(record) => self.observe_object(record),
+            value => self.observe_entries(std::iter::once(("", value))),
+        }
+    }
+
+    /// Adds the columns of a flattened record held as a map, such as the records of
+    /// [`crate::Flattener::flatten_ndjson`]
+    ///
+    /// # Arguments
+    ///
+    /// * `record` - A flattened record
+    ///
+    /// # Examples
+    /// ```
+    /// use smooth_json::{Flattener, SchemaInferrer};
+    ///
+    /// let flattener = Flattener::new();
+    /// let mut inferrer = SchemaInferrer::new();
+    /// for record in flattener.flatten_ndjson("{\"a\": 1}\n{\"b\": 2}\n".as_bytes()) {
+    ///     inferrer.observe_object(&record.unwrap());
+    /// }
+    ///
+    /// assert_eq!(inferrer.columns().len(), 2);
+    /// ```
+    pub fn observe_object(&mut self, record: &Map<String, Value>) {
+        self.observe_entries(record.iter().map(|(k, v)| (k.as_str(), v)))
+    }
+
+    fn observe_entries<'v>(&mut self, entries: impl Iterator<Item = (&'v str, &'v Value)>) {
+        self.records += 1;
+        let record = self.records;
+
+        let mut seen = 0;
+        for (name, value) in entries {
+            let column = match self.index.get(name) {
+                Some(&i) => &mut self.columns[i],
+                None => {
+                    self.index.insert(name.to_string(), self.columns.len());
+                    self.columns.push(Column {
+                        name: name.to_string(),
+                        types: BTreeSet::new(),
+                        data_type: JsonType::Null,
+                        // Missing from every earlier record
+                        nullable: record > 1,
+                        last_record: record,
+                    });
+                    self.columns.last_mut().unwrap()
+                }
+            };
+
+            let value_type = JsonType::of(value);
+            column.nullable |= value_type == JsonType::Null;
+            column.data_type = column.data_type.widen(&value_type);
+            column.types.insert(value_type);
+            column.last_record = record;
+            seen += 1;
+        }
+
+        if seen < self.columns.len() {
+            for column in &mut self.columns {
+                column.nullable |= column.last_record != record;
+            }
+        }
+    }
+
+    /// Returns the columns seen so far, in the order they were first seen in
+    /// # Examples
+    /// ```
+    /// use smooth_json::SchemaInferrer;
+    /// use serde_json::json;
+    ///
+    /// let mut inferrer = SchemaInferrer::new();
+    /// inferrer.observe(&json!({"b": 1}));
+    /// inferrer.observe(&json!({"a": 1, "b": 2}));
+    ///
+    /// let names: Vec<_> = inferrer.columns().iter().map(|c| c.name()).collect();
+    /// assert_eq!(names, vec!["b", "a"]);
+    /// ```
+    pub fn columns(&self) -> &[Column] {
+        &self.columns
+    }
+
+    /// Returns a column by its flattened key
+    /// # Examples
+    /// ```
+    /// use smooth_json::SchemaInferrer;
+    /// use serde_json::json;
+    ///
+    /// let mut inferrer = SchemaInferrer::new();
+    /// inferrer.observe(&json!({"a": 1}));
+    ///
+    /// assert!(inferrer.column("a").is_some());
+    /// assert!(inferrer.column("b").is_none());
+    /// ```
+    pub fn column(&self, name: &str) -> Option<&Column> {
+        self.index.get(name).map(|&i| &self.columns[i])
+    }
+
+    /// Returns the number of records seen so far
+    /// # Examples
+    /// ```
+    /// use smooth_json::SchemaInferrer;
+    ///
+    /// assert_eq!(SchemaInferrer::new().records(), 0);
+    /// ```
+    pub fn records(&self) -> usize {
+        self.records
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use serde_json::json;
+
+    use crate::EmptyObjects;
+    use crate::Flattener;
+
+    fn array(element: JsonType) -> JsonType {
+        JsonType::Array(Box::new(element))
+    }
+
+    #[test]
+    fn widening_lattice() {
+        let types = [
+            JsonType::Null,
+            JsonType::Bool,
+            JsonType::Int,
+            JsonType::Float,
+            JsonType::String,
+            array(JsonType::Int),
+            JsonType::Object,
+            JsonType::Mixed,
+        ];
+
+        let nested: Vec<_> = types
+            .iter()
+            .map(|t| JsonType::array_of(t.clone()))
+            .collect();
+        let all: Vec<_> = types.iter().chain(&nested).collect();
+        for a in &all {
+            for b in &all {
+                for c in &all {
+                    assert_eq!(a.widen(b).widen(c), a.widen(&b.widen(c)));
+                }
+            }
+        }
+
+        for a in &types {
+            assert_eq!(a.widen(a), *a);
+            assert_eq!(JsonType::Null.widen(a), *a);
+            assert_eq!(JsonType::Mixed.widen(a), JsonType::Mixed);
+            for b in &types {
+                assert_eq!(a.widen(b), b.widen(a));
+            }
+        }
+
+        assert_eq!(JsonType::Int.widen(&JsonType::Float), JsonType::Float);
+        assert_eq!(JsonType::Bool.widen(&JsonType::Int), JsonType::Mixed);
+        assert_eq!(JsonType::String.widen(&JsonType::Object), JsonType::Mixed);
+        assert_eq!(
+            array(JsonType::Int).widen(&array(JsonType::Float)),
+            array(JsonType::Float)
+        );
+        assert_eq!(
+            array(JsonType::Int).widen(&array(JsonType::String)),
+            JsonType::Mixed
+        );
+        assert_eq!(
+            array(JsonType::Int).widen(&JsonType::Int),
+            array(JsonType::Int)
+        );
+        assert_eq!(
+            array(JsonType::Int).widen(&JsonType::String),
+            JsonType::Mixed
+        );
+    }
+
+    #[test]
+    fn types_of_values() {
+        assert_eq!(JsonType::of(&json!(u64::MAX)), JsonType::Int);
+        assert_eq!(JsonType::of(&json!(-1)), JsonType::Int);
+        assert_eq!(JsonType::of(&json!(1.0)), JsonType::Float);
+        assert_eq!(JsonType::of(&json!([])), array(JsonType::Null));
+        assert_eq!(JsonType::of(&json!([null, 1])), array(JsonType::Int));
+        assert_eq!(
+            JsonType::of(&json!([[1], [2.5]])),
+            array(array(JsonType::Float))
+        );
+        assert_eq!(JsonType::of(&json!({})), JsonType::Object);
+        assert_eq!(array(array(JsonType::Int)).to_string(), "array<array<int>>");
+    }
+
+    #[test]
+    fn infer_from_flattened_records() {
+        let flattener = Flattener {
+            empty_objects: EmptyObjects::Object,
+            ..Default::default()
+        };
+        let records = [
+            json!({"id": 1, "tags": [], "user": {"name": "a", "meta": {}}}),
+            json!({"id": 2, "tags": ["x"], "user": {"name": null}, "score": 1}),
+            json!({"id": 3.5, "tags": ["y", "z"], "user": {"name": "c"}, "score": "high"}),
+        ];
+
+        let mut inferrer = SchemaInferrer::new();
+        for record in &records {
+            inferrer.observe(&flattener.flatten(record));
+        }
+
+        let columns: Vec<_> = inferrer
+            .columns()
+            .iter()
+            .map(|c| (c.name(), c.data_type().clone(), c.is_nullable()))
+            .collect();
+        assert_eq!(
+            columns,
+            vec![
+                ("id", JsonType::Float, false),
+                ("tags", array(JsonType::String), false),
+                ("user.meta", JsonType::Object, true),
+                ("user.name", JsonType::String, true),
+                ("score", JsonType::Mixed, true),
+            ]
+        );
+        assert_eq!(
+            inferrer.column("id").unwrap().types(),
+            &BTreeSet::from([JsonType::Int, JsonType::Float])
+        );
+        assert_eq!(
+            inferrer.column("user.name").unwrap().types(),
+            &BTreeSet::from([JsonType::Null, JsonType::String])
+        );
+        assert_eq!(inferrer.records(), 3);
+    }
+
+    #[test]
+    fn infer_from_root_values() {
+        let flattener = Flattener::new();
+        let mut inferrer = SchemaInferrer::new();
+
+        inferrer.observe(&json!("only"));
+        inferrer.observe(&flattener.flatten(&json!(1)));
+
+        assert_eq!(inferrer.columns().len(), 1);
+        assert_eq!(inferrer.columns()[0].name(), "");
+        assert_eq!(inferrer.columns()[0].data_type(), &JsonType::Mixed);
+    }
+}
@@ -0,0 +1,808 @@
+//! Flattening driven by a serde `Serializer`, without converting the input to a `Value` first.
+//!
+//! Values are laid out exactly as `serde_json::to_value` would lay them out, with each scalar
+//! inserted into the output as soon as it is serialized, see `emit`. Only subtrees that are kept
+//! whole because of `max_depth`, `keep_whole` or `stringify` are converted into a `Value`.
+
+use serde::Serialize;
+use serde::ser;
+use serde_json::Error;
+use serde_json::Map;
+use serde_json::Value;
+
+use crate::Flattener;
+use crate::Scratch;
+use crate::emit::Emitter;
+use crate::emit::Mark;
+use crate::filter::Visit;
+
+/// A value whose key is already in the emitter's key buffer.
+struct Node<'s, 'f, 'a, 'm> {
+    emitter: &'s mut Emitter<'f, 'a, 'm>,
+    arr: bool,
+    depth: usize,
+    /// Whether the value is part of the output, rather than only some of its children
+    included: bool,
+}
+
+impl Node<'_, '_, '_, '_> {
+    fn leaf(self, value: Value) -> Result<(), Error> {
+        if !self.included {
+            return Ok(());
+        }
+        self.emitter
+            .insert(value, self.arr)
+            .map_err(ser::Error::custom)
+    }
+}
+
+impl<'s, 'f, 'a, 'm> ser::Serializer for Node<'s, 'f, 'a, 'm> {
+    type Ok = ();
+    type Error = Error;
+
+    type SerializeSeq = Compound<'s, 'f, 'a, 'm>;
+    type SerializeTuple = Compound<'s, 'f, 'a, 'm>;
+    type SerializeTupleStruct = Compound<'s, 'f, 'a, 'm>;
+    type SerializeTupleVariant = Compound<'s, 'f, 'a, 'm>;
+    type SerializeMap = Compound<'s, 'f, 'a, 'm>;
+    type SerializeStruct = Compound<'s, 'f, 'a, 'm>;
+    type SerializeStructVariant = Compound<'s, 'f, 'a, 'm>;
+
+    fn serialize_bool(self, v: bool) -> Result<(), Error> {
+        self.leaf(Value::Bool(v))
+    }
+
+    fn serialize_i8(self, v: i8) -> Result<(), Error> {
+        self.leaf(Value::from(v))
+    }
+
+    fn serialize_i16(self, v: i16) -> Result<(), Error> {
+        self.leaf(Value::from(v))
+    }
+
+    fn serialize_i32(self, v: i32) -> Result<(), Error> {
+        self.leaf(Value::from(v))
+    }
+
+    fn serialize_i64(self, v: i64) -> Result<(), Error> {
+        self.leaf(Value::from(v))
+    }
+
+    fn serialize_i128(self, v: i128) -> Result<(), Error> {
+        self.leaf(v.serialize(serde_json::value::Serializer)?)
+    }
+
+    fn serialize_u8(self, v: u8) -> Result<(), Error> {
+        self.leaf(Value::from(v))
+    }
+
+    fn serialize_u16(self, v: u16) -> Result<(), Error> {
+        self.leaf(Value::from(v))
+    }
+
+    fn serialize_u32(self, v: u32) -> Result<(), Error> {
+        self.leaf(Value::from(v))
+    }
+
+    fn serialize_u64(self, v: u64) -> Result<(), Error> {
+        self.leaf(Value::from(v))
+    }
+
+    fn serialize_u128(self, v: u128) -> Result<(), Error> {
+        self.leaf(v.serialize(serde_json::value::Serializer)?)
+    }
+
+    fn serialize_f32(self, v: f32) -> Result<(), Error> {
+        self.leaf(Value::from(v))
+    }
+
+    fn serialize_f64(self, v: f64) -> Result<(), Error> {
+        self.leaf(Value::from(v))
+    }
+
+    fn serialize_char(self, v: char) -> Result<(), Error> {
+        self.leaf(Value::String(v.to_string()))
+    }
+
+    fn serialize_str(self, v: &str) -> Result<(), Error> {
+        self.leaf(Value::String(v.to_string()))
+    }
+
+    fn serialize_bytes(self, v: &[u8]) -> Result<(), Error> {
+        // Like `Value`, bytes become an array of numbers
+        let mut seq = Compound::new(self, Kind::Array, None);
+        for byte in v {
+            ser::SerializeSeq::serialize_element(&mut seq, byte)?;
+        }
+        ser::SerializeSeq::end(seq)
+    }
+
+    fn serialize_none(self) -> Result<(), Error> {
+        self.leaf(Value::Null)
+    }
+
+    fn serialize_some<T: Serialize + ?Sized>(self, value: &T) -> Result<(), Error> {
+        value.serialize(self)
+    }
+
+    fn serialize_unit(self) -> Result<(), Error> {
+        self.leaf(Value::Null)
+    }
+
+    fn serialize_unit_struct(self, _name: &'static str) -> Result<(), Error> {
+        self.leaf(Value::Null)
+    }
+
+    fn serialize_unit_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+    ) -> Result<(), Error> {
+        self.leaf(Value::String(variant.to_string()))
+    }
+
+    fn serialize_newtype_struct<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        value: &T,
+    ) -> Result<(), Error> {
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_variant<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+        value: &T,
+    ) -> Result<(), Error> {
+        // Serialized as `{variant: value}`
+        let emitter = self.emitter;
+        if emitter.kept_whole(self.depth) && !self.included {
+            return Ok(());
+        }
+        if emitter.kept_whole(self.depth) {
+            let wrapped = Map::from_iter([(variant.to_string(), serde_json::to_value(value)?)]);
+            return emitter
+                .insert_subtree(Value::Object(wrapped), self.arr)
+                .map_err(ser::Error::custom);
+        }
+
+        let mark = emitter.mark();
+        emitter.push_key(variant);
+        match emitter.visit(self.included) {
+            Visit::Skip => {}
+            visit => value.serialize(Node {
+                emitter: &mut *emitter,
+                arr: self.arr,
+                depth: self.depth + 1,
+                included: visit == Visit::Include,
+            })?,
+        }
+        emitter.truncate(mark);
+        Ok(())
+    }
+
+    fn serialize_seq(self, _len: Option<usize>) -> Result<Compound<'s, 'f, 'a, 'm>, Error> {
+        Ok(Compound::new(self, Kind::Array, None))
+    }
+
+    fn serialize_tuple(self, _len: usize) -> Result<Compound<'s, 'f, 'a, 'm>, Error> {
+        Ok(Compound::new(self, Kind::Array, None))
+    }
+
+    fn serialize_tuple_struct(
+        self,
+        _name: &'static str,
+        _len: usize,
+    ) -> Result<Compound<'s, 'f, 'a, 'm>, Error> {
+        Ok(Compound::new(self, Kind::Array, None))
+    }
+
+    fn serialize_tuple_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+        _len: usize,
+    ) -> Result<Compound<'s, 'f, 'a, 'm>, Error> {
+        Ok(Compound::new(self, Kind::Array, Some(variant)))
+    }
+
+    fn serialize_map(self, _len: Option<usize>) -> Result<Compound<'s, 'f, 'a, 'm>, Error> {
+        Ok(Compound::new(self, Kind::Object, None))
+    }
+
+    fn serialize_struct(
+        self,
+        _name: &'static str,
+        _len: usize,
+    ) -> Result<Compound<'s, 'f, 'a, 'm>, Error> {
+        Ok(Compound::new(self, Kind::Object, None))
+    }
+
+    fn serialize_struct_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+        _len: usize,
+    ) -> Result<Compound<'s, 'f, 'a, 'm>, Error> {
+        Ok(Compound::new(self, Kind::Object, Some(variant)))
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Kind {
+    Array,
+    Object,
+}
+
+/// An array or object being serialized.
+///
+/// Variants of enums are serialized as `{variant: array}` or `{variant: object}`, so the
+/// variant name is pushed as an extra key segment around the container.
+struct Compound<'s, 'f, 'a, 'm> {
+    emitter: &'s mut Emitter<'f, 'a, 'm>,
+    kind: Kind,
+    arr: bool,
+    depth: usize,
+    /// Whether the container is part of the output, only some of its children, or none of it
+    visit: Visit,
+    /// Where the buffers are truncated back to after each child
+    mark: Mark,
+    /// Where the buffers are truncated back to once the container ends, when a variant name
+    /// was pushed for it
+    outer_mark: Option<Mark>,
+    len: usize,
+    /// The container collected as a `Value` because it is kept whole, along with the variant
+    /// it has to be wrapped in
+    whole: Option<(Value, Option<&'static str>)>,
+    /// Key of the entry whose value is serialized next, when collecting `whole`
+    pending_key: Option<String>,
+}
+
+impl<'s, 'f, 'a, 'm> Compound<'s, 'f, 'a, 'm> {
+    fn new(node: Node<'s, 'f, 'a, 'm>, kind: Kind, variant: Option<&'static str>) -> Self {
+        let Node {
+            emitter,
+            arr,
+            mut depth,
+            included,
+        } = node;
+        let empty = || match kind {
+            Kind::Array => Value::Array(Vec::new()),
+            Kind::Object => Value::Object(Map::new()),
+        };
+
+        let mut visit = if included {
+            Visit::Include
+        } else {
+            Visit::Descend
+        };
+        let mut whole = None;
+        let mut outer_mark = None;
+        match variant {
+            Some(variant) if emitter.kept_whole(depth) => whole = Some((empty(), Some(variant))),
+            Some(variant) => {
+                outer_mark = Some(emitter.mark());
+                emitter.push_key(variant);
+                visit = emitter.visit(included);
+                depth += 1;
+            }
+            None => {}
+        }
+        if whole.is_none() && emitter.kept_whole(depth) {
+            whole = Some((empty(), None));
+        }
+        // Nothing inside a container kept whole is visited, so it is all or nothing
+        if whole.is_some() && visit != Visit::Include {
+            whole = None;
+            visit = Visit::Skip;
+        }
+
+        Compound {
+            mark: emitter.mark(),
+            emitter,
+            kind,
+            arr,
+            depth,
+            visit,
+            outer_mark,
+            len: 0,
+            whole,
+            pending_key: None,
+        }
+    }
+
+    fn element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        if let Some((Value::Array(elements), _)) = &mut self.whole {
+            elements.push(serde_json::to_value(value)?);
+        } else if self.visit != Visit::Skip {
+            let flattener = self.emitter.flattener;
+            if flattener.preserve_arrays {
+                self.emitter.push_index(self.len);
+            }
+            self.child(value, flattener.alt_array_flattening)?;
+        }
+        self.len += 1;
+        Ok(())
+    }
+
+    fn key(&mut self, key: &str) {
+        if self.whole.is_some() {
+            self.pending_key = Some(key.to_string());
+        } else if self.visit != Visit::Skip {
+            self.emitter.push_key(key);
+        }
+    }
+
+    fn value<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        if let Some((Value::Object(entries), _)) = &mut self.whole {
+            let key = self.pending_key.take().unwrap_or_default();
+            entries.insert(key, serde_json::to_value(value)?);
+        } else if self.visit != Visit::Skip {
+            self.child(value, self.arr)?;
+        }
+        self.len += 1;
+        Ok(())
+    }
+
+    /// Serializes an element or entry whose key segment was just pushed.
+    fn child<T: Serialize + ?Sized>(&mut self, value: &T, arr: bool) -> Result<(), Error> {
+        let visit = self.emitter.visit(self.visit == Visit::Include);
+        if visit != Visit::Skip {
+            value.serialize(Node {
+                emitter: &mut *self.emitter,
+                arr,
+                depth: self.depth + 1,
+                included: visit == Visit::Include,
+            })?;
+        }
+        self.emitter.truncate(self.mark);
+        Ok(())
+    }
+
+    fn end(self) -> Result<(), Error> {
+        let result = match self.whole {
+            Some((value, Some(variant))) => {
+                let wrapped = Map::from_iter([(variant.to_string(), value)]);
+                self.emitter
+                    .insert_subtree(Value::Object(wrapped), self.arr)
+            }
+            Some((value, None)) => self.emitter.insert_subtree(value, self.arr),
+            None if self.len == 0 && self.visit == Visit::Include => {
+                let empty = match self.kind {
+                    Kind::Array => Value::Array(Vec::new()),
+                    Kind::Object => Value::Object(Map::new()),
+                };
+                self.emitter.insert_subtree(empty, self.arr)
+            }
+            None => Ok(()),
+        };
+        if let Some(mark) = self.outer_mark {
+            self.emitter.truncate(mark);
+        }
+        result.map_err(ser::Error::custom)
+    }
+}
+
+/// Turns a map key into a string the way `serde_json::to_value` does.
+fn key_string<T: Serialize + ?Sized>(key: &T) -> Result<String, Error> {
+    match key.serialize(serde_json::value::Serializer)? {
+        Value::String(s) => Ok(s),
+        Value::Number(n) => Ok(n.to_string()),
+        Value::Bool(b) => Ok(b.to_string()),
+        _ => Err(ser::Error::custom("key must be a string")),
+    }
+}
+
+impl ser::SerializeSeq for Compound<'_, '_, '_, '_> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        self.element(value)
+    }
+
+    fn end(self) -> Result<(), Error> {
+        Compound::end(self)
+    }
+}
+
+impl ser::SerializeTuple for Compound<'_, '_, '_, '_> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        self.element(value)
+    }
+
+    fn end(self) -> Result<(), Error> {
+        Compound::end(self)
+    }
+}
+
+impl ser::SerializeTupleStruct for Compound<'_, '_, '_, '_> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        self.element(value)
+    }
+
+    fn end(self) -> Result<(), Error> {
+        Compound::end(self)
+    }
+}
+
+impl ser::SerializeTupleVariant for Compound<'_, '_, '_, '_> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        self.element(value)
+    }
+
+    fn end(self) -> Result<(), Error> {
+        Compound::end(self)
+    }
+}
+
+impl ser::SerializeMap for Compound<'_, '_, '_, '_> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_key<T: Serialize + ?Sized>(&mut self, key: &T) -> Result<(), Error> {
+        self.key(&key_string(key)?);
+        Ok(())
+    }
+
+    fn serialize_value<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        self.value(value)
+    }
+
+    fn end(self) -> Result<(), Error> {
+        Compound::end(self)
+    }
+}
+
+impl ser::SerializeStruct for Compound<'_, '_, '_, '_> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        key: &'static str,
+        value: &T,
+    ) -> Result<(), Error> {
+        self.key(key);
+        self.value(value)
+    }
+
+    fn end(self) -> Result<(), Error> {
+        Compound::end(self)
+    }
+}
+
+impl ser::SerializeStructVariant for Compound<'_, '_, '_, '_> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        key: &'static str,
+        value: &T,
+    ) -> Result<(), Error> {
+        self.key(key);
+        self.value(value)
+    }
+
+    fn end(self) -> Result<(), Error> {
+        Compound::end(self)
+    }
+}
+
+impl<'a> Flattener<'a> {
+    /// Flattens any serializable value without converting it to a `serde_json::Value` first
+    ///
+    /// Gives the same result as `flatten(&serde_json::to_value(value)?)`, except that values
+    /// merged by a collision are in field order rather than in the key order of
+    /// `serde_json::Map`. Errors are the ones `serde_json::to_value` would report, such as a map
+    /// with keys that are not strings.
+    ///
+    /// # Arguments
+    ///
+    /// * `value` - Any value implementing `Serialize`
+    ///
+    /// # Examples
+    /// ```
+    /// use smooth_json;
+    /// use serde_json::json;
+    /// use std::collections::BTreeMap;
+    ///
+    /// let flattener = smooth_json::Flattener::new();
+    /// let mut value = BTreeMap::new();
+    /// value.insert("a", vec![(1, "x"), (2, "y")]);
+    ///
+    /// let flattened = flattener.flatten_serialize(&value).unwrap();
+    ///
+    /// assert_eq!(flattened, json!({"a": [1, "x", 2, "y"]}));
+    /// ```
+    pub fn flatten_serialize<T: Serialize + ?Sized>(&self, value: &T) -> serde_json::Result<Value> {
+        let mut flattened_val = Map::new();
+        self.flatten_serialize_into(value, &mut flattened_val, &mut Scratch::new())?;
+        Ok(Value::Object(flattened_val))
+    }
+
+    /// Flattens any serializable value into an existing JSON object
+    ///
+    /// This is [`Flattener::flatten_serialize`] with the output map and buffers of
+    /// [`Flattener::flatten_into`]. When an error is returned, `out` holds the keys flattened
+    /// before it.
+    ///
+    /// # Arguments
+    ///
+    /// * `value` - Any value implementing `Serialize`
+    /// * `out` - The object to add flattened keys to
+    /// * `scratch` - Buffers reused between calls
+    ///
+    /// # Examples
+    /// ```
+    /// use smooth_json::{Flattener, Scratch};
+    /// use serde_json::{json, Map};
+    ///
+    /// let flattener = Flattener::new();
+    /// let records = vec![vec![("a", 1)], vec![("a", 2)]];
+    ///
+    /// let mut out = Map::new();
+    /// let mut scratch = Scratch::new();
+    /// for record in &records {
+    ///     out.clear();
+    ///     flattener.flatten_serialize_into(record, &mut out, &mut scratch).unwrap();
+    ///     assert!(out.contains_key(""));
+    /// }
+    /// ```
+    pub fn flatten_serialize_into<T: Serialize + ?Sized>(
+        &self,
+        value: &T,
+        out: &mut Map<String, Value>,
+        scratch: &mut Scratch,
+    ) -> serde_json::Result<()> {
+        let mut emitter = Emitter::new(self, out, scratch);
+        match emitter.visit(false) {
+            Visit::Skip => Ok(()),
+            visit => value.serialize(Node {
+                emitter: &mut emitter,
+                arr: false,
+                depth: 0,
+                included: visit == Visit::Include,
+            }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::collections::BTreeMap;
+    use std::collections::HashMap;
+
+    use serde::Serialize;
+    use serde_json::json;
+
+    use crate::CollisionPolicy;
+    use crate::DepthLimit;
+    use crate::EmptyObjects;
+
+    #[derive(Serialize)]
+    struct Order {
+        id: u64,
+        customer: Customer,
+        items: Vec<Item>,
+        notes: Option<String>,
+        tags: Vec<String>,
+        status: Status,
+        metadata: HashMap<String, i32>,
+        coordinates: (f64, f64),
+        unit: (),
+        wrapper: Wrapper,
+    }
+
+    #[derive(Serialize)]
+    struct Customer {
+        name: &'static str,
+        #[serde(rename = "e.mail")]
+        email: &'static str,
+        address: Option<Address>,
+    }
+
+    #[derive(Serialize)]
+    struct Address {
+        city: &'static str,
+        zip: u32,
+    }
+
+    #[derive(Serialize)]
+    struct Item {
+        sku: &'static str,
+        qty: i16,
+        price: f32,
+        kind: Kind,
+    }
+
+    #[derive(Serialize)]
+    enum Kind {
+        Physical { weight: f64 },
+        Digital(String),
+        Bundle(u8, u8),
+        Gift,
+    }
+
+    #[derive(Serialize)]
+    enum Status {
+        Shipped,
+    }
+
+    #[derive(Serialize)]
+    struct Wrapper(Vec<Vec<i32>>);
+
+    fn order() -> Order {
+        Order {
+            id: 7,
+            customer: Customer {
+                name: "Ada",
+                email: "ada@example.com",
+                address: Some(Address {
+                    city: "London",
+                    zip: 12345,
+                }),
+            },
+            items: vec![
+                Item {
+                    sku: "a",
+                    qty: 1,
+                    price: 2.5,
+                    kind: Kind::Physical { weight: 1.25 },
+                },
+                Item {
+                    sku: "b",
+                    qty: -2,
+                    price: f32::NAN,
+                    kind: Kind::Digital("link".to_string()),
+                },
+                Item {
+                    sku: "c",
+                    qty: 3,
+                    price: 0.1,
+                    kind: Kind::Bundle(1, 2),
+                },
+                Item {
+                    sku: "d",
+                    qty: 0,
+                    price: 1.0,
+                    kind: Kind::Gift,
+                },
+            ],
+            notes: None,
+            tags: vec![],
+            status: Status::Shipped,
+            metadata: HashMap::new(),
+            coordinates: (51.5, -0.12),
+            unit: (),
+            wrapper: Wrapper(vec![vec![1, 2], vec![], vec![3]]),
+        }
+    }
+
+    fn assert_same_output<T: Serialize>(flattener: &Flattener, value: &T) {
+        let expected = flattener.flatten(&serde_json::to_value(value).unwrap());
+        assert_eq!(flattener.flatten_serialize(value).unwrap(), expected);
+    }
+
+    #[test]
+    fn serialize_matches_flatten() {
+        let flatteners = [
+            Flattener::new(),
+            Flattener {
+                preserve_arrays: true,
+                ..Default::default()
+            },
+            Flattener {
+                alt_array_flattening: true,
+                ..Default::default()
+            },
+            Flattener {
+                separator: "::",
+                escape_keys: true,
+                ..Default::default()
+            },
+        ];
+
+        let mut keyed = BTreeMap::new();
+        keyed.insert(1, json!({"a": [1, {"b": 2}]}));
+        keyed.insert(2, json!({}));
+
+        for flattener in &flatteners {
+            assert_same_output(flattener, &order());
+            assert_same_output(flattener, &keyed);
+            assert_same_output(flattener, &vec![Some(1), None]);
+            assert_same_output(flattener, &"only");
+            assert_same_output(flattener, &(1, "x", [2.5]));
+            assert_same_output(flattener, &Vec::<i32>::new());
+        }
+    }
+
+    #[test]
+    fn serialize_honors_options() {
+        for empty_objects in [EmptyObjects::Omit, EmptyObjects::Object, EmptyObjects::Null] {
+            for depth_limit in [DepthLimit::Stringify, DepthLimit::Keep, DepthLimit::Drop] {
+                for max_depth in [None, Some(0), Some(1), Some(2), Some(3), Some(4)] {
+                    let flattener = Flattener {
+                        collision_policy: CollisionPolicy::Suffix,
+                        empty_objects,
+                        depth_limit,
+                        max_depth,
+                        ..Default::default()
+                    };
+                    assert_same_output(&flattener, &order());
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn serialize_honors_filters() {
+        let filters: [(&[&str], &[&str]); 5] = [
+            (&["customer.*"], &[]),
+            (&["items.kind.**"], &["items.kind.Bundle"]),
+            (&[], &["customer.address", "**.sku"]),
+            (&["items", "wrapper"], &["items.kind"]),
+            (&["*"], &[""]),
+        ];
+
+        for (include, exclude) in filters {
+            for max_depth in [None, Some(1), Some(2), Some(3)] {
+                for preserve_arrays in [false, true] {
+                    let flattener = Flattener {
+                        include,
+                        exclude,
+                        max_depth,
+                        preserve_arrays,
+                        empty_objects: EmptyObjects::Object,
+                        ..Default::default()
+                    };
+                    assert_same_output(&flattener, &order());
+                }
+            }
+        }
+
+        let stops: [(&[&str], &[&str]); 3] = [
+            (&["customer.address", "items.kind"], &["items.kind.Bundle"]),
+            (&["**"], &[]),
+            (&[], &["tags", "metadata", "items.kind.*", "wrapper"]),
+        ];
+        for (keep_whole, stringify) in stops {
+            for alt_array_flattening in [false, true] {
+                let flattener = Flattener {
+                    keep_whole,
+                    stringify,
+                    alt_array_flattening,
+                    max_depth: Some(3),
+                    ..Default::default()
+                };
+                assert_same_output(&flattener, &order());
+            }
+        }
+    }
+
+    #[test]
+    fn serialize_reports_invalid_keys() {
+        let mut map = BTreeMap::new();
+        map.insert(vec![1], 2);
+
+        assert!(serde_json::to_value(&map).is_err());
+        assert!(Flattener::new().flatten_serialize(&map).is_err());
+    }
+}
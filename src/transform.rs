@@ -0,0 +1,249 @@
+//! Renaming and transforming flattened keys on their way into the output.
+
+use std::borrow::Cow;
+
+use crate::Flattener;
+
+/// A change made to every flattened key, see [`Flattener::key_transforms`]
+/// # Examples
+/// ```
+/// use smooth_json::{Flattener, KeyTransform};
+/// use serde_json::json;
+///
+/// let flattener = Flattener {
+///     key_transforms: &[KeyTransform::SnakeCase, KeyTransform::ReplaceInvalid('_')],
+///     ..Default::default()
+/// };
+/// let flattened = flattener.flatten(&json!({"profile": {"firstName": "Ada"}}));
+///
+/// assert_eq!(flattened, json!({"profile_first_name": "Ada"}));
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyTransform {
+    /// Splits camelCase and PascalCase words with `_` and lowercases them, so `firstName` and
+    /// `HTTPServer` become `first_name` and `http_server`
+    SnakeCase,
+    /// Lowercases every character
+    Lowercase,
+    /// Replaces every character that is not an ASCII letter, digit or `_` with this one
+    ReplaceInvalid(char),
+    /// Shortens keys longer than this many bytes, ending them with `_` and 8 hex digits of a
+    /// hash of the whole key, so shortened keys stay distinct and are the same on every run
+    Truncate(usize),
+}
+
+impl KeyTransform {
+    fn apply(&self, key: &str) -> String {
+        match *self {
+            KeyTransform::SnakeCase => snake_case(key),
+            KeyTransform::Lowercase => key.to_lowercase(),
+            KeyTransform::ReplaceInvalid(replacement) => key
+                .chars()
+                .map(|c| {
+                    if c.is_ascii_alphanumeric() || c == '_' {
+                        c
+                    } else {
+                        replacement
+                    }
+                })
+                .collect(),
+            KeyTransform::Truncate(max_len) if key.len() > max_len => {
+                let suffix = format!("_{:08x}", fnv1a(key));
+                let mut end = max_len.saturating_sub(suffix.len());
+                while !key.is_char_boundary(end) {
+                    end -= 1;
+                }
+                let mut truncated = format!("{}{}", &key[..end], suffix);
+                // The suffix is ASCII, so this only cuts it when `max_len` is shorter than it
+                truncated.truncate(max_len);
+                truncated
+            }
+            KeyTransform::Truncate(_) => key.to_string(),
+        }
+    }
+}
+
+impl<'a> Flattener<'a> {
+    /// Turns a flattened key into the key written to the output, with `rename` and
+    /// `key_transforms`.
+    pub(crate) fn output_key<'k>(&self, key: &'k str) -> Cow<'k, str>
+    where
+        'a: 'k,
+    {
+        if let Some(name) = self.renamed(key) {
+            return Cow::Borrowed(name);
+        }
+        self.transform_key(key)
+    }
+
+    /// Turns a flattened key into the output key of its `n`th collision under
+    /// `CollisionPolicy::Suffix`. The suffix goes on the output name of a renamed key, and on
+    /// any other key before it is transformed, so truncated keys stay short.
+    pub(crate) fn suffixed_output_key(&self, key: &str, n: usize) -> String {
+        match self.renamed(key) {
+            Some(name) => format!("{}_{}", name, n),
+            None => self.transform_key(&format!("{}_{}", key, n)).into_owned(),
+        }
+    }
+
+    fn renamed(&self, key: &str) -> Option<&'a str> {
+        self.rename
+            .iter()
+            .find(|(path, _)| *path == key)
+            .map(|(_, name)| *name)
+    }
+
+    fn transform_key<'k>(&self, key: &'k str) -> Cow<'k, str> {
+        self.key_transforms
+            .iter()
+            .fold(Cow::Borrowed(key), |key, transform| {
+                Cow::Owned(transform.apply(&key))
+            })
+    }
+}
+
+fn snake_case(key: &str) -> String {
+    let mut snake = String::with_capacity(key.len() + 4);
+    let chars: Vec<char> = key.chars().collect();
+    for (i, &c) in chars.iter().enumerate() {
+        if c.is_uppercase() {
+            let prev = i.checked_sub(1).map(|i| chars[i]);
+            let next = chars.get(i + 1);
+            // A word starts after a lowercase letter or digit, or at the last capital of an
+            // acronym that is followed by a lowercase letter, as in `HTTPServer`
+            let starts_word = prev.is_some_and(|p| p.is_lowercase() || p.is_ascii_digit())
+                || (prev.is_some_and(char::is_uppercase) && next.is_some_and(|n| n.is_lowercase()));
+            if starts_word {
+                snake.push('_');
+            }
+            snake.extend(c.to_lowercase());
+        } else {
+            snake.push(c);
+        }
+    }
+    snake
+}
+
+/// The 32-bit FNV-1a hash, which unlike `std`'s hashers is the same on every run and platform.
+fn fnv1a(key: &str) -> u32 {
+    key.bytes().fold(0x811c_9dc5, |hash, byte| {
+        (hash ^ u32::from(byte)).wrapping_mul(0x0100_0193)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use serde_json::json;
+
+    use crate::CollisionPolicy;
+
+    #[test]
+    fn transforms() {
+        let apply = |transform: KeyTransform, key: &str| transform.apply(key);
+
+        assert_eq!(apply(KeyTransform::SnakeCase, "firstName"), "first_name");
+        assert_eq!(apply(KeyTransform::SnakeCase, "HTTPServer"), "http_server");
+        assert_eq!(
+            apply(KeyTransform::SnakeCase, "user.IDs2Go"),
+            "user.i_ds2_go"
+        );
+        assert_eq!(
+            apply(KeyTransform::SnakeCase, "a.B.already_snake"),
+            "a.b.already_snake"
+        );
+        assert_eq!(apply(KeyTransform::Lowercase, "Ünïcode.KEY"), "ünïcode.key");
+        assert_eq!(
+            apply(KeyTransform::ReplaceInvalid('_'), "a.b-c d[0]é"),
+            "a_b_c_d_0__"
+        );
+
+        assert_eq!(apply(KeyTransform::Truncate(12), "short"), "short");
+        let long = "a_very_long_column_name";
+        let truncated = apply(KeyTransform::Truncate(12), long);
+        assert_eq!(truncated, format!("a_v_{:08x}", fnv1a(long)));
+        assert_ne!(
+            truncated,
+            apply(KeyTransform::Truncate(12), "a_very_long_column_nam")
+        );
+        assert_eq!(apply(KeyTransform::Truncate(4), long).len(), 4);
+        assert_eq!(apply(KeyTransform::Truncate(11), "ééééééé").len(), 11);
+        // Reference value of FNV-1a, so the suffix never changes between releases
+        assert_eq!(fnv1a("a"), 0xe40c_292c);
+    }
+
+    #[test]
+    fn rename_and_transform_keys() {
+        let flattener = Flattener {
+            key_transforms: &[
+                KeyTransform::SnakeCase,
+                KeyTransform::ReplaceInvalid('_'),
+                KeyTransform::Truncate(20),
+            ],
+            rename: &[("profile.id", "profile.ID"), ("missing", "x")],
+            collision_policy: CollisionPolicy::Suffix,
+            ..Default::default()
+        };
+        let input = json!({
+            "profile": {"firstName": "Ada", "id": 1, "homeAddressPostcode": "N1"},
+            "profile_first_name": "Lovelace"
+        });
+
+        let mut expected = json!({
+            "profile_first_name": "Ada",
+            "profile_first_name_1": "Lovelace",
+            "profile.ID": 1
+        });
+        let truncated = format!("profile_hom_{:08x}", fnv1a("profile_home_address_postcode"));
+        expected[&truncated] = json!("N1");
+        assert_eq!(flattener.flatten(&input), expected);
+
+        let keys: Vec<_> = flattener.leaves(&input).map(|(key, _)| key).collect();
+        assert!(keys.contains(&Cow::Borrowed("profile.ID")));
+        assert!(keys.contains(&Cow::Borrowed(truncated.as_str())));
+    }
+
+    #[test]
+    fn suffixed_keys_are_transformed() {
+        let flattener = Flattener {
+            key_transforms: &[
+                KeyTransform::ReplaceInvalid('-'),
+                KeyTransform::Truncate(12),
+            ],
+            rename: &[("b", "renamed_b")],
+            collision_policy: CollisionPolicy::Suffix,
+            ..Default::default()
+        };
+        let input = json!({
+            "a": {"bcdefghijk": 1},
+            "a.bcdefghijk": 2,
+            "b": 3,
+            "x": {"b": 4}
+        });
+
+        let flat = flattener.flatten(&input);
+
+        // `a.bcdefghijk_1`, shortened back to 12 bytes
+        let suffixed = format!("a-b_{:08x}", fnv1a("a-bcdefghijk_1"));
+        assert_eq!(
+            flat,
+            json!({
+                "a-bcdefghijk": 1,
+                suffixed: 2,
+                "renamed_b": 3,
+                "x-b": 4
+            })
+        );
+
+        let renamed = Flattener {
+            rename: &[("a.b", "ab"), ("c", "ab")],
+            collision_policy: CollisionPolicy::Suffix,
+            ..Default::default()
+        };
+        assert_eq!(
+            renamed.flatten(&json!({"a": {"b": 1}, "c": 2})),
+            json!({"ab": 1, "ab_1": 2})
+        );
+    }
+}
@@ -0,0 +1,715 @@
+//! Rebuilding nested JSON from the output of [`Flattener::flatten`].
+
+use std::collections::HashMap;
+use std::fmt;
+
+use serde_json::Map;
+use serde_json::Value;
+
+use crate::Flattener;
+
+/// Errors that can occur when unflattening a flat JSON object
+/// # Examples
+/// ```
+/// use smooth_json::{Flattener, UnflattenError};
+/// use serde_json::json;
+///
+/// let flattener = Flattener { preserve_arrays: true, ..Default::default() };
+/// let err = flattener.unflatten(&json!({"a": 1, "a.b": 2})).unwrap_err();
+///
+/// assert_eq!(err, UnflattenError::Conflict { path: "a".to_string() });
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum UnflattenError {
+    /// The value passed to `unflatten` was not a JSON object
+    NotAnObject,
+    /// A key holds a value and is also the prefix of other keys
+    Conflict {
+        /// The flattened path of the conflicting key
+        path: String,
+    },
+    /// Sibling array columns sharing a prefix have different lengths, so they cannot be zipped
+    /// back into an array of objects
+    RaggedArrays {
+        /// The flattened prefix shared by the array columns
+        path: String,
+    },
+    /// Columns sharing a prefix can be rebuilt in more than one way, such as array columns
+    /// next to columns that are not arrays
+    Ambiguous {
+        /// The flattened prefix shared by the columns
+        path: String,
+    },
+}
+
+impl fmt::Display for UnflattenError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            UnflattenError::NotAnObject => write!(f, "flattened JSON must be an object"),
+            UnflattenError::Conflict { path } => {
+                write!(f, "`{}` holds a value and also has nested keys", path)
+            }
+            UnflattenError::RaggedArrays { path } => {
+                write!(f, "array columns under `{}` have different lengths", path)
+            }
+            UnflattenError::Ambiguous { path } => {
+                write!(
+                    f,
+                    "columns under `{}` could be an object or an array of objects",
+                    path
+                )
+            }
+        }
+    }
+}
+
+impl std::error::Error for UnflattenError {}
+
+/// A path trie built from flattened keys, materialized into a `Value` once all keys are inserted.
+#[derive(Default)]
+struct Node {
+    leaf: Option<Value>,
+    index: HashMap<String, usize>,
+    children: Vec<(String, Node)>,
+}
+
+impl Node {
+    fn child(&mut self, segment: &str) -> &mut Node {
+        let idx = match self.index.get(segment) {
+            Some(&idx) => idx,
+            None => {
+                self.index.insert(segment.to_string(), self.children.len());
+                self.children.push((segment.to_string(), Node::default()));
+                self.children.len() - 1
+            }
+        };
+        &mut self.children[idx].1
+    }
+
+    fn insert(&mut self, segments: &[String], value: Value) {
+        let mut node = self;
+        for segment in segments {
+            node = node.child(segment);
+        }
+        node.leaf = Some(value);
+    }
+
+    /// Drops an empty object written by `EmptyObjects::Object` when other keys are nested under
+    /// the same path, since they describe the same object.
+    fn absorb_empty_object(&mut self) {
+        if !self.children.is_empty()
+            && self
+                .leaf
+                .as_ref()
+                .is_some_and(|leaf| leaf.as_object().is_some_and(Map::is_empty))
+        {
+            self.leaf = None;
+        }
+    }
+
+    /// Rebuilds the subtree, treating segments that are exactly the array indices `0..n` as an
+    /// array. Any other segments, including sparse or huge indices, stay object keys.
+    fn into_indexed(mut self, flattener: &Flattener, path: &str) -> Result<Value, UnflattenError> {
+        self.absorb_empty_object();
+        if let Some(leaf) = self.leaf {
+            if !self.children.is_empty() {
+                return Err(UnflattenError::Conflict {
+                    path: path.to_string(),
+                });
+            }
+            return Ok(leaf);
+        }
+
+        let indices: Option<Vec<usize>> = self
+            .children
+            .iter()
+            .map(|(segment, _)| parse_index(segment))
+            .collect();
+
+        // Segments are distinct and `parse_index` only accepts one spelling of each index, so
+        // `len` indices that are all below `len` are exactly `0..len`.
+        let len = self.children.len();
+        match indices {
+            Some(indices) if len > 0 && indices.iter().all(|&idx| idx < len) => {
+                let mut arr = vec![Value::Null; len];
+                for (idx, (_, child)) in indices.into_iter().zip(self.children) {
+                    let mut child_path = path.to_string();
+                    flattener.write_index(&mut child_path, idx);
+                    arr[idx] = child.into_indexed(flattener, &child_path)?;
+                }
+                Ok(Value::Array(arr))
+            }
+            _ => {
+                let mut obj = Map::with_capacity(self.children.len());
+                for (segment, child) in self.children {
+                    let mut child_path = path.to_string();
+                    flattener.write_key(&mut child_path, &segment);
+                    let value = child.into_indexed(flattener, &child_path)?;
+                    obj.insert(segment, value);
+                }
+                Ok(Value::Object(obj))
+            }
+        }
+    }
+
+    /// Rebuilds the subtree, zipping sibling array columns that share a prefix into an array of
+    /// objects. When `strict`, columns that would be zipped are reported as ambiguous instead.
+    fn into_zipped(
+        mut self,
+        flattener: &Flattener,
+        path: &str,
+        strict: bool,
+    ) -> Result<Value, UnflattenError> {
+        self.absorb_empty_object();
+        if let Some(leaf) = self.leaf {
+            if !self.children.is_empty() {
+                return Err(UnflattenError::Conflict {
+                    path: path.to_string(),
+                });
+            }
+            return Ok(leaf);
+        }
+
+        // The root is always an object, and a single column is as likely to be a nested array as
+        // an array of objects, so only zip where two or more columns branch off.
+        if !path.is_empty() && self.children.len() > 1 {
+            let mut lengths = vec![];
+            let mut other_columns = false;
+            if self.column_lengths(&mut lengths, &mut other_columns) && !lengths.is_empty() {
+                let len = lengths[0];
+                if lengths.iter().any(|&l| l != len) {
+                    return Err(UnflattenError::RaggedArrays {
+                        path: path.to_string(),
+                    });
+                }
+                // A column that is not an array is either a plain field of an object holding
+                // lists, or what a field missing from some elements of an array leaves behind
+                if other_columns || (strict && len > 0) {
+                    return Err(UnflattenError::Ambiguous {
+                        path: path.to_string(),
+                    });
+                }
+                if len > 0 {
+                    return Ok(Value::Array((0..len).map(|i| self.select(i)).collect()));
+                }
+            }
+        }
+
+        let mut obj = Map::with_capacity(self.children.len());
+        for (segment, child) in self.children {
+            let mut child_path = path.to_string();
+            flattener.write_key(&mut child_path, &segment);
+            let value = child.into_zipped(flattener, &child_path, strict)?;
+            obj.insert(segment, value);
+        }
+        Ok(Value::Object(obj))
+    }
+
+    /// Collects the lengths of the array columns below this node and notes whether there are
+    /// other columns, returning `false` if a column is also the prefix of other keys.
+    fn column_lengths(&self, lengths: &mut Vec<usize>, other_columns: &mut bool) -> bool {
+        match &self.leaf {
+            Some(_) if !self.children.is_empty() => false,
+            Some(Value::Array(arr)) => {
+                lengths.push(arr.len());
+                true
+            }
+            Some(_) => {
+                *other_columns = true;
+                true
+            }
+            None => self
+                .children
+                .iter()
+                .all(|(_, child)| child.column_lengths(lengths, other_columns)),
+        }
+    }
+
+    /// Builds the object holding the `i`th element of every column below this node.
+    fn select(&self, i: usize) -> Value {
+        match &self.leaf {
+            Some(leaf) => leaf[i].clone(),
+            None => Value::Object(
+                self.children
+                    .iter()
+                    .map(|(segment, child)| (segment.clone(), child.select(i)))
+                    .collect(),
+            ),
+        }
+    }
+}
+
+/// Parses a key segment as an array index, rejecting forms `usize::from_str` accepts but
+/// `flatten` never writes (leading zeros or a `+` sign).
+fn parse_index(segment: &str) -> Option<usize> {
+    if segment.is_empty()
+        || !segment.bytes().all(|b| b.is_ascii_digit())
+        || (segment.len() > 1 && segment.starts_with('0'))
+    {
+        return None;
+    }
+    segment.parse().ok()
+}
+
+impl<'a> Flattener<'a> {
+    /// Rebuilds a nested JSON value from a flat object produced by [`Flattener::flatten`]
+    ///
+    /// Keys are split with the configured `separator`. With `preserve_arrays`, sibling segments
+    /// that are exactly the array indices `0..n` are turned back into arrays, which is the exact
+    /// inverse of flattening as long as the original keys do not look like array indices and
+    /// either do not contain the separator or were flattened with `escape_keys`. Sparse indices
+    /// stay object keys.
+    ///
+    /// Without `preserve_arrays`, array positions are not part of the keys, so the
+    /// reconstruction is best-effort: when two or more columns share a prefix and are all
+    /// arrays of the same length (e.g. `labels.id` and `labels.name`), they are zipped back into
+    /// an array of objects under that prefix. Rather than being guessed at, array columns of
+    /// different lengths are reported as [`UnflattenError::RaggedArrays`], and array columns
+    /// next to columns that are not arrays, such as `user.name` and `user.roles`, as
+    /// [`UnflattenError::Ambiguous`]: they are list fields of an object, or an array of objects
+    /// some of whose elements miss a field.
+    ///
+    /// The zipping itself is a guess: an object whose fields are all lists of the same length,
+    /// such as `{"user": {"tags": ["a", "b"], "roles": ["x", "y"]}}`, flattens to the same keys
+    /// as an array of objects and comes back as one. Use [`Flattener::unflatten_strict`] to
+    /// report it as ambiguous instead, or `preserve_arrays` when that matters.
+    ///
+    /// Empty objects kept with [`EmptyObjects::Object`](crate::EmptyObjects::Object) are
+    /// restored, and merged with any keys nested under the same path. Empty objects written as
+    /// `null` with [`EmptyObjects::Null`](crate::EmptyObjects::Null) cannot be told apart from
+    /// real nulls and stay `null`.
+    ///
+    /// # Arguments
+    ///
+    /// * `json` - A flat serde_json Object to unflatten
+    ///
+    /// # Examples
+    /// ```
+    /// use smooth_json;
+    /// use serde_json::json;
+    ///
+    /// let flattener = smooth_json::Flattener { preserve_arrays: true, ..Default::default() };
+    /// let example = json!({
+    ///     "a": [
+    ///         "b",
+    ///         { "c": "d" }
+    ///     ]
+    /// });
+    ///
+    /// let flattened_example = flattener.flatten(&example);
+    /// let unflattened_example = flattener.unflatten(&flattened_example).unwrap();
+    ///
+    /// assert_eq!(unflattened_example, example);
+    /// ```
+    pub fn unflatten(&self, json: &Value) -> Result<Value, UnflattenError> {
+        self.unflatten_with(json, false)
+    }
+
+    /// Rebuilds a nested JSON value like [`Flattener::unflatten`], but never guesses
+    ///
+    /// Without `preserve_arrays`, sibling array columns of the same length are reported as
+    /// [`UnflattenError::Ambiguous`] instead of being zipped into an array of objects, so only
+    /// a value that no other input flattens to is returned.
+    ///
+    /// # Arguments
+    ///
+    /// * `json` - A flat serde_json Object to unflatten
+    ///
+    /// # Examples
+    /// ```
+    /// use smooth_json::{Flattener, UnflattenError};
+    /// use serde_json::json;
+    ///
+    /// let flattener = Flattener::new();
+    /// let flat = json!({"labels.id": [1, 2], "labels.name": ["a", "b"], "user.id": 3});
+    ///
+    /// assert!(flattener.unflatten(&flat).is_ok());
+    /// assert_eq!(
+    ///     flattener.unflatten_strict(&flat),
+    ///     Err(UnflattenError::Ambiguous { path: "labels".to_string() })
+    /// );
+    /// ```
+    pub fn unflatten_strict(&self, json: &Value) -> Result<Value, UnflattenError> {
+        self.unflatten_with(json, true)
+    }
+
+    fn unflatten_with(&self, json: &Value, strict: bool) -> Result<Value, UnflattenError> {
+        let flat = json.as_object().ok_or(UnflattenError::NotAnObject)?;
+
+        let mut root = Node::default();
+        for (key, value) in flat {
+            root.insert(&self.split_key(key), value.clone());
+        }
+
+        if self.preserve_arrays {
+            root.into_indexed(self, "")
+        } else {
+            root.into_zipped(self, "", strict)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use serde_json::json;
+
+    use crate::KeyFormat;
+
+    fn preserving() -> Flattener<'static> {
+        Flattener {
+            preserve_arrays: true,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn round_trip_nested_array_preserve() {
+        let flattener = preserving();
+
+        let input: Value = json!({
+        "a": [
+                    "b",
+                    ["c", "d"],
+                    { "e": ["f", "g"] },
+                    [
+                        { "h": "i" },
+                        { "e": ["j", { "z": "y" }] }
+                    ],
+                    ["l"],
+                    "m"
+                 ]
+        });
+
+        let flat = flattener.flatten(&input);
+
+        assert_eq!(flattener.unflatten(&flat).unwrap(), input);
+    }
+
+    #[test]
+    fn unflatten_nested_array_preserve() {
+        let flattener = preserving();
+
+        let flat: Value = json!({
+          "a.0": "b",
+          "a.1.0": "c",
+          "a.1.1": "d",
+          "a.2.e.0": "f",
+          "a.2.e.1": "g",
+          "a.3.0.h": "i",
+          "a.3.1.e.0": "j",
+          "a.3.1.e.1.z": "y",
+          "a.4.0": "l",
+          "a.5": "m"
+        });
+
+        assert_eq!(
+            flattener.unflatten(&flat).unwrap(),
+            json!({
+                "a": [
+                    "b",
+                    ["c", "d"],
+                    { "e": ["f", "g"] },
+                    [
+                        { "h": "i" },
+                        { "e": ["j", { "z": "y" }] }
+                    ],
+                    ["l"],
+                    "m"
+                ]
+            })
+        );
+    }
+
+    #[test]
+    fn round_trip_custom_separator() {
+        let flattener = Flattener {
+            separator: "$",
+            preserve_arrays: true,
+            ..Default::default()
+        };
+
+        let input: Value = json!({
+            "a.b": { "c": [1, 2, { "d": null }] },
+            "e": [],
+            "f": true
+        });
+
+        let flat = flattener.flatten(&input);
+
+        assert_eq!(flattener.unflatten(&flat).unwrap(), input);
+    }
+
+    #[test]
+    fn round_trip_key_formats() {
+        let input: Value = json!({
+            "a.b": { "c": [1, [2, { "d/e": null }]] },
+            "it's": [],
+            "f~": true
+        });
+
+        for key_format in [
+            KeyFormat::Dotted,
+            KeyFormat::Bracketed,
+            KeyFormat::JsonPath,
+            KeyFormat::Pointer,
+        ] {
+            let flattener = Flattener {
+                key_format,
+                preserve_arrays: true,
+                escape_keys: true,
+                ..Default::default()
+            };
+
+            let flat = flattener.flatten(&input);
+
+            assert_eq!(flattener.unflatten(&flat).unwrap(), input, "{:?}", flat);
+        }
+    }
+
+    #[test]
+    fn round_trip_escaped_keys() {
+        let flattener = Flattener {
+            preserve_arrays: true,
+            escape_keys: true,
+            ..Default::default()
+        };
+
+        let input: Value = json!({
+            "a.b": [{ "c\\d": 1 }],
+            "a": { "b": [2] }
+        });
+
+        let flat = flattener.flatten(&input);
+
+        assert_eq!(flattener.unflatten(&flat).unwrap(), input);
+    }
+
+    #[test]
+    fn round_trip_empty_objects() {
+        let flattener = Flattener {
+            preserve_arrays: true,
+            empty_objects: crate::EmptyObjects::Object,
+            ..Default::default()
+        };
+
+        let input: Value = json!({
+            "meta": {},
+            "a": [{}, { "b": {} }],
+        });
+
+        let flat = flattener.flatten(&input);
+
+        assert_eq!(flattener.unflatten(&flat).unwrap(), input);
+    }
+
+    #[test]
+    fn empty_object_merges_with_nested_keys() {
+        let flattener = Flattener {
+            empty_objects: crate::EmptyObjects::Object,
+            ..Default::default()
+        };
+
+        let flat = flattener.flatten(&json!({ "a": [{}, { "b": 1 }] }));
+
+        assert_eq!(flat, json!({ "a": {}, "a.b": 1 }));
+        assert_eq!(
+            flattener.unflatten(&flat).unwrap(),
+            json!({ "a": { "b": 1 } })
+        );
+    }
+
+    #[test]
+    fn round_trip_root_values() {
+        let flattener = preserving();
+
+        for input in [json!("abc"), json!(["a", ["b"]]), json!([])] {
+            let flat = flattener.flatten(&input);
+            assert_eq!(flattener.unflatten(&flat).unwrap(), input);
+        }
+    }
+
+    #[test]
+    fn index_like_keys_stay_objects() {
+        let flattener = preserving();
+
+        let flat: Value = json!({
+            "a.01": 1,
+            "a.1": 2
+        });
+
+        assert_eq!(
+            flattener.unflatten(&flat).unwrap(),
+            json!({ "a": { "01": 1, "1": 2 } })
+        );
+    }
+
+    #[test]
+    fn sparse_and_huge_indices_stay_objects() {
+        let flattener = preserving();
+
+        assert_eq!(
+            flattener
+                .unflatten(&json!({"a.18446744073709551615": 1}))
+                .unwrap(),
+            json!({"a": {"18446744073709551615": 1}})
+        );
+        assert_eq!(
+            flattener.unflatten(&json!({"a.1000000000000": 1})).unwrap(),
+            json!({"a": {"1000000000000": 1}})
+        );
+        assert_eq!(
+            flattener.unflatten(&json!({"a.0": 1, "a.2": 2})).unwrap(),
+            json!({"a": {"0": 1, "2": 2}})
+        );
+    }
+
+    #[test]
+    fn zip_array_of_objects() {
+        let flattener = Flattener::new();
+
+        let input: Value = json!({
+            "id": 1,
+            "labels": [
+                {"id": 1, "name": "bug", "meta": {"color": "d73a4a"}},
+                {"id": 2, "name": "enhancement", "meta": {"color": "a2eeef"}}
+            ],
+            "user": {"login": "octocat", "id": 7},
+            "tags": ["a", "b"],
+            "assignees": []
+        });
+
+        let flat = flattener.flatten(&input);
+
+        assert_eq!(flattener.unflatten(&flat).unwrap(), input);
+    }
+
+    #[test]
+    fn zip_alt_array_flattening() {
+        let flattener = Flattener {
+            alt_array_flattening: true,
+            ..Default::default()
+        };
+
+        let input: Value = json!({
+            "issue": {
+                "labels": [
+                    {"id": 1, "name": "bug"}
+                ]
+            }
+        });
+
+        let flat = flattener.flatten(&input);
+
+        assert_eq!(
+            flat,
+            json!({"issue.labels.id": [1], "issue.labels.name": ["bug"]})
+        );
+        assert_eq!(flattener.unflatten(&flat).unwrap(), input);
+    }
+
+    #[test]
+    fn zip_single_column_stays_object() {
+        let flattener = Flattener::new();
+
+        let flat: Value = json!({"a.b": ["1", "2", "3"]});
+
+        assert_eq!(
+            flattener.unflatten(&flat).unwrap(),
+            json!({"a": {"b": ["1", "2", "3"]}})
+        );
+    }
+
+    #[test]
+    fn zip_lists_next_to_other_fields() {
+        let flattener = Flattener::new();
+
+        let flat = flattener.flatten(&json!({
+            "user": {"name": "x", "roles": ["a", "b"], "tags": ["c", "d"]}
+        }));
+        assert_eq!(
+            flattener.unflatten(&flat),
+            Err(UnflattenError::Ambiguous {
+                path: "user".to_string()
+            })
+        );
+
+        // An element missing a field leaves the same shape behind
+        let flat = flattener.flatten(&json!({"labels": [{"id": 1, "name": "a"}, {"id": 2}]}));
+        assert_eq!(flat, json!({"labels.id": [1, 2], "labels.name": "a"}));
+        assert_eq!(
+            flattener.unflatten(&flat),
+            Err(UnflattenError::Ambiguous {
+                path: "labels".to_string()
+            })
+        );
+    }
+
+    #[test]
+    fn zip_equal_length_lists() {
+        let flattener = Flattener::new();
+
+        // Indistinguishable from an array of objects, see the `unflatten` docs
+        let flat = json!({"user.tags": ["a", "b"], "user.roles": ["x", "y"], "id": 1});
+        assert_eq!(
+            flattener.unflatten_strict(&flat),
+            Err(UnflattenError::Ambiguous {
+                path: "user".to_string()
+            })
+        );
+
+        // Nothing to zip, so nothing to guess
+        let flat = json!({"user.tags": ["a", "b"], "id": [1, 2]});
+        assert_eq!(
+            flattener.unflatten_strict(&flat).unwrap(),
+            json!({"user": {"tags": ["a", "b"]}, "id": [1, 2]})
+        );
+    }
+
+    #[test]
+    fn zip_errors() {
+        let flattener = Flattener::new();
+
+        assert_eq!(
+            flattener.unflatten(&json!({"a.b": [1, 2], "a.c": [3, 4, 5], "a.d": 6})),
+            Err(UnflattenError::RaggedArrays {
+                path: "a".to_string()
+            })
+        );
+        assert_eq!(
+            flattener.unflatten(&json!({"a.b": [1, 2], "a.c": [3]})),
+            Err(UnflattenError::RaggedArrays {
+                path: "a".to_string()
+            })
+        );
+        assert_eq!(
+            flattener.unflatten(&json!({
+                "a": ["b", "c", "d", "l", "m"],
+                "a.e": ["f", "g", "j"],
+                "a.h": "i",
+                "a.e.z": "y",
+            })),
+            Err(UnflattenError::Conflict {
+                path: "a".to_string()
+            })
+        );
+    }
+
+    #[test]
+    fn unflatten_errors() {
+        let flattener = preserving();
+
+        assert_eq!(
+            flattener.unflatten(&json!(["a"])),
+            Err(UnflattenError::NotAnObject)
+        );
+        assert_eq!(
+            flattener.unflatten(&json!({ "a.0": 1, "a.0.b": 2 })),
+            Err(UnflattenError::Conflict {
+                path: "a.0".to_string()
+            })
+        );
+    }
+}
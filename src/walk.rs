@@ -0,0 +1,603 @@
+//! Iterative depth-first traversal shared by the flattening entry points.
+//!
+//! Work is kept on a heap-allocated stack instead of the call stack, so arbitrarily deep input
+//! cannot overflow it. The key of the current value is built in a single buffer that is
+//! truncated back to the parent's key before each sibling is visited.
+//!
+//! Values are walked as `Cow`s, so the same traversal serves borrowed input, where leaves are
+//! cloned, and owned input, where keys and leaves are moved out instead. Subtrees that are kept
+//! whole are cloned, serialized and dropped with heap stacks as well.
+
+use std::borrow::Cow;
+
+use serde_json::Map;
+use serde_json::Value;
+
+use crate::DepthLimit;
+use crate::EmptyObjects;
+use crate::Flattener;
+use crate::filter::Filter;
+use crate::filter::Stop;
+use crate::filter::Visit;
+
+/// Stands in for empty objects under `EmptyObjects::Null`, so they can still be borrowed.
+static NULL: Value = Value::Null;
+
+/// How a value extends the key of its parent.
+enum Segment<'v> {
+    /// The value being flattened, which has no key of its own
+    Root,
+    /// A value inside an object
+    Key(Cow<'v, str>),
+    /// A value inside an array, when `preserve_arrays` keeps the index in the key
+    Index(usize),
+    /// A value inside an array, sharing its parent's key
+    Inherit,
+}
+
+struct Frame<'v> {
+    key_len: usize,
+    source_len: usize,
+    path_len: usize,
+    /// The parent's key, when it is a string borrowed from the input
+    parent_key_ref: Option<&'v str>,
+    segment: Segment<'v>,
+    value: Cow<'v, Value>,
+    arr: bool,
+    depth: usize,
+    /// Whether an include pattern matched one of its parents
+    included: bool,
+}
+
+/// A value the walker stopped at.
+pub(crate) enum Leaf<'v> {
+    /// A scalar, or an empty array or object that is kept in the output
+    Value(Cow<'v, Value>),
+    /// A non-empty object or array at `max_depth`
+    TooDeep(Cow<'v, Value>),
+    /// An object or array matched by `stringify`
+    Stringify(Cow<'v, Value>),
+}
+
+pub(crate) struct Walker<'f, 'a, 'v> {
+    flattener: &'f Flattener<'a>,
+    stack: Vec<Frame<'v>>,
+    /// Flattened key of the last leaf returned by `next_leaf`
+    pub(crate) key: String,
+    /// The same key as `key`, when it is a string borrowed from the input
+    key_ref: Option<&'v str>,
+    /// Source path of the last leaf returned by `next_leaf`, see `Flattener::insert`
+    pub(crate) source: String,
+    filter: Filter,
+}
+
+impl<'f, 'a, 'v> Walker<'f, 'a, 'v> {
+    /// Starts walking `json`, reusing the allocations of `key` and `source` and the patterns
+    /// already parsed into `filter`.
+    pub(crate) fn new(
+        flattener: &'f Flattener<'a>,
+        json: Cow<'v, Value>,
+        mut key: String,
+        mut source: String,
+        mut filter: Filter,
+    ) -> Self {
+        key.clear();
+        source.clear();
+        filter.reset(flattener);
+        Walker {
+            flattener,
+            stack: vec![Frame {
+                key_len: 0,
+                source_len: 0,
+                path_len: 0,
+                parent_key_ref: None,
+                segment: Segment::Root,
+                value: json,
+                arr: false,
+                depth: 0,
+                included: false,
+            }],
+            key,
+            key_ref: None,
+            source,
+            filter,
+        }
+    }
+
+    /// Gives back the key and source buffers and the filter so they can be reused.
+    pub(crate) fn into_buffers(self) -> (String, String, Filter) {
+        (self.key, self.source, self.filter)
+    }
+
+    /// Advances to the next leaf in document order, leaving its path in `key` and `source`.
+    ///
+    /// Also returns whether the leaf should be wrapped in an array because it sits inside an
+    /// array under `alt_array_flattening`.
+    pub(crate) fn next_leaf(&mut self) -> Option<(Leaf<'v>, bool)> {
+        while let Some(frame) = self.stack.pop() {
+            self.key.truncate(frame.key_len);
+            self.source.truncate(frame.source_len);
+            self.filter.truncate(frame.path_len);
+            self.push_segment(&frame.segment);
+            self.key_ref = match frame.segment {
+                Segment::Root => Some(""),
+                // Escaping only ever lengthens a key, so an unchanged length means it is `k`
+                Segment::Key(Cow::Borrowed(k))
+                    if frame.key_len == 0 && k.len() == self.key.len() =>
+                {
+                    Some(k)
+                }
+                Segment::Key(_) | Segment::Index(_) => None,
+                Segment::Inherit => frame.parent_key_ref,
+            };
+
+            let included = match self.filter.visit(frame.included) {
+                Visit::Skip => {
+                    drop_value(frame.value);
+                    continue;
+                }
+                Visit::Descend => false,
+                Visit::Include => true,
+            };
+            // Only the children of a value that is not included can be part of the output
+            if !included && !self.descends(&frame) {
+                drop_value(frame.value);
+                continue;
+            }
+
+            if matches!(&*frame.value, Value::Object(_) | Value::Array(_)) {
+                match self.filter.stop() {
+                    Some(Stop::Value) => return Some((Leaf::Value(frame.value), frame.arr)),
+                    Some(Stop::String) => return Some((Leaf::Stringify(frame.value), frame.arr)),
+                    None => {}
+                }
+            }
+
+            match &*frame.value {
+                Value::Object(obj) if obj.is_empty() => match self.flattener.empty_objects {
+                    EmptyObjects::Omit => {}
+                    EmptyObjects::Object => return Some((Leaf::Value(frame.value), false)),
+                    EmptyObjects::Null => {
+                        return Some((Leaf::Value(Cow::Borrowed(&NULL)), frame.arr));
+                    }
+                },
+                // Empty arrays should be preserved, instead of being omitted
+                Value::Array(arr) if arr.is_empty() => {
+                    return Some((Leaf::Value(frame.value), false));
+                }
+                Value::Object(_) | Value::Array(_) => {
+                    if self
+                        .flattener
+                        .max_depth
+                        .is_some_and(|max_depth| frame.depth >= max_depth)
+                    {
+                        return Some((Leaf::TooDeep(frame.value), frame.arr));
+                    }
+                    self.push_children(frame, included);
+                }
+                _ => return Some((Leaf::Value(frame.value), frame.arr)),
+            }
+        }
+        None
+    }
+
+    /// Whether the walk goes on into the children of a value, rather than stopping at it.
+    fn descends(&self, frame: &Frame) -> bool {
+        let has_children = match &*frame.value {
+            Value::Object(obj) => !obj.is_empty(),
+            Value::Array(arr) => !arr.is_empty(),
+            _ => false,
+        };
+        has_children
+            && self
+                .flattener
+                .max_depth
+                .is_none_or(|max_depth| frame.depth < max_depth)
+            && self.filter.stop().is_none()
+    }
+
+    /// Queues the children of an object or array so they are visited in document order.
+    fn push_children(&mut self, parent: Frame<'v>, included: bool) {
+        let object_child = |segment, value| Frame {
+            key_len: self.key.len(),
+            source_len: self.source.len(),
+            path_len: self.filter.len(),
+            parent_key_ref: self.key_ref,
+            segment: Segment::Key(segment),
+            value,
+            arr: parent.arr,
+            depth: parent.depth + 1,
+            included,
+        };
+        let array_child = |i, value| Frame {
+            key_len: self.key.len(),
+            source_len: self.source.len(),
+            path_len: self.filter.len(),
+            parent_key_ref: self.key_ref,
+            segment: if self.flattener.preserve_arrays {
+                Segment::Index(i)
+            } else {
+                Segment::Inherit
+            },
+            value,
+            arr: self.flattener.alt_array_flattening,
+            depth: parent.depth + 1,
+            included,
+        };
+
+        match parent.value {
+            Cow::Borrowed(Value::Object(obj)) => self.stack.extend(
+                obj.iter()
+                    .rev()
+                    .map(|(k, v)| object_child(Cow::Borrowed(k), Cow::Borrowed(v))),
+            ),
+            Cow::Owned(Value::Object(obj)) => self.stack.extend(
+                obj.into_iter()
+                    .rev()
+                    .map(|(k, v)| object_child(Cow::Owned(k), Cow::Owned(v))),
+            ),
+            Cow::Borrowed(Value::Array(arr)) => self.stack.extend(
+                arr.iter()
+                    .enumerate()
+                    .rev()
+                    .map(|(i, v)| array_child(i, Cow::Borrowed(v))),
+            ),
+            Cow::Owned(Value::Array(arr)) => self.stack.extend(
+                arr.into_iter()
+                    .enumerate()
+                    .rev()
+                    .map(|(i, v)| array_child(i, Cow::Owned(v))),
+            ),
+            _ => unreachable!("only objects and arrays have children"),
+        }
+    }
+
+    fn push_segment(&mut self, segment: &Segment) {
+        match segment {
+            Segment::Root | Segment::Inherit => {}
+            Segment::Key(k) => {
+                self.flattener.push_key(&mut self.key, &mut self.source, k);
+                self.filter.push_key(k);
+            }
+            Segment::Index(i) => {
+                self.flattener
+                    .push_index(&mut self.key, &mut self.source, *i);
+                self.filter.push_index(*i);
+            }
+        }
+    }
+}
+
+/// An object or array being cloned by `into_owned`, along with the children left to clone.
+enum Partial<'v> {
+    Array(Vec<Value>, std::slice::Iter<'v, Value>),
+    /// Also holds the key of the child being cloned
+    Object(Map<String, Value>, serde_json::map::Iter<'v>, &'v str),
+}
+
+/// Clones a borrowed value like `Cow::into_owned`, without recursing once per level.
+pub(crate) fn into_owned(value: Cow<Value>) -> Value {
+    let mut value = match value {
+        Cow::Owned(value) => return value,
+        Cow::Borrowed(value) => value,
+    };
+
+    let mut stack = vec![];
+    loop {
+        let mut done = match value {
+            Value::Array(arr) => {
+                stack.push(Partial::Array(Vec::with_capacity(arr.len()), arr.iter()));
+                None
+            }
+            Value::Object(obj) => {
+                stack.push(Partial::Object(Map::new(), obj.iter(), ""));
+                None
+            }
+            scalar => Some(scalar.clone()),
+        };
+
+        // Hands finished values to their parents until one has a child left to clone
+        loop {
+            let Some(top) = stack.last_mut() else {
+                return done.expect("the value is finished once the stack is empty");
+            };
+            let child = match top {
+                Partial::Array(out, children) => {
+                    out.extend(done.take());
+                    children.next()
+                }
+                Partial::Object(out, children, key) => {
+                    if let Some(child) = done.take() {
+                        out.insert(key.to_string(), child);
+                    }
+                    children.next().map(|(k, v)| {
+                        *key = k;
+                        v
+                    })
+                }
+            };
+            match child {
+                Some(child) => {
+                    value = child;
+                    break;
+                }
+                None => {
+                    done = Some(match stack.pop() {
+                        Some(Partial::Array(out, _)) => Value::Array(out),
+                        Some(Partial::Object(out, _, _)) => Value::Object(out),
+                        None => unreachable!("the top of the stack was just seen"),
+                    });
+                }
+            }
+        }
+    }
+}
+
+/// Serializes a value as compact JSON text like `Value::to_string`, without recursing once per
+/// level.
+pub(crate) fn to_json_string(value: &Value) -> String {
+    enum Step<'v> {
+        Value(&'v Value),
+        Key(&'v str),
+        Text(&'static [u8]),
+    }
+
+    let mut out = Vec::new();
+    let mut stack = vec![Step::Value(value)];
+    while let Some(step) = stack.pop() {
+        match step {
+            Step::Text(text) => out.extend_from_slice(text),
+            Step::Key(key) => {
+                serde_json::to_writer(&mut out, key).expect("strings serialize");
+                out.push(b':');
+            }
+            Step::Value(Value::Array(arr)) => {
+                out.push(b'[');
+                stack.push(Step::Text(b"]"));
+                for (i, element) in arr.iter().enumerate().rev() {
+                    stack.push(Step::Value(element));
+                    if i > 0 {
+                        stack.push(Step::Text(b","));
+                    }
+                }
+            }
+            Step::Value(Value::Object(obj)) => {
+                out.push(b'{');
+                stack.push(Step::Text(b"}"));
+                for (i, (key, value)) in obj.iter().enumerate().rev() {
+                    stack.push(Step::Value(value));
+                    stack.push(Step::Key(key));
+                    if i > 0 {
+                        stack.push(Step::Text(b","));
+                    }
+                }
+            }
+            Step::Value(scalar) => {
+                serde_json::to_writer(&mut out, scalar).expect("scalars serialize");
+            }
+        }
+    }
+    String::from_utf8(out).expect("JSON text is UTF-8")
+}
+
+/// Drops an owned value one level at a time, since dropping it in one go recurses once per
+/// level.
+pub(crate) fn drop_value(value: Cow<Value>) {
+    let mut stack = match value {
+        Cow::Owned(value @ (Value::Array(_) | Value::Object(_))) => vec![value],
+        _ => return,
+    };
+    while let Some(value) = stack.pop() {
+        match value {
+            Value::Array(arr) => stack.extend(arr),
+            Value::Object(obj) => stack.extend(obj.into_iter().map(|(_, v)| v)),
+            _ => {}
+        }
+    }
+}
+
+/// Iterator over the leaves of a JSON value and their flattened keys, created by
+/// [`Flattener::leaves`]
+/// # Examples
+/// ```
+/// use smooth_json;
+/// use serde_json::json;
+///
+/// let flattener = smooth_json::Flattener::new();
+/// let example = json!({"a": {"b": 1}});
+///
+/// let leaves: smooth_json::Leaves = flattener.leaves(&example);
+/// ```
+pub struct Leaves<'f, 'a, 'v> {
+    walker: Walker<'f, 'a, 'v>,
+}
+
+impl<'f, 'a, 'v> Iterator for Leaves<'f, 'a, 'v> {
+    type Item = (Cow<'v, str>, &'v Value);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let (leaf, _) = self.walker.next_leaf()?;
+            let value = match leaf {
+                Leaf::TooDeep(_) if self.walker.flattener.depth_limit == DepthLimit::Drop => {
+                    continue;
+                }
+                Leaf::Value(value) | Leaf::TooDeep(value) | Leaf::Stringify(value) => value,
+            };
+            let Cow::Borrowed(value) = value else {
+                unreachable!("leaves only walks borrowed values")
+            };
+            let flattener = self.walker.flattener;
+            let key = if flattener.rename.is_empty() && flattener.key_transforms.is_empty() {
+                match self.walker.key_ref {
+                    Some(key) => Cow::Borrowed(key),
+                    None => Cow::Owned(self.walker.key.clone()),
+                }
+            } else {
+                Cow::Owned(flattener.output_key(&self.walker.key).into_owned())
+            };
+            return Some((key, value));
+        }
+    }
+}
+
+impl<'a> Flattener<'a> {
+    /// Walks the leaves of a JSON value along with their flattened keys, without building the
+    /// flattened object
+    ///
+    /// Keys follow the same `separator`, `escape_keys`, `preserve_arrays`, `rename` and
+    /// `key_transforms` rules as [`Flattener::flatten`], but nothing is cloned: every leaf is
+    /// yielded as a reference into `json`, and keys are borrowed from it when they are a top-level
+    /// key that is not renamed or transformed. Leaves are yielded one at a time in document order,
+    /// so repeated keys are not merged, `collision_policy` does not apply and
+    /// `alt_array_flattening` does not wrap values in arrays. Objects and arrays at `max_depth` are
+    /// yielded whole unless `depth_limit` is [`DepthLimit::Drop`], as are the ones matched by
+    /// `keep_whole` or `stringify`. Leaves left out by `include` or `exclude` are not yielded.
+    ///
+    /// # Arguments
+    ///
+    /// * `json` - A serde_json Value to walk
+    ///
+    /// # Examples
+    /// ```
+    /// use smooth_json;
+    /// use serde_json::json;
+    ///
+    /// let flattener = smooth_json::Flattener::new();
+    /// let example = json!({
+    ///     "name": "John Doe",
+    ///     "address": {
+    ///         "city": "London"
+    ///     },
+    ///     "phones": ["+44 1234567", "+44 2345678"]
+    /// });
+    ///
+    /// let leaves: Vec<_> = flattener
+    ///     .leaves(&example)
+    ///     .map(|(key, value)| (key.into_owned(), value.clone()))
+    ///     .collect();
+    ///
+    /// assert_eq!(leaves, vec![
+    ///     ("address.city".to_string(), json!("London")),
+    ///     ("name".to_string(), json!("John Doe")),
+    ///     ("phones".to_string(), json!("+44 1234567")),
+    ///     ("phones".to_string(), json!("+44 2345678")),
+    /// ]);
+    /// ```
+    pub fn leaves<'f, 'v>(&'f self, json: &'v Value) -> Leaves<'f, 'a, 'v> {
+        Leaves {
+            walker: Walker::new(
+                self,
+                Cow::Borrowed(json),
+                String::new(),
+                String::new(),
+                Filter::default(),
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use serde_json::Map;
+    use serde_json::json;
+
+    #[test]
+    fn leaves_borrow_top_level_keys() {
+        let flattener = Flattener::new();
+        let input: Value = json!({
+            "a": { "b": 1 },
+            "c": [2, { "d": 3 }],
+            "e": 4
+        });
+
+        let leaves: Vec<_> = flattener.leaves(&input).collect();
+
+        assert_eq!(
+            leaves,
+            vec![
+                (Cow::Borrowed("a.b"), &json!(1)),
+                (Cow::Borrowed("c"), &json!(2)),
+                (Cow::Borrowed("c.d"), &json!(3)),
+                (Cow::Borrowed("e"), &json!(4)),
+            ]
+        );
+        assert!(matches!(leaves[0].0, Cow::Owned(_)));
+        assert!(matches!(leaves[1].0, Cow::Borrowed(_)));
+        assert!(matches!(leaves[2].0, Cow::Owned(_)));
+        assert!(matches!(leaves[3].0, Cow::Borrowed(_)));
+        assert!(std::ptr::eq(leaves[3].1, &input["e"]));
+    }
+
+    #[test]
+    fn leaves_follow_flattener_options() {
+        let flattener = Flattener {
+            preserve_arrays: true,
+            escape_keys: true,
+            max_depth: Some(3),
+            empty_objects: EmptyObjects::Null,
+            ..Default::default()
+        };
+        let input: Value = json!({
+            "a.b": [{ "c": { "d": 1 } }, []],
+            "e": {}
+        });
+
+        let leaves: Vec<_> = flattener
+            .leaves(&input)
+            .map(|(key, value)| (key.into_owned(), value.clone()))
+            .collect();
+
+        assert_eq!(
+            leaves,
+            vec![
+                ("a\\.b.0.c".to_string(), json!({ "d": 1 })),
+                ("a\\.b.1".to_string(), json!([])),
+                ("e".to_string(), Value::Null),
+            ]
+        );
+
+        let dropping = Flattener {
+            depth_limit: DepthLimit::Drop,
+            ..flattener
+        };
+        assert_eq!(dropping.leaves(&input).count(), 2);
+    }
+
+    #[test]
+    fn leaves_match_flatten() {
+        let flattener = Flattener::new();
+        let input: Value = json!({
+            "a": [{ "b": "c" }, { "b": "d", "c": "e" }],
+            "f": { "g": null }
+        });
+
+        let mut collected = Map::new();
+        for (key, value) in flattener.leaves(&input) {
+            match collected.get_mut(key.as_ref()) {
+                Some(Value::Array(arr)) => arr.push(value.clone()),
+                Some(existing) => *existing = json!([existing.take(), value.clone()]),
+                None => {
+                    collected.insert(key.into_owned(), value.clone());
+                }
+            }
+        }
+
+        assert_eq!(Value::Object(collected), flattener.flatten(&input));
+    }
+
+    #[test]
+    fn kept_subtrees_match_serde_json() {
+        let input: Value = json!({
+            "a\"b": [1, -2.5, 1e300, "\u{1F600}\n", null, true, [], {}],
+            "c": {"d": [{"e": "\\"}], "f": u64::MAX}
+        });
+
+        assert_eq!(to_json_string(&input), input.to_string());
+        assert_eq!(into_owned(Cow::Borrowed(&input)), input);
+        assert_eq!(into_owned(Cow::Borrowed(&json!(1))), json!(1));
+        drop_value(Cow::Owned(input));
+    }
+}
@@ -0,0 +1,208 @@
+#![cfg(feature = "cli")]
+
+use std::io::Write;
+use std::process::{Command, Output, Stdio};
+
+use serde_json::{Value, json};
+
+fn flatten_json(args: &[&str], stdin: &str) -> Output {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_flatten-json"))
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    // The tool may exit before reading its input, such as on invalid arguments
+    let _ = child.stdin.take().unwrap().write_all(stdin.as_bytes());
+    child.wait_with_output().unwrap()
+}
+
+fn stdout(output: &Output) -> &str {
+    assert!(output.status.success(), "{:?}", output);
+    std::str::from_utf8(&output.stdout).unwrap()
+}
+
+#[test]
+fn cli_flattens_json_with_options() {
+    let output = flatten_json(
+        &["--separator", "_", "--preserve-arrays"],
+        r#"{"a": {"b": [1, {"c": 2}]}}"#,
+    );
+    let flattened: Value = serde_json::from_str(stdout(&output)).unwrap();
+    assert_eq!(flattened, json!({"a_b_0": 1, "a_b_1_c": 2}));
+
+    let output = flatten_json(
+        &["--max-depth=1", "--depth-limit=keep", "-f", "ndjson"],
+        r#"{"a": {"b": 1}, "c": 2}"#,
+    );
+    assert_eq!(stdout(&output), "{\"a\":{\"b\":1},\"c\":2}\n");
+
+    let output = flatten_json(
+        &[
+            "--key-format",
+            "pointer",
+            "--preserve-arrays",
+            "-f",
+            "ndjson",
+        ],
+        r#"{"a/b": [1]}"#,
+    );
+    assert_eq!(stdout(&output), "{\"/a~1b/0\":1}\n");
+
+    let output = flatten_json(
+        &[
+            "--include",
+            "user",
+            "--include=id",
+            "--exclude",
+            "**.password",
+            "-f",
+            "ndjson",
+        ],
+        r#"{"id": 1, "user": {"name": "x", "password": "y"}, "tags": ["a"]}"#,
+    );
+    assert_eq!(stdout(&output), "{\"id\":1,\"user.name\":\"x\"}\n");
+
+    let output = flatten_json(
+        &["--keep-whole", "ext", "--stringify=payload", "-f", "ndjson"],
+        r#"{"ext": {"a": 1}, "payload": {"b": [2]}}"#,
+    );
+    assert_eq!(
+        stdout(&output),
+        "{\"ext\":{\"a\":1},\"payload\":\"{\\\"b\\\":[2]}\"}\n"
+    );
+
+    let output = flatten_json(
+        &[
+            "--snake-case",
+            "--replace-invalid",
+            "_",
+            "--rename",
+            "profile.id=ID",
+            "-f",
+            "csv",
+        ],
+        r#"{"profile": {"firstName": "Ada", "id": 1}}"#,
+    );
+    assert_eq!(stdout(&output), "ID,profile_first_name\n1,Ada\n");
+}
+
+#[test]
+fn cli_reads_files_and_ndjson() {
+    let output = flatten_json(
+        &["-i", "ndjson"],
+        "{\"a\": {\"b\": 1}}\n\n{\"a\": {\"c\": 2}}\n",
+    );
+    assert_eq!(stdout(&output), "{\"a.b\":1}\n{\"a.c\":2}\n");
+
+    let output = flatten_json(
+        &["tests/input/file1.json", "-", "-f", "ndjson"],
+        "{\"x\": 1}",
+    );
+    let lines: Vec<&str> = stdout(&output).lines().collect();
+    assert_eq!(lines.len(), 2);
+    assert_eq!(lines[1], "{\"x\":1}");
+}
+
+#[test]
+fn cli_writes_json_arrays_of_records() {
+    let output = flatten_json(&["tests/input/file1.json", "-"], "{\"x\": {\"y\": 1}}");
+    let records: Value = serde_json::from_str(stdout(&output)).unwrap();
+    assert_eq!(records.as_array().unwrap().len(), 2);
+    assert_eq!(records[1], json!({"x.y": 1}));
+
+    let output = flatten_json(&["-i", "ndjson", "-f", "json"], "{\"a\": 1}\n");
+    let records: Value = serde_json::from_str(stdout(&output)).unwrap();
+    assert_eq!(records, json!([{"a": 1}]));
+
+    let output = flatten_json(&["-i", "ndjson", "-f", "json"], "");
+    assert_eq!(stdout(&output), "[]\n");
+}
+
+#[test]
+fn cli_reads_top_level_arrays_as_records() {
+    let input = r#"[{"a": {"b": 1}}, {"a": {"b": 2}}]"#;
+
+    let output = flatten_json(&["-f", "csv"], input);
+    assert_eq!(stdout(&output), "a.b\n1\n2\n");
+
+    let output = flatten_json(&[], r#"[{"a": {"b": 1}}]"#);
+    let records: Value = serde_json::from_str(stdout(&output)).unwrap();
+    assert_eq!(records, json!([{"a.b": 1}]));
+}
+
+#[test]
+fn cli_writes_csv() {
+    let input =
+        "{\"id\": 1, \"tags\": [\"a\", \"b\"]}\n{\"id\": 2, \"user\": {\"name\": \"x;y\"}}\n";
+
+    let output = flatten_json(&["-i", "ndjson", "-f", "csv", "-d", ";"], input);
+    assert_eq!(
+        stdout(&output),
+        "id;tags;user.name\n1;\"[\"\"a\"\",\"\"b\"\"]\";\n2;;\"x;y\"\n"
+    );
+
+    let output = flatten_json(&["-i", "ndjson", "-f", "csv", "--explode-arrays"], input);
+    assert_eq!(stdout(&output), "id,tags,user.name\n1,a,\n1,b,\n2,,x;y\n");
+}
+
+#[test]
+fn cli_writes_to_output_file() {
+    let path = std::env::temp_dir().join(format!("smooth-json-cli-{}.json", std::process::id()));
+    let output = flatten_json(&["-o", path.to_str().unwrap()], r#"{"a": {"b": 1}}"#);
+    assert_eq!(stdout(&output), "");
+
+    let written = std::fs::read_to_string(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(
+        serde_json::from_str::<Value>(&written).unwrap(),
+        json!({"a.b": 1})
+    );
+}
+
+#[test]
+fn cli_exit_codes() {
+    let output = flatten_json(&["-i", "ndjson"], "{\"a\": 1}\n{\"a\":\n");
+    assert_eq!(output.status.code(), Some(1));
+    assert!(String::from_utf8_lossy(&output.stderr).starts_with("flatten-json: <stdin>:2: "));
+
+    let output = flatten_json(
+        &["--collision-policy", "error"],
+        r#"{"a": {"b": 1}, "a.b": 2}"#,
+    );
+    assert_eq!(output.status.code(), Some(1));
+
+    let output = flatten_json(&["--max-keys", "1"], r#"{"a": {"b": 1, "c": 2}}"#);
+    assert_eq!(output.status.code(), Some(1));
+
+    let output = flatten_json(&["-i", "ndjson", "-f", "csv"], "{\"a\": 1}\n2\n");
+    assert_eq!(output.status.code(), Some(1));
+    assert_eq!(
+        String::from_utf8_lossy(&output.stderr),
+        "flatten-json: <stdin>:2: a CSV record must be a JSON object\n"
+    );
+
+    for args in [
+        &["--no-such-option"][..],
+        &["--max-depth"],
+        &["--max-keys=-1"],
+        &["--escape-keys=yes"],
+        &["-f", "xml"],
+        &["--join-arrays", ",", "--explode-arrays"],
+        &["--rename", "a"],
+        &["--replace-invalid", "__"],
+    ] {
+        let output = flatten_json(args, "{}");
+        assert_eq!(output.status.code(), Some(2), "{:?}", args);
+    }
+
+    let output = flatten_json(&["tests/input/missing.json"], "");
+    assert_eq!(output.status.code(), Some(3));
+
+    let output = flatten_json(&["--version"], "");
+    assert_eq!(
+        stdout(&output),
+        format!("flatten-json {}\n", env!("CARGO_PKG_VERSION"))
+    );
+}
@@ -163,3 +163,36 @@ fn preserve_arrays_integration() {
     assert_eq!(flattened["items.1.id"], 2);
     assert_eq!(flattened["items.1.name"], "Second");
 }
+
+#[test]
+fn streaming_matches_flatten_on_fixtures() {
+    let fixtures = [
+        include_str!("input/file1.json"),
+        include_str!("input/file2.json"),
+    ];
+    let flatteners = [
+        Flattener::new(),
+        Flattener {
+            preserve_arrays: true,
+            ..Default::default()
+        },
+        Flattener {
+            alt_array_flattening: true,
+            ..Default::default()
+        },
+    ];
+
+    for fixture in fixtures {
+        let input: serde_json::Value = serde_json::from_str(fixture).unwrap();
+        for flattener in &flatteners {
+            assert_eq!(
+                flattener.flatten_from_str(fixture).unwrap(),
+                flattener.flatten(&input)
+            );
+            assert_eq!(
+                flattener.flatten_from_reader(fixture.as_bytes()).unwrap(),
+                flattener.flatten(&input)
+            );
+        }
+    }
+}